@@ -5,7 +5,7 @@ use figment::{
 };
 use log::{debug, info, trace};
 use serde_derive::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 //Duplicated in transport
 const MAX_MTU: u16 = 3 * 1024;
@@ -15,12 +15,15 @@ pub struct Config {
     // The network address myceli will listen on for incoming messages.
     pub listen_address: String,
     // The timeout before retrying a dag transfer, measured in milliseconds. This is reset every window.
+    // Each transfer adapts its own timeout to the measured round-trip time, never exceeding this.
     pub retry_timeout_duration: u64,
     // Directory path for myceli to use for storage.
+    // ":memory:" keeps everything in RAM instead, lost on restart (needs the memory feature).
     pub storage_path: String,
     // The MTU (in bytes) used to chunk up messages into UDP packets. Maximum value is 3072.
     pub mtu: u16,
-    // The number of blocks to send in each window of a DAG transfer.
+    // The number of blocks to send in the first window of a DAG transfer.
+    // Later windows grow while no blocks go missing (up to 4x this) and shrink when they do.
     pub window_size: u32,
     // The size (in bytes) of the blocks that a file is broken up into when imported.
     pub block_size: Option<u32>,
@@ -35,6 +38,20 @@ pub struct Config {
     pub watched_directory: Option<String>,
     //How much storage space should Local Storage use? Measured in kiB. Default is 1 GiB
     pub disk_usage: u64,
+    // Compression of blocks at rest: "none", "zstd" or "lz4", as built in. Blocks which don't shrink are
+    // stored as-is either way. Default is "none".
+    pub block_compression: String,
+    // Multihash imported files' blocks are addressed by, by multicodec name: "sha2-256", "sha2-512", "sha3-256",
+    // "sha3-512", "blake2b-256", "blake2b-512", "blake2s-256" or "blake3". Default is "sha2-256".
+    pub block_hash: String,
+    // Chunks of up to this many bytes (at most 64) are inlined into their parent block as identity CIDs, rather
+    // than stored and sent as blocks of their own. Peers must be new enough to know identity CIDs carry their
+    // blocks. Default is 0 (nothing inlined).
+    pub inline_limit: u8,
+    // Encrypt blocks and names at rest with this 256-bit key, given as 64 hex digits. Default is none (unencrypted).
+    pub storage_key: Option<String>,
+    // A file holding the storage key instead, as 32 raw bytes or 64 hex digits. Default is none.
+    pub storage_key_file: Option<String>,
     //Minimum amount of time (milliseconds) to elapse between background tasks
     //Note: some background tasks can send a packet on the network depending on circumstance.
     //Default is 10000 (10 seconds).
@@ -42,6 +59,29 @@ pub struct Config {
     //Maximum is 3600000 (1 hour)
     pub chatter_ms: u32,
     pub shipper_throttle_packet_delay_ms: u32,
+    // Least time in milliseconds between background scrub steps, which re-hash stored blocks looking for corruption.
+    // 0 turns background scrubbing off. Default is 60000 (1 minute).
+    pub scrub_interval_ms: u64,
+    // Blocks re-hashed by each background scrub step. Default is 4.
+    pub scrub_batch: u16,
+    // Relays to send DAGs through for destinations that can't be reached directly,
+    // as destination address -> next hop address. Default is empty (send everything directly).
+    pub routes: BTreeMap<String, String>,
+    // Address of a local DTN bundle agent. If set, messages for dtn: or ipn: endpoint IDs are sent as
+    // BPv7 bundles by way of it, and bundles it delivers are accepted. Default is none (plain UDP only).
+    pub bundle_agent: Option<String>,
+    // This node's endpoint ID, the source of the bundles it sends. Required along with bundle_agent.
+    pub bundle_eid: Option<String>,
+    // Lifetime in milliseconds given to bundles sent. Default is 86400000 (1 day).
+    pub bundle_lifetime_ms: u64,
+    // If set, every UDP datagram is framed as a CCSDS Space Packet with this APID (0-2047), and
+    // datagrams with other APIDs are ignored. Default is none (unframed datagrams).
+    pub space_packet_apid: Option<u16>,
+    // Send telecommand rather than telemetry packets, as the ground side of a link would. Default is false.
+    pub space_packet_telecommand: bool,
+    // Flag the packets of a multi-chunk message as first/continuation/last segments.
+    // If false every packet is flagged unsegmented. Default is true.
+    pub space_packet_segmented: bool,
 }
 
 impl Default for Config {
@@ -66,8 +106,22 @@ impl Default for Config {
             radio_address: None,
             watched_directory: None,
             disk_usage: 1024 * 1024,
+            block_compression: "none".to_string(),
+            block_hash: "sha2-256".to_string(),
+            inline_limit: 0,
+            storage_key: None,
+            storage_key_file: None,
             chatter_ms: 10_000,
             shipper_throttle_packet_delay_ms: 0,
+            scrub_interval_ms: 60_000,
+            scrub_batch: 4,
+            routes: BTreeMap::default(),
+            bundle_agent: None,
+            bundle_eid: None,
+            bundle_lifetime_ms: 24 * 60 * 60 * 1000,
+            space_packet_apid: None,
+            space_packet_telecommand: false,
+            space_packet_segmented: true,
         }
     }
 }
@@ -103,14 +157,37 @@ impl Config {
         if config.mtu > MAX_MTU {
             bail!("Configured MTU is too large, cannot exceed {MAX_MTU}",);
         }
+        if config.space_packet_apid.unwrap_or_default() > 0x7FF {
+            bail!("space_packet_apid must fit in 11 bits");
+        }
+        if config.space_packet_apid.is_some() && config.bundle_agent.is_some() {
+            bail!("space_packet_apid cannot be combined with a bundle_agent");
+        }
         if config.block_size.is_none() {
-            let sz = mtu2block_size(config.mtu).into();
+            // Space packet headers come out of each datagram
+            let mtu = match config.space_packet_apid {
+                Some(_) => config.mtu.saturating_sub(6),
+                None => config.mtu,
+            };
+            let sz = mtu2block_size(mtu).into();
             info!("Used a mtu {} to deduce block_size {}", config.mtu, sz);
             config.block_size = Some(sz);
         }
         if config.block_size.unwrap() < 128 {
             bail!("block_size too small");
         }
+        if config.inline_limit > 64 {
+            bail!("inline_limit cannot exceed 64 bytes, the most a CID can carry");
+        }
+        if config.scrub_batch == 0 {
+            bail!("scrub_batch must be at least 1");
+        }
+        if config.storage_key.is_some() && config.storage_key_file.is_some() {
+            bail!("Set either storage_key or storage_key_file, not both");
+        }
+        if config.bundle_agent.is_some() && config.bundle_eid.is_none() {
+            bail!("bundle_eid must be set to use a bundle_agent");
+        }
         Ok(config)
     }
 }
@@ -3,7 +3,7 @@ use clap::{arg, Parser, ValueEnum};
 use log::{debug, error, info, trace};
 use messages::{ApplicationAPI, Message};
 use std::time::Duration;
-use transports::{Transport, UdpTransport, MAX_MTU};
+use transports::{SpacePacketConfig, Transport, UdpTransport, MAX_MTU};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(version, long_about = None, propagate_version = true)]
@@ -44,6 +44,11 @@ pub struct Cli {
         help = "An optional network address to bind to"
     )]
     bind_address: String,
+    #[arg(
+        long,
+        help = "Frame messages as CCSDS Space Packets with this APID, for an instance configured with space_packet_apid"
+    )]
+    apid: Option<u16>,
     #[clap(subcommand)]
     command: ApplicationAPI,
 }
@@ -52,6 +57,13 @@ impl Cli {
     pub async fn run(&self) -> Result<()> {
         let mut transport =
             UdpTransport::new(&self.bind_address, self.mtu, self.chunk_transmit_throttle)?;
+        if let Some(apid) = self.apid {
+            transport.set_space_packets(SpacePacketConfig {
+                apid,
+                telecommand: true,
+                segmented: true,
+            })?;
+        }
         transport
             .set_read_timeout(Some(Duration::from_secs(60 * 60)))
             .expect("Failed to set timeout");
@@ -78,10 +90,11 @@ impl Cli {
                     Ok((Message::ApplicationAPI(msg), _)) => {
                         let json = serde_json::to_string(&msg).unwrap();
                         info!("Received response: {msg:?} from {instance_addr} \nJSON: {json}");
-                        match self.output_format {
-                            Format::Json => println!("{json}"),
-                            Format::Debug => println!("{msg:?}"),
-                            Format::None => panic!("Response received {msg:?} which implies listen_mode==true but output_format==None {self:?}"),
+                        match (&self.output_format, &msg) {
+                            (Format::Json, _) => println!("{json}"),
+                            (Format::Debug, ApplicationAPI::StorageStats { stats }) => println!("{stats}"),
+                            (Format::Debug, _) => println!("{msg:?}"),
+                            (Format::None, _) => panic!("Response received {msg:?} which implies listen_mode==true but output_format==None {self:?}"),
                         }
 
                         return Ok(());
@@ -114,6 +127,10 @@ async fn main() -> Result<()> {
     if cli.mtu > MAX_MTU {
         bail!("Configured MTU is too large, cannot exceed {MAX_MTU}",);
     }
+    // Asking for stats is pointless without hearing the answer
+    if matches!(cli.command, ApplicationAPI::GetStorageStats) && cli.output_format == Format::None {
+        cli.output_format = Format::Debug;
+    }
     if cli.output_format != Format::None {
         cli.listen_mode = true;
     } else if cli.listen_mode {
@@ -3,12 +3,14 @@ use std::collections::VecDeque;
 use anyhow::Result;
 use async_stream::try_stream;
 use bytes::Bytes;
-use cid::Cid;
+use cid::{multihash::Code, Cid};
 use futures::{Stream, StreamExt, TryFutureExt, TryStreamExt};
 
 use crate::builder::encode_unixfs_pb;
+use crate::codecs::Codec;
 use crate::types::Block;
 use crate::unixfs::{dag_pb, unixfs_pb, DataType, Node, UnixfsNode};
+use crate::{inline_cid, DEFAULT_HASH};
 
 /// Default degree number for balanced tree, taken from unixfs specs
 /// <https://github.com/ipfs/specs/blob/main/UNIXFS.mdayout>
@@ -17,8 +19,13 @@ pub const DEFAULT_DEGREE: usize = 174;
 #[derive(Debug, PartialEq, Eq)]
 pub enum TreeBuilder {
     /// TreeBuilder that builds a "balanced tree" with a max degree size of
-    /// degree
-    Balanced { degree: usize },
+    /// degree, addressing its blocks by hash and inlining leaves of up to
+    /// inline_limit bytes into their parents
+    Balanced {
+        degree: usize,
+        hash: Code,
+        inline_limit: usize,
+    },
 }
 
 impl TreeBuilder {
@@ -28,7 +35,39 @@ impl TreeBuilder {
 
     pub fn balanced_tree_with_degree(degree: usize) -> Self {
         assert!(degree > 1);
-        TreeBuilder::Balanced { degree }
+        TreeBuilder::Balanced {
+            degree,
+            hash: DEFAULT_HASH,
+            inline_limit: 0,
+        }
+    }
+
+    /// Address blocks by hash rather than the default sha2-256
+    pub fn with_hash(self, hash: Code) -> Self {
+        match self {
+            TreeBuilder::Balanced {
+                degree,
+                inline_limit,
+                ..
+            } => TreeBuilder::Balanced {
+                degree,
+                hash,
+                inline_limit,
+            },
+        }
+    }
+
+    /// Address leaves of up to limit bytes by identity CIDs carrying their data, rather than
+    /// as blocks of their own. 0, the default, inlines nothing. A lone leaf is the root, so
+    /// is never inlined.
+    pub fn with_inline_limit(self, limit: usize) -> Self {
+        match self {
+            TreeBuilder::Balanced { degree, hash, .. } => TreeBuilder::Balanced {
+                degree,
+                hash,
+                inline_limit: limit,
+            },
+        }
     }
 
     pub fn stream_tree(
@@ -36,7 +75,11 @@ impl TreeBuilder {
         chunks: impl Stream<Item = std::io::Result<Bytes>> + Send,
     ) -> impl Stream<Item = Result<Block>> {
         match self {
-            TreeBuilder::Balanced { degree } => stream_balanced_tree(chunks, *degree),
+            TreeBuilder::Balanced {
+                degree,
+                hash,
+                inline_limit,
+            } => stream_balanced_tree(chunks, *degree, *hash, *inline_limit),
         }
     }
 }
@@ -50,6 +93,8 @@ struct LinkInfo {
 fn stream_balanced_tree(
     in_stream: impl Stream<Item = std::io::Result<Bytes>> + Send,
     degree: usize,
+    hash: Code,
+    inline_limit: usize,
 ) -> impl Stream<Item = Result<Block>> {
     try_stream! {
         // degree = 8
@@ -76,12 +121,14 @@ fn stream_balanced_tree(
         // when each node reaches `degree` number of links
         let mut tree: VecDeque<Vec<(Cid, LinkInfo)>> = VecDeque::new();
         tree.push_back(Vec::with_capacity(degree));
+        // the first leaf, if inlined, in case it turns out to be the root after all
+        let mut first_inlined = None;
 
         let hash_par: usize = 8;
 
         let in_stream = in_stream.err_into::<anyhow::Error>().map(|chunk| {
-            tokio::task::spawn_blocking(|| {
-                chunk.and_then(|chunk| TreeNode::Leaf(chunk).encode())
+            tokio::task::spawn_blocking(move || {
+                chunk.and_then(|chunk| TreeNode::Leaf(chunk).encode(hash))
             }).err_into::<anyhow::Error>()
         }).buffered(hash_par).map(|x| x.and_then(|x| x));
 
@@ -108,7 +155,7 @@ fn stream_balanced_tree(
 
                     // create node, keeping the cid
                     let links = std::mem::replace(&mut tree[i], Vec::with_capacity(degree));
-                    let (block, link_info) = TreeNode::Stem(links).encode()?;
+                    let (block, link_info) = TreeNode::Stem(links).encode(hash)?;
                     let cid = *block.cid();
                     yield block;
 
@@ -122,14 +169,25 @@ fn stream_balanced_tree(
 
             // now that we know the tree is in a "healthy" state to
             // recieve more links, add the link to the tree
-            tree[0].push((*block.cid(), link_info));
-            yield block;
+            if inline_limit > 0 && block.data().len() <= inline_limit {
+                let cid = inline_cid(Codec::Raw, block.data())?;
+                if tree.len() == 1 && tree[0].is_empty() {
+                    first_inlined = Some(block);
+                }
+                tree[0].push((cid, link_info));
+            } else {
+                tree[0].push((*block.cid(), link_info));
+                yield block;
+            }
             // at this point, the leaf node may have `degree` number of
             // links, but no other stem node will
         }
 
-        // our stream had 1 chunk that we have already yielded
+        // our stream had 1 chunk that we have already yielded, unless inlined
         if tree.len() == 1 && tree[0].len() == 1 {
+            if let Some(block) = first_inlined {
+                yield block;
+            }
             return
         }
 
@@ -137,7 +195,7 @@ fn stream_balanced_tree(
         // since all the stem nodes are able to recieve links
         // we don't have to worry about "overflow"
         while let Some(links) = tree.pop_front() {
-            let (block, link_info) = TreeNode::Stem(links).encode()?;
+            let (block, link_info) = TreeNode::Stem(links).encode(hash)?;
             let cid = *block.cid();
             yield block;
 
@@ -197,12 +255,12 @@ enum TreeNode {
 }
 
 impl TreeNode {
-    fn encode(self) -> Result<(Block, LinkInfo)> {
+    fn encode(self, hash: Code) -> Result<(Block, LinkInfo)> {
         match self {
             TreeNode::Leaf(bytes) => {
                 let len = bytes.len();
                 let node = UnixfsNode::Raw(bytes);
-                let block = node.encode()?;
+                let block = node.encode_with_hash(hash)?;
                 let link_info = LinkInfo {
                     // in a leaf the raw data len and encoded len are the same since our leaf
                     // nodes are raw unixfs nodes
@@ -214,7 +272,7 @@ impl TreeNode {
             TreeNode::Stem(links) => {
                 let mut encoded_len: u64 = links.iter().map(|(_, l)| l.encoded_len).sum();
                 let node = create_unixfs_node_from_links(links)?;
-                let block = node.encode()?;
+                let block = node.encode_with_hash(hash)?;
                 encoded_len += block.data().len() as u64;
                 let raw_data_len = node
                     .filesize()
@@ -253,7 +311,7 @@ mod tests {
         if num_chunks / degree == 0 {
             let chunk = chunks.next().await.unwrap().unwrap();
             let leaf = TreeNode::Leaf(chunk);
-            let (block, _) = leaf.encode().unwrap();
+            let (block, _) = leaf.encode(DEFAULT_HASH).unwrap();
             tree[0].push(block);
             return tree;
         }
@@ -261,7 +319,7 @@ mod tests {
         while let Some(chunk) = chunks.next().await {
             let chunk = chunk.unwrap();
             let leaf = TreeNode::Leaf(chunk);
-            let (block, link_info) = leaf.encode().unwrap();
+            let (block, link_info) = leaf.encode(DEFAULT_HASH).unwrap();
             links[0].push((*block.cid(), link_info));
             tree[0].push(block);
         }
@@ -273,7 +331,7 @@ mod tests {
             let mut links_layer = Vec::with_capacity(count);
             for links in prev_layer.chunks(degree) {
                 let stem = TreeNode::Stem(links.to_vec());
-                let (block, link_info) = stem.encode().unwrap();
+                let (block, link_info) = stem.encode(DEFAULT_HASH).unwrap();
                 links_layer.push((*block.cid(), link_info));
                 tree_layer.push(block);
             }
@@ -337,12 +395,12 @@ mod tests {
 
     fn make_leaf(data: usize) -> (Block, LinkInfo) {
         TreeNode::Leaf(BytesMut::from(&data.to_be_bytes()[..]).freeze())
-            .encode()
+            .encode(DEFAULT_HASH)
             .unwrap()
     }
 
     fn make_stem(links: Vec<(Cid, LinkInfo)>) -> (Block, LinkInfo) {
-        TreeNode::Stem(links).encode().unwrap()
+        TreeNode::Stem(links).encode(DEFAULT_HASH).unwrap()
     }
 
     #[tokio::test]
@@ -450,7 +508,7 @@ mod tests {
     async fn balanced_tree_test_leaf() {
         let num_chunks = 1;
         let expect = build_expect(num_chunks, 3).await;
-        let got = stream_balanced_tree(test_chunk_stream(1), 3);
+        let got = stream_balanced_tree(test_chunk_stream(1), 3, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -460,7 +518,7 @@ mod tests {
         let num_chunks = 3;
         let degrees = 3;
         let expect = build_expect(num_chunks, degrees).await;
-        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees);
+        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -470,7 +528,7 @@ mod tests {
         let degrees = 3;
         let num_chunks = 9;
         let expect = build_expect(num_chunks, degrees).await;
-        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees);
+        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -480,7 +538,7 @@ mod tests {
         let degrees = 3;
         let num_chunks = 10;
         let expect = build_expect(num_chunks, degrees).await;
-        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees);
+        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -490,7 +548,7 @@ mod tests {
         let num_chunks = 125;
         let degrees = 5;
         let expect = build_expect(num_chunks, degrees).await;
-        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees);
+        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -500,7 +558,7 @@ mod tests {
         let num_chunks = 780;
         let degrees = 11;
         let expect = build_expect(num_chunks, degrees).await;
-        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees);
+        let got = stream_balanced_tree(test_chunk_stream(num_chunks), degrees, DEFAULT_HASH, 0);
         tokio::pin!(got);
         ensure_equal(expect, got, num_chunks as u64 * CHUNK_SIZE).await;
     }
@@ -7,6 +7,7 @@ use std::{
 use anyhow::{ensure, Result};
 use async_recursion::async_recursion;
 use bytes::Bytes;
+use cid::multihash::Code;
 use futures::{
     stream::{self, BoxStream},
     Stream, StreamExt,
@@ -19,6 +20,7 @@ use crate::{
     chunker::{self, Chunker, ChunkerConfig, DEFAULT_CHUNK_SIZE_LIMIT},
     types::Block,
     unixfs::{dag_pb, unixfs_pb, DataType, Node, UnixfsNode},
+    DEFAULT_HASH, MAX_INLINE,
 };
 
 #[derive(Debug, PartialEq)]
@@ -253,6 +255,8 @@ pub struct FileBuilder {
     reader: Option<Pin<Box<dyn AsyncRead + Send>>>,
     chunker: Chunker,
     degree: usize,
+    hash: Code,
+    inline_limit: usize,
 }
 
 impl Default for FileBuilder {
@@ -263,6 +267,8 @@ impl Default for FileBuilder {
             reader: None,
             chunker: Chunker::Fixed(chunker::Fixed::default()),
             degree: DEFAULT_DEGREE,
+            hash: DEFAULT_HASH,
+            inline_limit: 0,
         }
     }
 }
@@ -279,6 +285,8 @@ impl Debug for FileBuilder {
             .field("name", &self.name)
             .field("chunker", &self.chunker)
             .field("degree", &self.degree)
+            .field("hash", &self.hash)
+            .field("inline_limit", &self.inline_limit)
             .field("reader", &reader)
             .finish()
     }
@@ -322,6 +330,19 @@ impl FileBuilder {
         self
     }
 
+    /// Set the multihash blocks are addressed by.
+    pub fn hash(mut self, hash: Code) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Inline chunks of up to limit bytes into their parent as identity CIDs, rather than
+    /// making blocks of them. At most [`MAX_INLINE`]; 0, the default, inlines nothing.
+    pub fn inline_limit(mut self, limit: usize) -> Self {
+        self.inline_limit = limit;
+        self
+    }
+
     pub fn content_bytes<B: Into<Bytes>>(mut self, content: B) -> Self {
         let bytes = content.into();
         self.reader = Some(Box::pin(std::io::Cursor::new(bytes)));
@@ -336,7 +357,14 @@ impl FileBuilder {
     pub async fn build(self) -> Result<File> {
         let degree = self.degree;
         let chunker = self.chunker;
-        let tree_builder = TreeBuilder::balanced_tree_with_degree(degree);
+        ensure!(
+            self.inline_limit <= MAX_INLINE,
+            "can't inline more than {MAX_INLINE} bytes into a CID, not {}",
+            self.inline_limit
+        );
+        let tree_builder = TreeBuilder::balanced_tree_with_degree(degree)
+            .with_hash(self.hash)
+            .with_inline_limit(self.inline_limit);
         if let Some(path) = self.path {
             let name = match self.name {
                 Some(n) => n,
@@ -687,6 +715,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_builder_hash() -> Result<()> {
+        let blake3 = crate::parse_hash("blake3")?;
+        let blocks: Vec<Block> = FileBuilder::new()
+            .name("bar.txt")
+            .content_bytes(vec![7u8; 1000])
+            .fixed_chunker(100)
+            .hash(blake3)
+            .build()
+            .await?
+            .encode()
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(blocks.len(), 11);
+        for block in &blocks {
+            assert_eq!(block.cid().hash().code(), u64::from(blake3));
+            block.validate()?;
+        }
+        let default = FileBuilder::new()
+            .name("bar.txt")
+            .content_bytes(vec![7u8; 1000])
+            .fixed_chunker(100)
+            .build()
+            .await?
+            .encode_root()
+            .await?;
+        assert_eq!(default.cid().hash().code(), u64::from(DEFAULT_HASH));
+        assert_ne!(default.cid(), blocks.last().unwrap().cid());
+        assert!(crate::parse_hash("md5").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_builder_inline_limit() -> Result<()> {
+        let encode = |len: usize, limit: usize| async move {
+            let blocks: Vec<Block> = FileBuilder::new()
+                .name("bar.txt")
+                .content_bytes(vec![7u8; len])
+                .fixed_chunker(100)
+                .inline_limit(limit)
+                .build()
+                .await?
+                .encode()
+                .await?
+                .try_collect()
+                .await?;
+            anyhow::Ok(blocks)
+        };
+        // The 10-byte tail goes into the root rather than a block of its own
+        let blocks = encode(1010, 32).await?;
+        assert_eq!(blocks.len(), 11);
+        let root = blocks.last().unwrap();
+        assert_eq!(root.links().len(), 11);
+        let tail = root.links().last().unwrap();
+        assert_eq!(crate::inlined_data(tail), Some(&[7u8; 10][..]));
+        assert!(blocks.iter().all(|b| b.cid() != tail));
+        for block in &blocks {
+            block.validate()?;
+        }
+        assert_eq!(encode(1010, 0).await?.len(), 12);
+
+        // A lone chunk is the root, so stays a block
+        let blocks = encode(10, 32).await?;
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(crate::inlined_data(blocks[0].cid()), None);
+
+        assert!(encode(10, MAX_INLINE + 1).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_recursive_dir_builder() -> Result<()> {
         let dir = DirectoryBuilder::new().build().await?;
@@ -9,9 +9,51 @@ pub use crate::types::{Block, Link, LinkRef, Links, LoadedCid, PbLinks, Source};
 
 use crate::codecs::Codec;
 use anyhow::{bail, Context as _, Result};
+use bytes::Bytes;
+use cid::multihash::{Code, Multihash, MultihashDigest};
 use cid::Cid;
 use libipld::{prelude::Codec as _, Ipld, IpldCodec};
 
+/// Multihash blocks are addressed by unless another is asked for.
+pub const DEFAULT_HASH: Code = Code::Sha2_256;
+
+/// Multihashes blocks may be addressed by, under their multicodec table names.
+const HASHES: &[(&str, Code)] = &[
+    ("sha2-256", Code::Sha2_256),
+    ("sha2-512", Code::Sha2_512),
+    ("sha3-256", Code::Sha3_256),
+    ("sha3-512", Code::Sha3_512),
+    ("blake2b-256", Code::Blake2b256),
+    ("blake2b-512", Code::Blake2b512),
+    ("blake2s-256", Code::Blake2s256),
+    ("blake3", Code::Blake3_256),
+];
+
+/// Most bytes a block can have and still be inlined into its CID with the identity multihash.
+pub const MAX_INLINE: usize = 64;
+
+/// Address data by an identity CID, which carries the data itself, so needs no block of its own.
+pub fn inline_cid(codec: Codec, data: &[u8]) -> Result<Cid> {
+    let hash = Multihash::wrap(Codec::Identity.into(), data)?;
+    Ok(Cid::new_v1(codec.into(), hash))
+}
+
+/// The data an identity CID carries, if it is one.
+pub fn inlined_data(cid: &Cid) -> Option<&[u8]> {
+    (cid.hash().code() == u64::from(Codec::Identity)).then(|| cid.hash().digest())
+}
+
+/// Look up a multihash by its multicodec name, e.g. "blake3".
+pub fn parse_hash(name: &str) -> Result<Code> {
+    match HASHES.iter().find(|(n, _)| *n == name) {
+        Some((_, code)) => Ok(*code),
+        None => bail!(
+            "unsupported hash {name:?}, expected one of {:?}",
+            HASHES.iter().map(|(n, _)| *n).collect::<Vec<_>>()
+        ),
+    }
+}
+
 /// Extract links from the given content.
 ///
 /// Links will be returned as a vec with meaningful order
@@ -29,6 +71,29 @@ pub fn parse_links(cid: &Cid, bytes: &[u8]) -> Result<Vec<Cid>> {
     Ok(cids)
 }
 
+/// Encode a document as a single block of an IPLD codec, DagCbor or DagJson.
+///
+/// The document is expected in that codec, though DagCbor also takes dag-json,
+/// so that JSON can be kept compactly as CBOR.
+pub fn encode_document(codec: Codec, hash: Code, document: &[u8]) -> Result<Block> {
+    let ipld_codec = match codec {
+        Codec::DagCbor => IpldCodec::DagCbor,
+        Codec::DagJson => IpldCodec::DagJson,
+        _ => bail!("unsupported document codec {:?}", codec),
+    };
+    let ipld: Ipld = match ipld_codec.decode(document) {
+        Ok(ipld) => ipld,
+        Err(_) if codec == Codec::DagCbor => IpldCodec::DagJson
+            .decode(document)
+            .context("document is neither dag-cbor nor dag-json")?,
+        Err(e) => return Err(e),
+    };
+    let data = ipld_codec.encode(&ipld)?;
+    let cid = Cid::new_v1(codec.into(), hash.digest(&data));
+    let links = parse_links(&cid, &data)?;
+    Ok(Block::new(cid, Bytes::from(data), links))
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -51,7 +116,48 @@ pub mod tests {
         let child: Cid = "bafkreiepinbumzepnoln7co5vea4kf3lcctnqolb3u6bvsellgznymt2uq"
             .try_into()
             .unwrap();
-        let expected = [child.clone(), child.clone()];
+        let expected = [child, child];
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    pub fn document_links_parsed() {
+        let child: Cid = "bafkreiepinbumzepnoln7co5vea4kf3lcctnqolb3u6bvsellgznymt2uq"
+            .try_into()
+            .unwrap();
+        let json = format!(r#"{{"temp": 21, "image": {{"/": "{child}"}}}}"#);
+        let as_json = encode_document(Codec::DagJson, DEFAULT_HASH, json.as_bytes()).unwrap();
+        assert_eq!(as_json.cid().codec(), u64::from(Codec::DagJson));
+        assert_eq!(as_json.links(), [child]);
+        as_json.validate().unwrap();
+
+        let as_cbor = encode_document(Codec::DagCbor, DEFAULT_HASH, json.as_bytes()).unwrap();
+        assert_eq!(as_cbor.cid().codec(), u64::from(Codec::DagCbor));
+        assert_eq!(as_cbor.links(), [child]);
+        assert!(as_cbor.data().len() < as_json.data().len());
+        // Already CBOR, so kept as is
+        let again = encode_document(Codec::DagCbor, DEFAULT_HASH, as_cbor.data()).unwrap();
+        assert_eq!(again.cid(), as_cbor.cid());
+
+        assert!(encode_document(Codec::DagJson, DEFAULT_HASH, as_cbor.data()).is_err());
+        assert!(encode_document(Codec::Raw, DEFAULT_HASH, json.as_bytes()).is_err());
+    }
+
+    #[test]
+    pub fn inlined_data_roundtrip() {
+        let cid = inline_cid(Codec::Raw, b"tiny").unwrap();
+        assert_eq!(cid.codec(), u64::from(Codec::Raw));
+        assert_eq!(inlined_data(&cid), Some(&b"tiny"[..]));
+        Block::new(cid, Bytes::from_static(b"tiny"), vec![])
+            .validate()
+            .unwrap();
+        assert!(Block::new(cid, Bytes::from_static(b"tinier"), vec![])
+            .validate()
+            .is_err());
+
+        assert!(inline_cid(Codec::Raw, &[0; MAX_INLINE]).is_ok());
+        assert!(inline_cid(Codec::Raw, &[0; MAX_INLINE + 1]).is_err());
+        let hashed = Cid::new_v1(Codec::Raw.into(), DEFAULT_HASH.digest(b"tiny"));
+        assert_eq!(inlined_data(&hashed), None);
+    }
 }
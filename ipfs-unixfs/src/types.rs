@@ -6,7 +6,7 @@ use cid::Cid;
 use libipld::error::{InvalidMultihash, UnsupportedMultihash};
 use multihash::{Code, MultihashDigest};
 
-use crate::{codecs::Codec, parse_links, unixfs::dag_pb};
+use crate::{codecs::Codec, inlined_data, parse_links, unixfs::dag_pb};
 
 #[derive(Debug)]
 pub struct LoadedCid {
@@ -55,14 +55,21 @@ impl Block {
 
     /// Validate the block. Will return an error if the hash or the links are wrong.
     pub fn validate(&self) -> Result<()> {
-        // check that the cid is supported
-        let code = self.cid.hash().code();
-        let mh = Code::try_from(code)
-            .map_err(|_| UnsupportedMultihash(code))?
-            .digest(&self.data);
-        // check that the hash matches the data
-        if mh.digest() != self.cid.hash().digest() {
-            return Err(InvalidMultihash(mh.to_bytes()).into());
+        // an identity cid is the data itself
+        if let Some(inlined) = inlined_data(&self.cid) {
+            if inlined != self.data {
+                return Err(InvalidMultihash(self.cid.hash().to_bytes()).into());
+            }
+        } else {
+            // check that the cid is supported
+            let code = self.cid.hash().code();
+            let mh = Code::try_from(code)
+                .map_err(|_| UnsupportedMultihash(code))?
+                .digest(&self.data);
+            // check that the hash matches the data
+            if mh.digest() != self.cid.hash().digest() {
+                return Err(InvalidMultihash(mh.to_bytes()).into());
+            }
         }
         // check that the links are complete
         let expected_links = parse_links(&self.cid, &self.data)?;
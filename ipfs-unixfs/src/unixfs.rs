@@ -7,7 +7,10 @@ use std::{
 
 use anyhow::{anyhow, bail, ensure, Result};
 use bytes::{Buf, Bytes};
-use cid::{multihash::MultihashDigest, Cid};
+use cid::{
+    multihash::{Code, MultihashDigest},
+    Cid,
+};
 use futures::{future::BoxFuture, stream::BoxStream, Stream};
 use prost::Message;
 
@@ -15,6 +18,7 @@ use crate::{
     chunker::DEFAULT_CHUNK_SIZE_LIMIT,
     codecs::Codec,
     types::{Block, Link, LinkRef, Links, PbLinks},
+    DEFAULT_HASH,
 };
 
 pub mod unixfs_pb {
@@ -153,11 +157,15 @@ impl UnixfsNode {
     }
 
     pub fn encode(&self) -> Result<Block> {
+        self.encode_with_hash(DEFAULT_HASH)
+    }
+
+    pub fn encode_with_hash(&self, hash: Code) -> Result<Block> {
         let res = match self {
             UnixfsNode::Raw(data) => {
                 let out = data.clone();
                 let links = vec![];
-                let cid = Cid::new_v1(Codec::Raw as _, cid::multihash::Code::Sha2_256.digest(&out));
+                let cid = Cid::new_v1(Codec::Raw as _, hash.digest(&out));
                 Block::new(cid, out, links)
             }
             UnixfsNode::RawNode(node)
@@ -169,10 +177,7 @@ impl UnixfsNode {
                     .links()
                     .map(|x| Ok(x?.cid))
                     .collect::<Result<Vec<_>>>()?;
-                let cid = Cid::new_v1(
-                    Codec::DagPb as _,
-                    cid::multihash::Code::Sha2_256.digest(&out),
-                );
+                let cid = Cid::new_v1(Codec::DagPb as _, hash.digest(&out));
                 Block::new(cid, out, links)
             }
         };
@@ -0,0 +1,320 @@
+// Only the providers which keep things on disk (files, sqlite) pack blocks for it
+#![cfg_attr(not(any(feature = "files", feature = "sqlite")), allow(dead_code))]
+
+use crate::{compression::Compression, error::StorageError};
+#[cfg(feature = "encryption")]
+use anyhow::anyhow;
+use anyhow::{bail, Result};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use cid::multibase;
+#[cfg(feature = "encryption")]
+use std::fs;
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+const KEY_LEN: usize = 32;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+// Sealed as the key check of an encrypted store
+const KEY_CHECK: &[u8] = b"myceli storage";
+
+// Key for sealing blocks and names at rest. What's sealed is bound to the CID it's stored under.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct Cipher(ChaCha20Poly1305);
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    // A 256-bit key, as 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            bail!("Storage key must be {} hex digits", KEY_LEN * 2);
+        }
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?;
+        Ok(Self::from_bytes(&key))
+    }
+
+    // A file holding the key, either as 32 raw bytes or as hex
+    pub fn from_key_file(path: &Path) -> Result<Self> {
+        let contents = fs::read(path)?;
+        if contents.len() == KEY_LEN {
+            Ok(Self::from_bytes(&contents))
+        } else {
+            Self::from_hex(std::str::from_utf8(&contents)?)
+        }
+    }
+
+    fn from_bytes(key: &[u8]) -> Self {
+        Self(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    // A fresh random nonce, followed by the ciphertext and tag
+    pub fn seal(&self, data: &[u8], aad: &str) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: data,
+            aad: aad.as_bytes(),
+        };
+        let sealed = self
+            .0
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("Unable to encrypt {aad}"))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    pub fn open(&self, sealed: &[u8], aad: &str) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            bail!("Sealed {aad} is too short");
+        }
+        let (nonce, msg) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg,
+            aad: aad.as_bytes(),
+        };
+        self.0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("Unable to decrypt {aad}"))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+// Without encryption built in there's no key to be had, so a store can only be read if it's unencrypted
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone, Debug)]
+pub struct Cipher(std::convert::Infallible);
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub fn from_hex(_hex: &str) -> Result<Self> {
+        bail!("Storage encryption is not built in")
+    }
+
+    pub fn from_key_file(_path: &Path) -> Result<Self> {
+        bail!("Storage encryption is not built in")
+    }
+
+    pub fn seal(&self, _data: &[u8], _aad: &str) -> Result<Vec<u8>> {
+        match self.0 {}
+    }
+
+    pub fn open(&self, _sealed: &[u8], _aad: &str) -> Result<Vec<u8>> {
+        match self.0 {}
+    }
+}
+
+// How a provider turns blocks and names into what it keeps on disk, and back
+#[derive(Debug, Default)]
+pub(crate) struct AtRest {
+    pub compression: Compression,
+    cipher: Option<Cipher>,
+    // Present once the store is encrypted, which is then true of everything in it
+    key_check: Option<Vec<u8>>,
+    // Set for a store opened only to be read, which can't start being encrypted
+    pub read_only: bool,
+}
+
+impl AtRest {
+    pub fn new(key_check: Option<Vec<u8>>) -> Self {
+        Self {
+            key_check,
+            ..Default::default()
+        }
+    }
+
+    // Returns a key check to persist when this starts encrypting an empty store
+    pub fn set_cipher(&mut self, cipher: Option<Cipher>, empty: bool) -> Result<Option<Vec<u8>>> {
+        let mut new_check = None;
+        match (&cipher, &self.key_check) {
+            (None, None) => {}
+            (None, Some(_)) => bail!(StorageError::Locked),
+            (Some(c), Some(check)) => {
+                if c.open(check, "").ok().as_deref() != Some(KEY_CHECK) {
+                    bail!("The storage key given doesn't open this storage");
+                }
+            }
+            (Some(_), None) if self.read_only => {
+                bail!("Storage isn't encrypted, it needs no key to be read")
+            }
+            (Some(c), None) => {
+                if !empty {
+                    bail!("Storage already holds unencrypted data, convert it into an encrypted store instead");
+                }
+                new_check = Some(c.seal(KEY_CHECK, "")?);
+                self.key_check = new_check.clone();
+            }
+        }
+        self.cipher = cipher;
+        Ok(new_check)
+    }
+
+    // Compresses then seals a block's data, returning the compression used
+    pub fn pack(&self, cid: &str, data: &[u8]) -> Result<(Compression, Vec<u8>)> {
+        let (compression, data) = self.compression.compress(data);
+        match &self.cipher {
+            Some(c) => Ok((compression, c.seal(&data, cid)?)),
+            None => Ok((compression, data)),
+        }
+    }
+
+    pub fn unpack(&self, cid: &str, compression: Compression, data: Vec<u8>) -> Result<Vec<u8>> {
+        let opened = match (&self.cipher, &self.key_check) {
+            (Some(c), _) => c.open(&data, cid),
+            (None, Some(_)) => bail!(StorageError::Locked),
+            (None, None) => Ok(data),
+        };
+        opened
+            .and_then(|d| compression.decompress(d))
+            .map_err(|e| StorageError::CorruptBlock(cid.to_string(), e.to_string()).into())
+    }
+
+    // Names are kept as text, so sealed ones are base64
+    pub fn seal_name(&self, cid: &str, name: &str) -> Result<String> {
+        match &self.cipher {
+            Some(c) => Ok(multibase::encode(
+                multibase::Base::Base64,
+                c.seal(name.as_bytes(), &name_aad(cid))?,
+            )),
+            None => Ok(name.to_string()),
+        }
+    }
+
+    pub fn open_name(&self, cid: &str, name: String) -> Result<String> {
+        match (&self.cipher, &self.key_check) {
+            (Some(c), _) => {
+                let (_, sealed) = multibase::decode(name)?;
+                Ok(String::from_utf8(c.open(&sealed, &name_aad(cid))?)?)
+            }
+            (None, Some(_)) => bail!(StorageError::Locked),
+            (None, None) => Ok(name),
+        }
+    }
+}
+
+fn name_aad(cid: &str) -> String {
+    format!("name of {cid}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "encryption")]
+    use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+
+    #[cfg(feature = "encryption")]
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[cfg(feature = "encryption")]
+    fn cipher(k: &str) -> Cipher {
+        Cipher::from_hex(&k.repeat(64)).unwrap()
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_key_check_guards_store() {
+        let mut fresh = AtRest::default();
+        let check = fresh.set_cipher(Some(cipher("a")), true).unwrap().unwrap();
+        let (_, sealed) = fresh.pack("cid", b"data").unwrap();
+        let name = fresh.seal_name("cid", "file.txt").unwrap();
+        assert_ne!(name, "file.txt");
+
+        let mut reopened = AtRest::new(Some(check));
+        assert!(matches!(
+            reopened
+                .unpack("cid", Compression::None, sealed.clone())
+                .unwrap_err()
+                .downcast_ref(),
+            Some(StorageError::Locked)
+        ));
+        assert!(reopened.set_cipher(Some(cipher("b")), false).is_err());
+        assert!(matches!(
+            reopened.set_cipher(None, false).unwrap_err().downcast_ref(),
+            Some(StorageError::Locked)
+        ));
+        assert_eq!(reopened.set_cipher(Some(cipher("a")), false).unwrap(), None);
+        assert_eq!(
+            reopened
+                .unpack("cid", Compression::None, sealed.clone())
+                .unwrap(),
+            b"data"
+        );
+        assert_eq!(reopened.open_name("cid", name.clone()).unwrap(), "file.txt");
+        assert!(reopened.open_name("other", name).is_err());
+        assert!(matches!(
+            reopened
+                .unpack("other", Compression::None, sealed)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(StorageError::CorruptBlock(..))
+        ));
+
+        let mut plain = AtRest::default();
+        assert!(plain.set_cipher(Some(cipher("a")), false).is_err());
+        assert_eq!(plain.set_cipher(None, false).unwrap(), None);
+
+        // Nothing is written into a store opened only to be read
+        let mut read_only = AtRest {
+            read_only: true,
+            ..AtRest::default()
+        };
+        assert!(read_only.set_cipher(Some(cipher("a")), true).is_err());
+        assert_eq!(read_only.set_cipher(None, true).unwrap(), None);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_seal_round_trip() {
+        let cipher = Cipher::from_hex(KEY).unwrap();
+        let sealed = cipher.seal(b"payload", "cid").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + 7], b"payload");
+        assert_ne!(sealed, cipher.seal(b"payload", "cid").unwrap());
+        assert_eq!(cipher.open(&sealed, "cid").unwrap(), b"payload");
+        assert!(cipher.open(&sealed, "other").is_err());
+        assert!(cipher.open(&sealed[1..], "cid").is_err());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_key_sources() {
+        let dir = TempDir::new().unwrap();
+        let raw = dir.child("raw");
+        raw.write_binary(&[7u8; KEY_LEN]).unwrap();
+        let hex = dir.child("hex");
+        hex.write_binary(format!("{}\n", "07".repeat(KEY_LEN)).as_bytes())
+            .unwrap();
+        let sealed = Cipher::from_key_file(raw.path())
+            .unwrap()
+            .seal(b"x", "")
+            .unwrap();
+        let from_hex = Cipher::from_key_file(hex.path()).unwrap();
+        assert_eq!(from_hex.open(&sealed, "").unwrap(), b"x");
+        assert!(Cipher::from_key_file(dir.child("missing").path()).is_err());
+        assert!(Cipher::from_hex("0011").is_err());
+        assert!(Cipher::from_hex(&"zz".repeat(KEY_LEN)).is_err());
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn test_no_key_without_encryption() {
+        assert!(Cipher::from_hex(&"07".repeat(32)).is_err());
+        let mut locked = AtRest::new(Some(b"check".to_vec()));
+        assert!(matches!(
+            locked.set_cipher(None, false).unwrap_err().downcast_ref(),
+            Some(StorageError::Locked)
+        ));
+        assert_eq!(AtRest::default().set_cipher(None, true).unwrap(), None);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::util::verify_dag;
 use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
-use cid::Cid;
+use cid::{multihash::MultihashDigest, Cid};
 use ipfs_unixfs::Block;
 use std::fmt;
 use std::str::FromStr;
@@ -21,6 +21,36 @@ impl StoredBlock {
         let block: Block = self.try_into()?;
         block.validate()
     }
+
+    // Whether the data still hashes to the CID, unlike validate() not caring whether links parse
+    pub fn hash_matches(&self) -> Result<bool> {
+        let cid = Cid::from_str(&self.cid)?;
+        if let Some(data) = ipfs_unixfs::inlined_data(&cid) {
+            return Ok(data == self.data);
+        }
+        let code = cid::multihash::Code::try_from(cid.hash().code())?;
+        Ok(code.digest(&self.data).digest() == cid.hash().digest())
+    }
+}
+
+// The block an identity CID carries within itself, so which is always at hand without being stored
+pub fn inlined_block(cid: &str) -> Option<StoredBlock> {
+    let parsed = Cid::from_str(cid).ok()?;
+    let data = ipfs_unixfs::inlined_data(&parsed)?.to_vec();
+    let links = ipfs_unixfs::parse_links(&parsed, &data).ok()?;
+    Some(StoredBlock {
+        cid: cid.to_owned(),
+        filename: None,
+        data,
+        links: links.iter().map(|l| l.to_string()).collect(),
+    })
+}
+
+// Whether cid carries its own block, so is never stored, fetched or sent
+pub fn is_inlined(cid: &str) -> bool {
+    Cid::from_str(cid)
+        .map(|c| ipfs_unixfs::inlined_data(&c).is_some())
+        .unwrap_or(false)
 }
 
 impl fmt::Debug for StoredBlock {
@@ -119,6 +149,14 @@ mod tests {
         assert!(stored_block.validate().is_ok());
     }
 
+    #[test]
+    pub fn test_hash_matches_detects_flipped_bit() {
+        let mut block = generate_stored_blocks(1).unwrap().pop().unwrap();
+        assert!(block.hash_matches().unwrap());
+        block.data[0] ^= 0x10;
+        assert!(!block.hash_matches().unwrap());
+    }
+
     #[test]
     pub fn test_invalid_block_no_links() {
         let mut blocks = generate_stored_blocks(1).unwrap();
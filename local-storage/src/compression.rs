@@ -0,0 +1,129 @@
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+// How a block's bytes are kept at rest. CIDs are always of the uncompressed bytes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+impl Compression {
+    // Returns the compression actually used, which is none whenever it wouldn't save anything
+    pub fn compress(self, data: &[u8]) -> (Compression, Vec<u8>) {
+        let packed: Option<Vec<u8>> = match self {
+            Compression::None => None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(data, ZSTD_LEVEL).ok(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        };
+        match packed {
+            Some(p) if p.len() < data.len() => (self, p),
+            _ => (Compression::None, data.to_vec()),
+        }
+    }
+
+    pub fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(zstd::decode_all(data.as_slice())?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(&data)?),
+            #[allow(unreachable_patterns)]
+            _ => bail!("Block stored with {self:?} compression, which isn't built in"),
+        }
+    }
+
+    // As recorded alongside blocks in sqlite
+    pub fn code(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => bail!("Unknown block compression code {code}"),
+        }
+    }
+
+    // Suffix of a compressed block's file
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Zstd => "zst",
+            Compression::Lz4 => "lz4",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" | "" => Ok(Compression::None),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            #[cfg(feature = "lz4")]
+            "lz4" => Ok(Compression::Lz4),
+            _ => bail!("Block compression {s} is unknown or not built in"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    fn round_trip(c: Compression) {
+        let data = b"abcdefgh".repeat(64);
+        let (used, packed) = c.compress(&data);
+        assert_eq!(used, c);
+        assert!(packed.len() < data.len());
+        assert_eq!(used.decompress(packed).unwrap(), data);
+        assert_eq!(Compression::from_code(used.code()).unwrap(), c);
+    }
+
+    #[test]
+    fn test_incompressible_stored_as_is() {
+        let data: Vec<u8> = (0..=255u8).collect();
+        for c in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            assert_eq!(c.compress(&data), (Compression::None, data.clone()));
+        }
+    }
+
+    #[test]
+    fn test_unknown_rejected() {
+        assert_eq!(Compression::from_str("none").unwrap(), Compression::None);
+        assert!(Compression::from_str("gzip").is_err());
+        assert!(Compression::from_code(9).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_round_trip() {
+        round_trip(Compression::Zstd);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4_round_trip() {
+        round_trip(Compression::Lz4);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::provider::StorageProvider;
+use anyhow::Result;
+use cid::Cid;
+use log::{error, info, warn};
+
+// Blocks read from the source at a time
+const BATCH: usize = 64;
+
+// What was copied across
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Converted {
+    pub blocks: u64,
+    pub names: u64,
+    pub dangling: u64,
+    // Blocks whose data no longer matches their CID, left dangling in the destination to be fetched again
+    pub corrupt: Vec<String>,
+    // Blocks copied without checking, their hash function being unsupported
+    pub unverified: u64,
+}
+
+// Copies every block (with its name, priority and expiry) and every dangling CID from one provider into another
+pub fn convert(from: &dyn StorageProvider, to: &mut dyn StorageProvider) -> Result<Converted> {
+    let mut result = Converted::default();
+    let mut after = String::new();
+    loop {
+        let cids = from.get_cids_after(&after, BATCH)?;
+        let Some(last) = cids.last() else {
+            break;
+        };
+        after = last.clone();
+        for cid in cids {
+            let block = from.get_block_by_cid(&cid)?;
+            match block.hash_matches() {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Block {cid} does not match its hash, not copying it");
+                    to.ack_cid(&Cid::try_from(cid.as_str())?);
+                    result.corrupt.push(cid);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Copying {cid} unverified: {e:?}");
+                    result.unverified += 1;
+                }
+            }
+            to.import_block(&block)?;
+            result.blocks += 1;
+            if block.filename.is_some() {
+                result.names += 1;
+            }
+            let priority = from.get_priority(&cid)?;
+            if priority > 0 {
+                to.set_priority(&cid, priority)?;
+            }
+            if let Some(expires) = from.get_expiry(&cid)? {
+                to.set_expiry(&cid, expires)?;
+            }
+        }
+        info!("Copied {} blocks so far", result.blocks);
+    }
+    for cid in from.get_dangling_cids()? {
+        if !to.has_cid(&cid) {
+            to.ack_cid(&cid);
+            result.dangling += 1;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block::StoredBlock, memory_provider::MemoryStorageProvider};
+    use cid::multihash::MultihashDigest;
+
+    fn cid(s: &[u8]) -> String {
+        Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(s)).to_string()
+    }
+
+    #[test]
+    fn test_convert_copies_and_verifies() {
+        let mut from = MemoryStorageProvider::new(u64::MAX);
+        let (root, good, bad, absent) = (cid(b"r"), cid(b"g"), cid(b"b"), cid(b"a"));
+        let blocks = [
+            (
+                &root,
+                b"r".to_vec(),
+                vec![good.clone(), bad.clone(), absent.clone()],
+            ),
+            (&good, b"g".to_vec(), vec![]),
+            (&bad, b"flipped".to_vec(), vec![]),
+        ];
+        for (c, data, links) in blocks {
+            from.import_block(&StoredBlock {
+                cid: c.clone(),
+                filename: None,
+                data,
+                links,
+            })
+            .unwrap();
+        }
+        from.name_dag(&root, "r.txt").unwrap();
+        from.set_priority(&root, 5).unwrap();
+        from.set_expiry(&root, u64::MAX >> 1).unwrap();
+
+        let mut to = MemoryStorageProvider::new(u64::MAX);
+        let converted = convert(&from, &mut to).unwrap();
+        assert_eq!(converted.blocks, 2);
+        assert_eq!(converted.names, 1);
+        assert_eq!(converted.corrupt, vec![bad.clone()]);
+        assert_eq!(converted.unverified, 0);
+        assert_eq!(
+            to.get_block_by_cid(&root).unwrap(),
+            from.get_block_by_cid(&root).unwrap()
+        );
+        assert_eq!(to.get_priority(&root).unwrap(), 5);
+        assert_eq!(to.get_expiry(&root).unwrap(), Some(u64::MAX >> 1));
+        let mut missing = to.get_missing_cid_blocks(&root).unwrap();
+        missing.sort();
+        let mut expected = vec![bad, absent];
+        expected.sort();
+        assert_eq!(missing, expected);
+        assert_eq!(to.get_dangling_cids().unwrap().len(), 2);
+    }
+
+    #[cfg(all(feature = "sqlite", feature = "files"))]
+    #[test]
+    fn test_convert_sqlite_to_files_and_back() {
+        use crate::{
+            block::validate_dag, file_provider::FileStorageProvider,
+            sql_provider::SqliteStorageProvider, storage::Storage,
+        };
+        use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+        use std::sync::{Arc, Mutex};
+
+        let dir = TempDir::new().unwrap();
+        let sqlite = SqliteStorageProvider::new(dir.child("storage.db").to_str().unwrap()).unwrap();
+        let from = Arc::new(Mutex::new(sqlite));
+        let mut storage = Storage::new(from.clone(), 256);
+        let file = dir.child("data");
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        file.write_binary(&data).unwrap();
+        let root = storage.import_path(file.path()).unwrap();
+
+        let mut to =
+            FileStorageProvider::new(dir.child("files").to_str().unwrap(), u64::MAX).unwrap();
+        let converted = convert(&*from.lock().unwrap(), &mut to).unwrap();
+        assert!(converted.corrupt.is_empty());
+        assert_eq!(
+            converted.blocks as usize,
+            from.lock().unwrap().get_available_cids().unwrap().len()
+        );
+        assert!(to.get_missing_cid_blocks(&root).unwrap().is_empty());
+        assert_eq!(to.get_name(&root).unwrap(), "data");
+        validate_dag(&to.get_all_dag_blocks(&root).unwrap()).unwrap();
+
+        // Blocks come back in CID order, children often ahead of their parents
+        let mut back = SqliteStorageProvider::new(dir.child("back.db").to_str().unwrap()).unwrap();
+        assert_eq!(convert(&to, &mut back).unwrap().blocks, converted.blocks);
+        assert!(back.get_missing_cid_blocks(&root).unwrap().is_empty());
+        validate_dag(&back.get_all_dag_blocks(&root).unwrap()).unwrap();
+    }
+}
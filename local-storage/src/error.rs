@@ -6,4 +6,12 @@ pub enum StorageError {
     BlockNotFound(String, String),
     #[error("DAG incomplete {0}")]
     DagIncomplete(String),
+    #[error("Storage schema version {0} is newer than this build understands ({1})")]
+    SchemaTooNew(u32, u32),
+    #[error("Storage schema version {0} is older than this build expects ({1}), run myceli on it first to upgrade it")]
+    SchemaTooOld(u32, u32),
+    #[error("Stored block {0} is corrupt: {1}")]
+    CorruptBlock(String, String),
+    #[error("Storage is encrypted, a key is needed to read it")]
+    Locked,
 }
@@ -1,41 +1,122 @@
-use crate::{block::StoredBlock, provider::StorageProvider};
-use anyhow::Result;
+use crate::{
+    at_rest::{AtRest, Cipher},
+    block::{inlined_block, StoredBlock},
+    compression::Compression,
+    error::StorageError,
+    provider::{
+        expired_dag_cids, now_secs, pinned_bytes, GcActivity, StorageProvider, StorageStats,
+    },
+};
+use anyhow::{bail, Result};
 use cid::{multibase, Cid};
 use log::{debug, error, info, trace};
 use std::{
+    cell::Cell,
     cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
     fmt::Debug,
     fs,
     fs::{canonicalize, create_dir_all, read_dir, DirEntry, File},
     io::{Read, Write},
-    path::PathBuf,
-    time::SystemTime,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
+// Counting DAGs and dangling CIDs walks the store, so stats redo it at most this often
+const RECOUNT_INTERVAL: Duration = Duration::from_secs(60);
+
 pub(crate) struct FileStorageProvider {
     dir: PathBuf,
     usage: u64,
     old_blocks: Vec<OnDiskBlock>,
     high: u64,
+    block_count: u64,
+    gc: GcActivity,
+    at_rest: AtRest,
+    // Usage and block count as last written to the tally file, None once they've changed since
+    tallied: Option<(u64, u64)>,
+    imports: u64,
+    // Staged files written since the last sync
+    unsynced: Vec<PathBuf>,
+    // DAGs, dangling CIDs and pinned bytes as last counted, and when
+    counted: Cell<Option<(Instant, u64, u64, u64)>>,
 }
 impl FileStorageProvider {
     #[allow(dead_code)]
     pub fn new(storage_folder: &str, high_usage: u64) -> Result<Self> {
-        let mut me = Self {
-            dir: storage_folder.into(),
-            usage: 0,
-            old_blocks: vec![],
-            high: high_usage,
-        };
+        let mut me = Self::at(storage_folder.into(), high_usage);
         create_dir_all(me.blocks())?;
         me.dir = canonicalize(storage_folder)?;
+        me.at_rest = AtRest::new(fs::read(me.key_check()).ok());
         debug!("FileStorageProvider({:?})", &me.dir);
         create_dir_all(me.cids())?;
         create_dir_all(me.names())?;
-        me.count_blocks();
-        me.prune_names()?;
+        create_dir_all(me.priorities())?;
+        create_dir_all(me.expiries())?;
+        create_dir_all(me.quarantine())?;
+        create_dir_all(me.state())?;
+        create_dir_all(me.staging())?;
+        let moved = me.shard_flat(&me.blocks())? + me.shard_flat(&me.cids())?;
+        if moved > 0 {
+            info!("Moved {moved} files from the flat layout into shards");
+        }
+        let recovered = me.recover_staged()?;
+        // A tally is only left by a provider whose changes had all settled, so with one there's no
+        // half-done import leaving unreferenced blocks to prune, and no need to walk the store in full
+        match me.read_tally() {
+            Some(tally) if moved == 0 && recovered == 0 => {
+                (me.usage, me.block_count) = tally;
+                me.tallied = Some(tally);
+            }
+            _ => {
+                me.prune_names()?;
+                me.count_blocks();
+            }
+        }
+        me.write_tally()?;
         Ok(me)
     }
+    // Opens a store only to read from it, e.g. to convert it, leaving it exactly as it was.
+    // It has to be in the current layout with no imports left to finish, as nothing gets moved.
+    pub fn open_read_only(storage_folder: &str) -> Result<Self> {
+        let mut me = Self::at(canonicalize(storage_folder)?, 0);
+        let has_files = |dir: &Path| -> Result<bool> {
+            Ok(read_dir(dir)?
+                .flat_map(|r| r.ok())
+                .any(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false)))
+        };
+        let staged = read_dir(me.staging())
+            .map(|mut r| r.next().is_some())
+            .unwrap_or(false);
+        if has_files(&me.blocks())? || has_files(&me.cids())? || staged {
+            bail!(
+                "Storage at {:?} is in an older layout or has imports to finish, run myceli on it first",
+                &me.dir
+            );
+        }
+        me.at_rest = AtRest::new(fs::read(me.key_check()).ok());
+        me.at_rest.read_only = true;
+        match me.read_tally() {
+            Some(tally) => (me.usage, me.block_count) = tally,
+            None => me.count_blocks(),
+        }
+        Ok(me)
+    }
+    fn at(dir: PathBuf, high_usage: u64) -> Self {
+        Self {
+            dir,
+            usage: 0,
+            old_blocks: vec![],
+            high: high_usage,
+            block_count: 0,
+            gc: GcActivity::default(),
+            at_rest: AtRest::default(),
+            tallied: None,
+            imports: 0,
+            unsynced: vec![],
+            counted: Cell::new(None),
+        }
+    }
     fn blocks(&self) -> PathBuf {
         self.dir.join("blocks")
     }
@@ -45,10 +126,194 @@ impl FileStorageProvider {
     fn names(&self) -> PathBuf {
         self.dir.join("names")
     }
+    fn priorities(&self) -> PathBuf {
+        self.dir.join("priorities")
+    }
+    fn quarantine(&self) -> PathBuf {
+        self.dir.join("quarantine")
+    }
+    fn expiries(&self) -> PathBuf {
+        self.dir.join("expiries")
+    }
+    fn state(&self) -> PathBuf {
+        self.dir.join("state")
+    }
+    fn key_check(&self) -> PathBuf {
+        self.dir.join("key_check")
+    }
+    fn tally(&self) -> PathBuf {
+        self.dir.join("tally")
+    }
+    fn staging(&self) -> PathBuf {
+        self.dir.join("staging")
+    }
     fn block_path(&self, cid: &Cid) -> PathBuf {
         let mh = cid.hash().to_bytes();
         let hash = multibase::encode(multibase::Base::Base36Lower, mh);
-        self.blocks().join(hash)
+        shard(&self.blocks(), &hash)
+    }
+    fn cid_path(&self, cid: &str) -> PathBuf {
+        shard(&self.cids(), cid)
+    }
+    // Moves files left directly in dir by the flat layout into their shards, returning how many
+    fn shard_flat(&self, dir: &Path) -> Result<usize> {
+        let mut moved = 0;
+        for e in read_dir(dir)?.flat_map(|r| r.ok()) {
+            if !e.file_type()?.is_file() {
+                continue;
+            }
+            let path = e.path();
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let to = shard(dir, stem).with_file_name(e.file_name());
+            create_dir_all(to.parent().unwrap_or(dir))?;
+            fs::rename(&path, to)?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+    fn read_tally(&self) -> Option<(u64, u64)> {
+        let tally = fs::read_to_string(self.tally()).ok()?;
+        let (usage, blocks) = tally.trim().split_once(' ')?;
+        Some((usage.parse().ok()?, blocks.parse().ok()?))
+    }
+    // Persists usage and block count so the next startup needn't walk every block to learn them.
+    // Written as changes settle (each GC pass), and removed by mark_dirty before the next change.
+    fn write_tally(&mut self) -> Result<()> {
+        let tally = (self.usage, self.block_count);
+        if Some(tally) != self.tallied {
+            let temp = self.dir.join("tally.tmp");
+            fs::write(&temp, format!("{} {}", tally.0, tally.1))?;
+            fs::rename(temp, self.tally())?;
+            self.tallied = Some(tally);
+        }
+        Ok(())
+    }
+    // Removes the tally before usage or the block count changes, so after a reset mid-change the
+    // next startup finds none and recounts rather than trusting a stale one
+    fn mark_dirty(&mut self) -> Result<()> {
+        if self.tallied.take().is_some() {
+            match fs::remove_file(self.tally()) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => sync_dir(&self.dir)?,
+            }
+        }
+        Ok(())
+    }
+    // Where the block actually is, compressed ones having the algorithm's extension
+    fn stored_block_path(&self, cid: &Cid) -> Option<(PathBuf, Compression)> {
+        stored_variant(&self.block_path(cid))
+    }
+    // Bytes on disk of the blocks in DAGs given a nonzero priority
+    fn count_pinned_bytes(&self) -> Result<u64> {
+        let mut roots = Vec::new();
+        for entry in read_dir(self.priorities())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if self.get_priority(&name)? > 0 {
+                roots.push(name);
+            }
+        }
+        Ok(pinned_bytes(self, roots, |cid| {
+            Cid::try_from(cid)
+                .ok()
+                .and_then(|c| self.stored_block_path(&c))
+                .and_then(|(path, _)| fs::metadata(path).ok())
+                .map(|m| m.len())
+                .unwrap_or(0)
+        }))
+    }
+    // Accounts for a block file about to be put at path, replacing whichever variant of it is there
+    fn replace_block_file(&mut self, path: &Path) -> Result<()> {
+        self.mark_dirty()?;
+        if let Some((old_path, _)) = stored_variant(&path.with_extension("")) {
+            let size = fs::metadata(&old_path)?.len();
+            self.usage = self.usage.saturating_sub(size);
+            self.old_blocks.retain(|o| o.path != old_path);
+            if old_path != path {
+                fs::remove_file(&old_path)?;
+            }
+        } else {
+            self.block_count += 1;
+        }
+        Ok(())
+    }
+    // Where an import's files wait, laid out under staging/ as they will be in the store
+    fn import_staging(&self, import: u64) -> PathBuf {
+        self.staging().join(import.to_string())
+    }
+    fn staged(&self, import: u64, path: &Path) -> PathBuf {
+        self.import_staging(import)
+            .join(path.strip_prefix(&self.dir).unwrap_or(path))
+    }
+    // Makes the staged files written so far durable, each directory synced once however many went into it
+    fn sync_staged(&mut self) -> Result<()> {
+        let mut dirs = BTreeSet::new();
+        for path in self.unsynced.drain(..) {
+            File::open(&path)?.sync_all()?;
+            if let Some(parent) = path.parent() {
+                dirs.insert(parent.to_path_buf());
+            }
+        }
+        for dir in dirs {
+            sync_dir(&dir)?;
+        }
+        Ok(())
+    }
+    // Stages the root's name and marks the import as all there, so it's finished even after a reset
+    fn mark_staged(&mut self, import: u64, root: &str, name: Option<&str>) -> Result<PathBuf> {
+        self.sync_staged()?;
+        if let Some(name) = name {
+            let sealed = self.at_rest.seal_name(root, name)?;
+            write_synced(
+                &self.staged(import, &self.names().join(root)),
+                sealed.as_bytes(),
+            )?;
+        }
+        let staging = self.import_staging(import);
+        write_synced(&staging.join(STAGED), &[])?;
+        Ok(staging)
+    }
+    // Moves a fully staged DAG into place, names last. Each kind's renames are synced before the next
+    // kind's start, so a name never survives a reset without the blocks it names.
+    fn commit_staged(&mut self, staging: &Path) -> Result<()> {
+        for kind in ["blocks", "cids", "names"] {
+            let mut moved_into = BTreeSet::new();
+            for from in files_under(&staging.join(kind))? {
+                let to = self.dir.join(from.strip_prefix(staging)?);
+                let parent = to.parent().unwrap_or(&self.dir).to_path_buf();
+                create_dir_synced(&parent)?;
+                if kind == "blocks" {
+                    self.replace_block_file(&to)?;
+                    self.usage += fs::metadata(&from)?.len();
+                }
+                fs::rename(&from, &to)?;
+                moved_into.insert(parent);
+            }
+            for dir in moved_into {
+                sync_dir(&dir)?;
+            }
+        }
+        fs::remove_dir_all(staging)?;
+        Ok(())
+    }
+    // Finishes imports which were fully staged before a reset and drops those which weren't
+    fn recover_staged(&mut self) -> Result<usize> {
+        let mut recovered = 0;
+        for staging in read_dir(self.staging())?
+            .flat_map(|r| r.ok())
+            .map(|e| e.path())
+        {
+            if staging.join(STAGED).is_file() {
+                info!("Finishing interrupted import staged at {staging:?}");
+                self.commit_staged(&staging)?;
+                recovered += 1;
+            } else {
+                info!("Dropping partially staged import {staging:?}");
+                fs::remove_dir_all(&staging)?;
+            }
+        }
+        Ok(recovered)
     }
     fn get_missing(&self, out: &mut Vec<String>, cid: &str) {
         if let Ok(block) = self.get_block_by_cid(cid) {
@@ -94,66 +359,117 @@ impl FileStorageProvider {
         Ok((to_skip, to_fetch))
     }
 
-    fn rentry_to_cid_str(&self, r: std::io::Result<DirEntry>, existent: bool) -> Option<String> {
-        self.entry_to_cid_str(r.ok()?, existent)
-    }
-    fn entry_to_cid_str(&self, e: DirEntry, existent: bool) -> Option<String> {
-        if e.metadata().ok()?.is_file() == existent {
-            let cid_str = e.file_name().to_str()?.to_owned();
-            let cid = Cid::try_from(cid_str.as_str()).ok()?;
-            let block_path = self.block_path(&cid);
-            if !block_path.is_file() {
-                debug!("Dangling CID: {}", &cid_str);
-                return None;
-            }
-            Some(cid_str)
-        } else {
-            None
+    // The CID a file in cids/ is named for, if its block is (or isn't, for !stored) here
+    fn entry_to_cid_str(&self, e: DirEntry, stored: bool) -> Option<String> {
+        let cid_str = e.file_name().to_str()?.to_owned();
+        let cid = Cid::try_from(cid_str.as_str()).ok()?;
+        if self.stored_block_path(&cid).is_some() != stored {
+            trace!("Skipping {cid_str}, whose block being stored isn't {stored}");
+            return None;
         }
+        Some(cid_str)
     }
+    // Totals up every block, keeping the 99 oldest as GC candidates along with the CIDs of each
     fn count_blocks(&mut self) {
-        if let Ok(rd) = fs::read_dir(self.blocks()) {
-            self.old_blocks = rd
-                .flat_map(|r| r.ok())
-                .flat_map(OnDiskBlock::from)
-                .take(99)
-                .collect();
+        if let Ok(entries) = sharded(&self.blocks()) {
+            self.old_blocks = entries.flat_map(OnDiskBlock::from).collect();
             self.old_blocks.sort_by(|a, b| b.cmp(a));
             self.usage = self.old_blocks.iter().map(|b| b.size).sum();
+            self.block_count = self.old_blocks.len() as u64;
+            let newer = self.old_blocks.len().saturating_sub(99);
+            self.old_blocks.drain(..newer);
+            if let Err(e) = self.index_old_blocks() {
+                error!("Unable to find the CIDs of GC candidates: {e:?}");
+            }
         }
     }
-    fn drop_cids_with_block_path(&self, block_path: &std::path::Path) -> Result<()> {
-        trace!("drop_cids_with_block_path({block_path:?})");
-        for e in read_dir(self.cids())?.flat_map(|r| r.ok()) {
-            let cid_path = e.path();
-            trace!("Checking CID path {cid_path:?}");
-            if let Some(Some(cid_str)) = cid_path.file_name().map(|f| f.to_str()) {
-                if let Ok(cid) = Cid::try_from(cid_str) {
-                    if self.block_path(&cid) == block_path {
-                        match fs::remove_file(&cid_path) {
-                            Ok(_) => {
-                                info!("Removed {cid_path:?} because its block {block_path:?} is gone.");
-                                fs::remove_file(self.names().join(cid_str)).ok();//It's totally normal to not exist
-                            },
-                            Err(e) => error!("Error removing dangling CID {cid_path:?} (corresponding to {block_path:?}): {e}"),
-                        }
-                    }
-                }
+    // Fills in which CIDs refer to each GC candidate, walking cids/ once for all of them rather than
+    // once per block collected
+    fn index_old_blocks(&mut self) -> Result<()> {
+        let by_path: HashMap<PathBuf, usize> = self
+            .old_blocks
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.path.with_extension(""), i))
+            .collect();
+        for e in sharded(&self.cids())? {
+            let Some(cid_str) = e.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            let Ok(cid) = Cid::try_from(cid_str.as_str()) else {
+                continue;
+            };
+            if let Some(&i) = by_path.get(&self.block_path(&cid)) {
+                self.old_blocks[i].cids.push(cid_str);
             }
         }
         Ok(())
     }
+    fn drop_cids_of_block(&self, odb: &OnDiskBlock) {
+        for cid_str in &odb.cids {
+            let cid_path = self.cid_path(cid_str);
+            match fs::remove_file(&cid_path) {
+                Ok(_) => {
+                    info!(
+                        "Removed {cid_path:?} because its block {:?} is gone.",
+                        &odb.path
+                    );
+                    fs::remove_file(self.names().join(cid_str)).ok(); //It's totally normal to not exist
+                    fs::remove_file(self.priorities().join(cid_str)).ok();
+                    fs::remove_file(self.expiries().join(cid_str)).ok();
+                }
+                Err(e) => error!(
+                    "Error removing dangling CID {cid_path:?} (corresponding to {:?}): {e}",
+                    &odb.path
+                ),
+            }
+        }
+    }
+    // Returns the bytes freed
+    fn remove_block(&mut self, cid_str: &str) -> Result<u64> {
+        let cid = Cid::try_from(cid_str)?;
+        self.mark_dirty()?;
+        let mut freed = 0;
+        if let Some((block_path, _)) = self.stored_block_path(&cid) {
+            let m = fs::metadata(&block_path)?;
+            fs::remove_file(&block_path)?;
+            freed = m.len();
+            self.usage = self.usage.saturating_sub(freed);
+            self.block_count = self.block_count.saturating_sub(1);
+            self.old_blocks.retain(|o| o.path != block_path);
+        }
+        fs::remove_file(self.cid_path(cid_str))?;
+        fs::remove_file(self.names().join(cid_str)).ok();
+        fs::remove_file(self.priorities().join(cid_str)).ok();
+        Ok(freed)
+    }
+    // Drops every DAG that has expired, if any has
+    fn remove_expired_dags(&mut self) -> Result<bool> {
+        let now = now_secs();
+        let roots = self.get_expired_dags(now)?;
+        if roots.is_empty() {
+            return Ok(false);
+        }
+        let cids = expired_dag_cids(self, &roots, now)?;
+        let mut freed = 0;
+        for cid in &cids {
+            freed += self.remove_block(cid)?;
+        }
+        self.gc.removed(cids.len() as u64, freed);
+        for root in &roots {
+            fs::remove_file(self.expiries().join(root))?;
+        }
+        info!("Removed expired DAGs {roots:?}, {} blocks", cids.len());
+        Ok(true)
+    }
+    // Drops blocks no CID refers to. Block files are named by multihash, so go by the CIDs.
     fn prune_names(&self) -> Result<()> {
-        let rd = fs::read_dir(self.blocks())?;
-        for p in rd.filter_map(|r| r.map(|e| e.path()).ok()) {
-            let filename = p
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default();
-            let c = Cid::try_from(filename);
-            let good = c.map(|c| self.has_cid(&c));
-            if !good.unwrap_or(false) {
+        let referenced: HashSet<PathBuf> = sharded(&self.cids())?
+            .filter_map(|e| Cid::try_from(e.file_name().to_str()?).ok())
+            .map(|c| self.block_path(&c))
+            .collect();
+        for p in sharded(&self.blocks())?.map(|e| e.path()) {
+            if !referenced.contains(&p.with_extension("")) {
                 fs::remove_file(p)?;
             }
         }
@@ -164,33 +480,94 @@ impl FileStorageProvider {
 impl StorageProvider for FileStorageProvider {
     fn import_block(&mut self, block: &StoredBlock) -> anyhow::Result<()> {
         let cid = Cid::try_from(block.cid.as_str())?;
-        let block_path = self.block_path(&cid);
+        let (compression, data) = self.at_rest.pack(&block.cid, &block.data)?;
+        let block_path = self
+            .block_path(&cid)
+            .with_extension(compression.extension());
 
-        if !block_path.is_file() {
-            self.usage += u64::try_from(block.data.len()).ok().unwrap_or(0);
-        } else if let Some(idx) = self.old_blocks.iter().position(|o| o.path == block_path) {
-            self.old_blocks.remove(idx);
-        }
-        File::create(&block_path)?.write_all(block.data.as_slice())?;
-        let mut f = File::create(self.cids().join(&block.cid))?;
+        self.replace_block_file(&block_path)?;
+        self.usage += u64::try_from(data.len()).ok().unwrap_or(0);
+        create_dir_all(block_path.parent().unwrap_or(&self.dir))?;
+        File::create(&block_path)?.write_all(data.as_slice())?;
+        let cid_path = self.cid_path(&block.cid);
+        create_dir_all(cid_path.parent().unwrap_or(&self.dir))?;
+        let mut f = File::create(cid_path)?;
         for l in &block.links {
             writeln!(&mut f, "{}", &l)?;
         }
         if let Some(name) = &block.filename {
             self.name_dag(&block.cid, name)?;
         }
+        // The corrupt copy set aside by quarantine_block is done with once the block is re-fetched
+        let set_aside = self.quarantine().join(&block.cid);
+        if set_aside.is_file() {
+            fs::remove_file(set_aside)?;
+        }
+        Ok(())
+    }
+
+    fn begin_import(&mut self) -> Result<u64> {
+        self.imports += 1;
+        let staging = self.import_staging(self.imports);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        Ok(self.imports)
+    }
+
+    fn stage_block(&mut self, import: u64, block: &StoredBlock) -> Result<()> {
+        let cid = Cid::try_from(block.cid.as_str())?;
+        let (compression, data) = self.at_rest.pack(&block.cid, &block.data)?;
+        let block_path = self
+            .block_path(&cid)
+            .with_extension(compression.extension());
+        let links: String = block.links.iter().map(|l| format!("{l}\n")).collect();
+        for (path, contents) in [
+            (self.staged(import, &block_path), data.as_slice()),
+            (
+                self.staged(import, &self.cid_path(&block.cid)),
+                links.as_bytes(),
+            ),
+        ] {
+            create_dir_synced(path.parent().unwrap_or(&self.dir))?;
+            File::create(&path)?.write_all(contents)?;
+            self.unsynced.push(path);
+        }
+        // Synced a batch at a time rather than file by file
+        if self.unsynced.len() >= 2 * SYNC_BATCH {
+            self.sync_staged()?;
+        }
         Ok(())
     }
 
+    fn commit_import(&mut self, import: u64, root: &str, name: Option<&str>) -> Result<()> {
+        let staging = self.mark_staged(import, root, name)?;
+        // Should this fail part way, the next startup finishes it
+        self.commit_staged(&staging)
+    }
+
+    fn abort_import(&mut self, import: u64) {
+        let staging = self.import_staging(import);
+        self.unsynced.retain(|p| !p.starts_with(&staging));
+        if staging.exists() && !staging.join(STAGED).exists() {
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                error!("Unable to drop staged import {staging:?}: {e}");
+            }
+        }
+    }
+
     fn get_available_cids(&self) -> anyhow::Result<Vec<String>> {
-        let mut result: Vec<String> = read_dir(self.cids())?
-            .filter_map(|f| self.rentry_to_cid_str(f, true))
+        let mut result: Vec<String> = sharded(&self.cids())?
+            .filter_map(|e| self.entry_to_cid_str(e, true))
             .collect();
         result.sort();
         Ok(result)
     }
 
     fn get_block_by_cid(&self, cid_str: &str) -> anyhow::Result<StoredBlock> {
+        if let Some(block) = inlined_block(cid_str) {
+            return Ok(block);
+        }
         let mut result = StoredBlock {
             cid: cid_str.to_string(),
             filename: self.get_name(cid_str).ok(),
@@ -198,14 +575,21 @@ impl StorageProvider for FileStorageProvider {
             links: vec![],
         };
         let cid = Cid::try_from(cid_str)?;
-        let block_path = self.block_path(&cid);
-        File::open(block_path)?.read_to_end(&mut result.data)?;
+        let Some((block_path, compression)) = self.stored_block_path(&cid) else {
+            bail!(StorageError::BlockNotFound(cid_str.to_string(), "no block file".to_string()));
+        };
+        let mut data = vec![];
+        File::open(block_path)?.read_to_end(&mut data)?;
+        result.data = self.at_rest.unpack(cid_str, compression, data)?;
         result.links = self.get_links_by_cid(cid_str)?;
         Ok(result)
     }
 
     fn get_links_by_cid(&self, cid: &str) -> anyhow::Result<Vec<String>> {
-        let links_path = self.cids().join(cid);
+        if let Some(block) = inlined_block(cid) {
+            return Ok(block.links);
+        }
+        let links_path = self.cid_path(cid);
         let result = std::fs::read_to_string(links_path)?
             .lines()
             .map(String::from)
@@ -225,16 +609,133 @@ impl StorageProvider for FileStorageProvider {
     }
 
     fn name_dag(&self, cid: &str, file_name: &str) -> anyhow::Result<()> {
-        File::create(self.names().join(cid))?.write_all(file_name.as_bytes())?;
+        let sealed = self.at_rest.seal_name(cid, file_name)?;
+        File::create(self.names().join(cid))?.write_all(sealed.as_bytes())?;
         Ok(())
     }
 
     fn get_name(&self, cid: &str) -> Result<String> {
         let mut result = String::default();
         File::open(self.names().join(cid))?.read_to_string(&mut result)?;
+        self.at_rest.open_name(cid, result)
+    }
+
+    fn set_priority(&self, cid: &str, priority: u8) -> anyhow::Result<()> {
+        if !self.cid_path(cid).is_file() {
+            bail!("Can't prioritize unknown DAG {cid}");
+        }
+        File::create(self.priorities().join(cid))?.write_all(priority.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    fn get_priority(&self, cid: &str) -> Result<u8> {
+        match fs::read_to_string(self.priorities().join(cid)) {
+            Ok(s) => Ok(s.trim().parse()?),
+            Err(_) => Ok(0),
+        }
+    }
+
+    fn set_expiry(&self, cid: &str, expires: u64) -> Result<()> {
+        File::create(self.expiries().join(cid))?.write_all(expires.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    fn get_expiry(&self, cid: &str) -> Result<Option<u64>> {
+        match fs::read_to_string(self.expiries().join(cid)) {
+            Ok(s) => Ok(Some(s.trim().parse()?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_expired_dags(&self, now: u64) -> Result<Vec<String>> {
+        let mut result: Vec<(u64, String)> = read_dir(self.expiries())?
+            .flat_map(|r| r.ok())
+            .filter_map(|e| e.file_name().to_str().map(String::from))
+            .filter_map(|c| Some((self.get_expiry(&c).ok()??, c)))
+            .filter(|(e, _)| *e <= now)
+            .collect();
+        result.sort();
+        Ok(result.into_iter().map(|(_, c)| c).collect())
+    }
+
+    fn get_cids_after(&self, after: &str, limit: usize) -> Result<Vec<String>> {
+        let mut result: Vec<String> = sharded(&self.cids())?
+            .filter_map(|e| self.entry_to_cid_str(e, true))
+            .filter(|c| c.as_str() > after)
+            .collect();
+        result.sort();
+        result.truncate(limit);
         Ok(result)
     }
 
+    fn quarantine_block(&mut self, cid_str: &str) -> Result<()> {
+        let cid = Cid::try_from(cid_str)?;
+        let Some((block_path, compression)) = self.stored_block_path(&cid) else {
+            bail!("Can't quarantine {cid_str}, which isn't stored");
+        };
+        self.mark_dirty()?;
+        let size = fs::metadata(&block_path)?.len();
+        // Links, name and priority stay, so the DAG is whole again once the block is re-fetched
+        let set_aside = self.quarantine().join(cid_str);
+        fs::rename(
+            &block_path,
+            set_aside.with_extension(compression.extension()),
+        )?;
+        self.usage = self.usage.saturating_sub(size);
+        self.block_count = self.block_count.saturating_sub(1);
+        self.old_blocks.retain(|o| o.path != block_path);
+        Ok(())
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        let (dags, dangling_cids, pinned) = match self.counted.get() {
+            Some((at, dags, dangling, pinned)) if at.elapsed() < RECOUNT_INTERVAL => {
+                (dags, dangling, pinned)
+            }
+            _ => {
+                let dags = read_dir(self.names())?.count() as u64;
+                let dangling = self.get_dangling_cids()?.len() as u64;
+                let pinned = self.count_pinned_bytes()?;
+                self.counted
+                    .set(Some((Instant::now(), dags, dangling, pinned)));
+                (dags, dangling, pinned)
+            }
+        };
+        Ok(StorageStats {
+            used_bytes: self.usage,
+            blocks: self.block_count,
+            dags,
+            dangling_cids,
+            pinned_bytes: pinned,
+            ..self.gc.stats()
+        })
+    }
+
+    fn save_state(&self, key: &str, value: &[u8]) -> Result<()> {
+        // Write then rename, so a reset mid-write leaves the previous state intact
+        let path = self.state().join(key);
+        let temp = self.state().join(format!("{key}.tmp"));
+        File::create(&temp)?.write_all(value)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    fn load_state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.state().join(key)) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete_state(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.state().join(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     fn get_missing_cid_blocks(&self, cid: &str) -> anyhow::Result<Vec<String>> {
         let mut result = Vec::new();
         self.get_missing(&mut result, cid);
@@ -274,10 +775,31 @@ impl StorageProvider for FileStorageProvider {
     }
 
     fn incremental_gc(&mut self) -> bool {
+        if let Err(e) = self.write_tally() {
+            error!("Unable to save the usage tally: {e:?}");
+        }
+        // Expired DAGs go first, whatever the usage, as nobody wants them anymore
+        match self.remove_expired_dags() {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => error!("Trouble removing expired DAGs: {e:?}"),
+        }
+        if self.usage >= self.high && self.old_blocks.is_empty() {
+            // Candidates are only gathered once needed, e.g. the first time after a restart
+            self.count_blocks();
+            debug!(
+                "There are {} GC candidates in blocks/",
+                &self.old_blocks.len()
+            );
+        }
         if self.usage < self.high {
             trace!("No need to GC: usage={} < high={}", &self.usage, self.high);
             false
         } else if let Some(odb) = self.old_blocks.pop() {
+            if let Err(e) = self.mark_dirty() {
+                error!("Unable to mark the usage tally stale: {e:?}");
+                return false;
+            }
             match fs::remove_file(&odb.path) {
                 Ok(_) => {
                     info!(
@@ -285,9 +807,9 @@ impl StorageProvider for FileStorageProvider {
                         &odb.path, self.usage, self.high
                     );
                     self.usage -= odb.size;
-                    if let Err(e) = self.drop_cids_with_block_path(&odb.path) {
-                        error!("Trouble dropping CIDs for block: {e}");
-                    }
+                    self.block_count = self.block_count.saturating_sub(1);
+                    self.gc.removed(1, odb.size);
+                    self.drop_cids_of_block(&odb);
                 }
                 Err(e) => {
                     error!("Error removing old block {odb:?} to free up space! {e:?}");
@@ -295,31 +817,135 @@ impl StorageProvider for FileStorageProvider {
             }
             true
         } else {
-            self.count_blocks();
-            debug!("There are {} files in blocks/", &self.old_blocks.len());
-            !self.old_blocks.is_empty()
+            false
         }
     }
 
     fn has_cid(&self, cid: &Cid) -> bool {
+        if ipfs_unixfs::inlined_data(cid).is_some() {
+            return true;
+        }
         let s = cid.to_string();
-        if !self.cids().join(s).is_file() {
+        if !self.cid_path(&s).is_file() {
             return false;
         }
-        self.block_path(cid).is_file()
+        self.stored_block_path(cid).is_some()
     }
 
     fn ack_cid(&self, cid: &Cid) {
-        let p = self.cids().join(cid.to_string());
+        let p = self.cid_path(&cid.to_string());
         fs::OpenOptions::new().append(true).open(p).ok();
     }
 
     fn get_dangling_cids(&self) -> Result<Vec<Cid>> {
-        Ok(read_dir(self.cids())?
-            .filter_map(|f| self.rentry_to_cid_str(f, false))
+        Ok(sharded(&self.cids())?
+            .filter_map(|e| self.entry_to_cid_str(e, false))
             .filter_map(|s| Cid::try_from(s.as_str()).ok())
             .collect())
     }
+
+    fn set_compression(&mut self, compression: Compression) {
+        self.at_rest.compression = compression;
+    }
+
+    fn set_cipher(&mut self, cipher: Option<Cipher>) -> Result<()> {
+        let empty =
+            sharded(&self.cids())?.next().is_none() && read_dir(self.names())?.next().is_none();
+        if let Some(check) = self.at_rest.set_cipher(cipher, empty)? {
+            fs::write(self.key_check(), check)?;
+        }
+        Ok(())
+    }
+}
+
+// Marks a staged import as complete
+const STAGED: &str = "staged";
+// Blocks staged between syncs
+const SYNC_BATCH: usize = 64;
+
+// Where the variant of a block file (by compression) which is stored is
+fn stored_variant(path: &Path) -> Option<(PathBuf, Compression)> {
+    [Compression::None, Compression::Zstd, Compression::Lz4]
+        .into_iter()
+        .map(|c| (path.with_extension(c.extension()), c))
+        .find(|(p, _)| p.is_file())
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_synced(parent)?;
+    }
+    let mut f = File::create(path)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    // The file's entry isn't durable until its directory is synced too
+    if let Some(parent) = path.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Like create_dir_all, but what's created is synced into its parent
+fn create_dir_synced(dir: &Path) -> Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_synced(parent)?;
+    }
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => {}
+    }
+    if let Some(parent) = dir.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+// Every file somewhere under dir, which needn't exist
+fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut result = vec![];
+    let Ok(rd) = read_dir(dir) else {
+        return Ok(result);
+    };
+    for e in rd.flat_map(|r| r.ok()) {
+        if e.file_type()?.is_dir() {
+            result.extend(files_under(&e.path())?);
+        } else {
+            result.push(e.path());
+        }
+    }
+    Ok(result)
+}
+
+// Where a block or CID file goes, sharded by the two characters before the last (as go-ipfs flatfs'
+// next-to-last/2) so no directory grows past ~1000 entries
+fn shard(dir: &Path, name: &str) -> PathBuf {
+    let end = name.len().saturating_sub(1);
+    let prefix = match name.get(end.saturating_sub(2)..end) {
+        Some(p) if !p.is_empty() => p,
+        _ => "_",
+    };
+    dir.join(prefix).join(name)
+}
+
+// Every file in dir's shards
+fn sharded(dir: &Path) -> Result<impl Iterator<Item = DirEntry>> {
+    Ok(read_dir(dir)?
+        .flat_map(|r| r.ok())
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .flat_map(|s| {
+            read_dir(s.path())
+                .into_iter()
+                .flatten()
+                .flat_map(|r| r.ok())
+        }))
 }
 
 #[derive(Eq, PartialEq, Debug)]
@@ -327,6 +953,8 @@ struct OnDiskBlock {
     modt: SystemTime,
     size: u64,
     path: PathBuf,
+    // The CIDs whose block this is, to drop along with it
+    cids: Vec<String>,
 }
 impl OnDiskBlock {
     fn from(e: DirEntry) -> Option<Self> {
@@ -338,6 +966,7 @@ impl OnDiskBlock {
             modt: m.modified().ok()?,
             size: m.len(),
             path: e.path(),
+            cids: vec![],
         })
     }
 }
@@ -530,6 +1159,38 @@ pub mod tests {
         assert_eq!(links, missing_links);
     }
 
+    #[test]
+    pub fn test_inlined_block_never_missing() {
+        let mut harness = TestHarness::new();
+        let tiny = ipfs_unixfs::inline_cid(ipfs_unixfs::codecs::Codec::Raw, b"hi").unwrap();
+        let root = StoredBlock {
+            cid: "bafybeibhdee56vnqurkkk53wsfik3nkkgteuoi5nsarmbtsvi5wrxkopki".to_string(),
+            data: vec![],
+            links: vec![tiny.to_string()],
+            filename: None,
+        };
+        harness.provider.import_block(&root).unwrap();
+
+        assert!(harness.provider.has_cid(&tiny));
+        assert!(harness
+            .provider
+            .get_missing_cid_blocks(&root.cid)
+            .unwrap()
+            .is_empty());
+        let blocks = harness.provider.get_all_dag_blocks(&root.cid).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].data, b"hi");
+        assert!(harness
+            .provider
+            .get_links_by_cid(&tiny.to_string())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            harness.provider.get_available_cids().unwrap(),
+            vec![root.cid]
+        );
+    }
+
     #[test]
     pub fn test_verify_detect_no_missing_blocks() {
         let mut harness = TestHarness::new();
@@ -562,6 +1223,457 @@ pub mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    pub fn test_priority_roundtrip() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(false);
+
+        assert_eq!(harness.provider.get_priority(&root.cid).unwrap(), 0);
+        harness.provider.set_priority(&root.cid, 200).unwrap();
+        assert_eq!(harness.provider.get_priority(&root.cid).unwrap(), 200);
+    }
+
+    #[test]
+    pub fn test_stats_track_imports_and_gc() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        harness.provider.name_dag(&root.cid, "hi.txt").unwrap();
+        let stats = harness.provider.get_stats().unwrap();
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.used_bytes, root.data.len() as u64 + 2);
+        assert_eq!(stats.dags, 1);
+        assert_eq!(stats.gc_runs, 0);
+        assert_eq!(stats.pinned_bytes, 0);
+        harness.provider.set_priority(&root.cid, 1).unwrap();
+        harness.provider.counted.set(None);
+        assert_eq!(
+            harness.provider.get_stats().unwrap().pinned_bytes,
+            root.data.len() as u64 + 2
+        );
+
+        harness.provider.set_expiry(&root.cid, 1).unwrap();
+        assert!(harness.provider.incremental_gc());
+        let stats = harness.provider.get_stats().unwrap();
+        assert_eq!((stats.blocks, stats.used_bytes), (0, 0));
+        assert_eq!(stats.gc_runs, 1);
+        assert_eq!(stats.gc_blocks_removed, 3);
+        assert_eq!(stats.gc_bytes_freed, root.data.len() as u64 + 2);
+        //DAGs are only recounted once RECOUNT_INTERVAL has passed
+        assert_eq!(stats.dags, 1);
+        harness.provider.counted.set(None);
+        assert_eq!(harness.provider.get_stats().unwrap().dags, 0);
+    }
+
+    #[test]
+    pub fn test_expired_dag_collected_first_sparing_shared_blocks() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        harness.provider.name_dag(&root.cid, "hi.txt").unwrap();
+        // 'h' is also a DAG of its own, which hasn't expired
+        harness.provider.name_dag(&root.links[0], "h.txt").unwrap();
+        harness.provider.set_expiry(&root.cid, 1).unwrap();
+        assert_eq!(harness.provider.get_expiry(&root.cid).unwrap(), Some(1));
+        assert_eq!(
+            harness.provider.get_expired_dags(now_secs()).unwrap(),
+            vec![root.cid.clone()]
+        );
+
+        assert!(harness.provider.incremental_gc());
+        let has = |c: &str| harness.provider.has_cid(&Cid::try_from(c).unwrap());
+        assert!(!has(&root.cid));
+        assert!(has(&root.links[0]));
+        assert!(!has(&root.links[1]));
+        assert!(harness
+            .provider
+            .get_expired_dags(now_secs())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn test_quarantined_block_refetched() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        let h = harness.provider.get_block_by_cid(&root.links[0]).unwrap();
+        let cid = Cid::try_from(h.cid.as_str()).unwrap();
+        assert_eq!(
+            harness.provider.get_cids_after("", 9).unwrap().len(),
+            3,
+            "every block gets listed"
+        );
+        assert_eq!(
+            harness.provider.get_cids_after(&h.cid, 9).unwrap(),
+            vec![root.links[1].clone(), root.cid.clone()]
+        );
+
+        // Flip a bit of 'h' on disk
+        fs::write(harness.provider.block_path(&cid), [0x69]).unwrap();
+        let corrupt = harness.provider.get_block_by_cid(&h.cid).unwrap();
+        assert!(!corrupt.hash_matches().unwrap());
+        harness.provider.quarantine_block(&h.cid).unwrap();
+        assert!(!harness.provider.has_cid(&cid));
+        assert_eq!(
+            harness.provider.get_missing_cid_blocks(&root.cid).unwrap(),
+            vec![h.cid.clone()]
+        );
+        assert_eq!(harness.provider.get_stats().unwrap().blocks, 2);
+        assert_eq!(read_dir(harness.provider.quarantine()).unwrap().count(), 1);
+        assert_eq!(harness.provider.get_dangling_cids().unwrap(), vec![cid]);
+
+        harness.provider.import_block(&h).unwrap();
+        assert!(harness.provider.has_cid(&cid));
+        assert!(harness
+            .provider
+            .get_missing_cid_blocks(&root.cid)
+            .unwrap()
+            .is_empty());
+        assert_eq!(read_dir(harness.provider.quarantine()).unwrap().count(), 0);
+    }
+
+    #[test]
+    pub fn test_read_only_open_leaves_store_as_is() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        harness.provider.write_tally().unwrap();
+        let stray = shard(&harness.provider.blocks(), "stray");
+        create_dir_all(stray.parent().unwrap()).unwrap();
+        fs::write(&stray, b"stray").unwrap();
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+
+        let reader = FileStorageProvider::open_read_only(&dir).unwrap();
+        assert_eq!(reader.get_all_dag_blocks(&root.cid).unwrap().len(), 3);
+        assert_eq!(reader.usage, harness.provider.usage);
+        assert!(stray.is_file());
+
+        let flat = harness.provider.cids().join("flat");
+        fs::write(&flat, b"").unwrap();
+        assert!(FileStorageProvider::open_read_only(&dir).is_err());
+        assert!(flat.is_file());
+    }
+
+    #[test]
+    pub fn test_flat_layout_sharded_on_startup() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        let usage = harness.provider.usage;
+        // Lay everything out as older builds did
+        for dir in [harness.provider.blocks(), harness.provider.cids()] {
+            for e in sharded(&dir).unwrap().collect::<Vec<_>>() {
+                fs::rename(e.path(), dir.join(e.file_name())).unwrap();
+            }
+        }
+        fs::remove_file(harness.provider.tally()).ok();
+
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        for dir in [reopened.blocks(), reopened.cids()] {
+            assert!(read_dir(&dir).unwrap().all(|e| e.unwrap().path().is_dir()));
+            assert_eq!(sharded(&dir).unwrap().count(), 3);
+        }
+        assert_eq!(reopened.usage, usage);
+        assert_eq!(reopened.get_all_dag_blocks(&root.cid).unwrap().len(), 3);
+        assert_eq!(
+            shard(Path::new("cids"), "bafyxyz"),
+            PathBuf::from("cids/xy/bafyxyz")
+        );
+        assert_eq!(shard(Path::new("cids"), "b"), PathBuf::from("cids/_/b"));
+    }
+
+    #[test]
+    pub fn test_tally_spares_walking_blocks() {
+        let mut harness = TestHarness::new();
+        harness.import_hi(true);
+        let tally = (harness.provider.usage, harness.provider.block_count);
+        harness.provider.write_tally().unwrap();
+        // A block appearing behind the provider's back goes unnoticed, as it's not looked for
+        let stray = harness.provider.blocks().join("zz").join("stray");
+        create_dir_all(stray.parent().unwrap()).unwrap();
+        fs::write(&stray, [1, 2, 3]).unwrap();
+
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!((reopened.usage, reopened.block_count), tally);
+        assert!(stray.is_file());
+
+        fs::remove_file(reopened.tally()).unwrap();
+        let recounted = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!((recounted.usage, recounted.block_count), tally);
+        assert!(!stray.is_file(), "unreferenced blocks are pruned");
+    }
+
+    #[test]
+    pub fn test_tally_removed_until_changes_settle() {
+        let mut harness = TestHarness::new();
+        harness.import_hi(false);
+        harness.provider.write_tally().unwrap();
+        assert!(harness.provider.tally().is_file());
+        harness.import_hi(true);
+        assert!(!harness.provider.tally().is_file());
+        // As if reset part way through an import, after its block but before its CID
+        let stray = harness.provider.blocks().join("zz").join("stray");
+        create_dir_all(stray.parent().unwrap()).unwrap();
+        fs::write(&stray, [1, 2, 3]).unwrap();
+
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!(
+            (reopened.usage, reopened.block_count),
+            (harness.provider.usage, harness.provider.block_count)
+        );
+        assert!(!stray.is_file());
+    }
+
+    #[test]
+    pub fn test_gc_after_restart_with_tally() {
+        let mut harness = TestHarness::new();
+        harness.import_hi(true);
+        harness.provider.write_tally().unwrap();
+
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let mut reopened = FileStorageProvider::new(&dir, 1).unwrap();
+        assert!(reopened.old_blocks.is_empty());
+        assert!(reopened.incremental_gc());
+        assert_eq!(reopened.block_count, 2);
+        assert_eq!(sharded(&reopened.blocks()).unwrap().count(), 2);
+        // The collected block's CID goes with it rather than being left dangling
+        assert_eq!(sharded(&reopened.cids()).unwrap().count(), 2);
+        assert!(!reopened.tally().is_file());
+    }
+
+    #[test]
+    pub fn test_staged_import_recovered_on_startup() {
+        let mut source = TestHarness::new();
+        let root = source.import_hi(true);
+        let blocks = source.provider.get_all_dag_blocks(&root.cid).unwrap();
+
+        let stage = |provider: &mut FileStorageProvider| {
+            let import = provider.begin_import().unwrap();
+            for block in &blocks {
+                provider.stage_block(import, block).unwrap();
+            }
+            provider
+                .mark_staged(import, &root.cid, Some("hi.txt"))
+                .unwrap()
+        };
+
+        let mut harness = TestHarness::new();
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        // Fully staged when the power went out: finished on startup
+        stage(&mut harness.provider);
+        assert!(harness.provider.unsynced.is_empty());
+        assert!(harness.provider.get_available_cids().unwrap().is_empty());
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!(reopened.get_all_dag_blocks(&root.cid).unwrap().len(), 3);
+        assert_eq!(reopened.get_name(&root.cid).unwrap(), "hi.txt");
+        assert_eq!(read_dir(reopened.staging()).unwrap().count(), 0);
+
+        // Only partly staged: dropped on startup
+        let mut harness = TestHarness::new();
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let staging = stage(&mut harness.provider);
+        fs::remove_file(staging.join(STAGED)).unwrap();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert!(reopened.get_available_cids().unwrap().is_empty());
+        assert!(reopened.get_name(&root.cid).is_err());
+        assert_eq!(read_dir(reopened.staging()).unwrap().count(), 0);
+    }
+
+    #[test]
+    pub fn test_staged_files_synced_in_batches() {
+        let mut harness = TestHarness::new();
+        let import = harness.provider.begin_import().unwrap();
+        let block = |i: usize| {
+            let data = i.to_le_bytes().to_vec();
+            StoredBlock {
+                cid: Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(&data)).to_string(),
+                data,
+                links: vec![],
+                filename: None,
+            }
+        };
+        for i in 0..SYNC_BATCH - 1 {
+            harness.provider.stage_block(import, &block(i)).unwrap();
+        }
+        assert_eq!(harness.provider.unsynced.len(), 2 * (SYNC_BATCH - 1));
+        harness
+            .provider
+            .stage_block(import, &block(SYNC_BATCH))
+            .unwrap();
+        assert!(harness.provider.unsynced.is_empty());
+        harness.provider.stage_block(import, &block(0)).unwrap();
+        harness.provider.abort_import(import);
+        assert!(harness.provider.unsynced.is_empty());
+    }
+
+    #[test]
+    pub fn test_name_published_last() {
+        let mut source = TestHarness::new();
+        let root = source.import_hi(true);
+        let blocks = source.provider.get_all_dag_blocks(&root.cid).unwrap();
+
+        let mut harness = TestHarness::new();
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        // Something in the way of the name: the commit stops there, with the blocks already in place
+        let obstacle = harness.provider.names().join(&root.cid);
+        create_dir_all(obstacle.join("x")).unwrap();
+        assert!(harness
+            .provider
+            .import_dag(&blocks, &root.cid, Some("hi.txt"))
+            .is_err());
+        assert_eq!(
+            harness
+                .provider
+                .get_all_dag_blocks(&root.cid)
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(harness.provider.get_name(&root.cid).is_err());
+
+        // Still staged, so it's finished on startup
+        fs::remove_dir_all(&obstacle).unwrap();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!(reopened.get_name(&root.cid).unwrap(), "hi.txt");
+        assert_eq!(read_dir(reopened.staging()).unwrap().count(), 0);
+    }
+
+    #[test]
+    pub fn test_failed_import_leaves_nothing() {
+        let mut source = TestHarness::new();
+        let root = source.import_hi(true);
+        let mut blocks = source.provider.get_all_dag_blocks(&root.cid).unwrap();
+        blocks.push(StoredBlock {
+            cid: "not a cid".to_string(),
+            data: vec![1],
+            links: vec![],
+            filename: None,
+        });
+
+        let mut harness = TestHarness::new();
+        assert!(harness
+            .provider
+            .import_dag(&blocks, &root.cid, Some("hi.txt"))
+            .is_err());
+        assert!(harness.provider.get_available_cids().unwrap().is_empty());
+        assert!(harness.provider.get_name(&root.cid).is_err());
+        assert_eq!(read_dir(harness.provider.staging()).unwrap().count(), 0);
+
+        blocks.pop();
+        harness
+            .provider
+            .import_dag(&blocks, &root.cid, Some("hi.txt"))
+            .unwrap();
+        assert_eq!(harness.provider.get_name(&root.cid).unwrap(), "hi.txt");
+        assert_eq!(harness.provider.block_count, 3);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    pub fn test_compressed_at_rest() {
+        let mut harness = TestHarness::new();
+        harness.provider.set_compression(Compression::Lz4);
+        let block = |data: Vec<u8>| StoredBlock {
+            cid: Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(&data)).to_string(),
+            data,
+            links: vec![],
+            filename: None,
+        };
+        let packable = block(b"0123456789".repeat(50));
+        let random = block((0..=255u8).collect());
+        harness.provider.import_block(&packable).unwrap();
+        harness.provider.import_block(&random).unwrap();
+        let packable_cid = Cid::try_from(packable.cid.as_str()).unwrap();
+        let random_cid = Cid::try_from(random.cid.as_str()).unwrap();
+        let (path, compression) = harness.provider.stored_block_path(&packable_cid).unwrap();
+        assert_eq!(compression, Compression::Lz4);
+        let packed = fs::metadata(path).unwrap().len();
+        assert!(packed < 500);
+        assert_eq!(
+            harness.provider.stored_block_path(&random_cid).unwrap(),
+            (harness.provider.block_path(&random_cid), Compression::None)
+        );
+        // Usage is what's on disk
+        assert_eq!(harness.provider.usage, packed + 256);
+        for b in [&packable, &random] {
+            let got = harness.provider.get_block_by_cid(&b.cid).unwrap();
+            assert_eq!(got.data, b.data);
+            assert!(got.hash_matches().unwrap());
+        }
+        assert_eq!(harness.provider.get_available_cids().unwrap().len(), 2);
+
+        harness.provider.quarantine_block(&packable.cid).unwrap();
+        assert!(!harness.provider.has_cid(&packable_cid));
+        harness.provider.set_compression(Compression::None);
+        harness.provider.import_block(&packable).unwrap();
+        assert_eq!(
+            harness.provider.stored_block_path(&packable_cid).unwrap().1,
+            Compression::None
+        );
+        assert_eq!(harness.provider.remove_block(&packable.cid).unwrap(), 500);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    pub fn test_encrypted_at_rest() {
+        let mut harness = TestHarness::new();
+        let key = |k: &str| Some(Cipher::from_hex(&k.repeat(64)).unwrap());
+        harness.provider.set_cipher(key("1")).unwrap();
+        let data = b"secret payload".to_vec();
+        let block = StoredBlock {
+            cid: Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(&data)).to_string(),
+            data,
+            links: vec![],
+            filename: Some("secret.txt".to_owned()),
+        };
+        harness.provider.import_block(&block).unwrap();
+        let cid = Cid::try_from(block.cid.as_str()).unwrap();
+        let on_disk = fs::read(harness.provider.block_path(&cid)).unwrap();
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+        let name = fs::read_to_string(harness.provider.names().join(&block.cid)).unwrap();
+        assert!(!name.contains("secret"));
+
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let mut reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert!(reopened.get_block_by_cid(&block.cid).is_err());
+        assert!(reopened.set_cipher(None).is_err());
+        assert!(reopened.set_cipher(key("2")).is_err());
+        reopened.set_cipher(key("1")).unwrap();
+        let got = reopened.get_block_by_cid(&block.cid).unwrap();
+        assert_eq!(got, block);
+        assert!(got.hash_matches().unwrap());
+
+        let mut plain = TestHarness::new();
+        plain.import_hi(false);
+        assert!(plain.provider.set_cipher(key("1")).is_err());
+    }
+
+    #[test]
+    pub fn test_blocks_survive_reopening() {
+        let mut harness = TestHarness::new();
+        let root = harness.import_hi(true);
+        // Block files are named by multihash rather than CID, which mustn't make them look unreferenced
+        harness.provider.prune_names().unwrap();
+        let dir = harness.provider.dir.to_str().unwrap().to_owned();
+        let reopened = FileStorageProvider::new(&dir, 9).unwrap();
+        assert_eq!(reopened.get_all_dag_blocks(&root.cid).unwrap().len(), 3);
+    }
+
+    #[test]
+    pub fn test_state_roundtrip() {
+        let harness = TestHarness::new();
+
+        assert_eq!(harness.provider.load_state("sync").unwrap(), None);
+        harness.provider.save_state("sync", b"first").unwrap();
+        harness.provider.save_state("sync", b"second").unwrap();
+        assert_eq!(
+            harness.provider.load_state("sync").unwrap(),
+            Some(b"second".to_vec())
+        );
+        harness.provider.delete_state("sync").unwrap();
+        harness.provider.delete_state("sync").unwrap();
+        assert_eq!(harness.provider.load_state("sync").unwrap(), None);
+    }
+
     #[test]
     pub fn test_oldestfilesortsfirst() {
         let a = assert_fs::NamedTempFile::new("yo").unwrap();
@@ -570,6 +1682,7 @@ pub mod tests {
             modt: m.modified().unwrap(),
             size: m.len(),
             path: PathBuf::from(a.path()),
+            cids: vec![],
         };
         std::thread::sleep(std::time::Duration::from_secs(1));
         let b = assert_fs::NamedTempFile::new("yo").unwrap();
@@ -578,6 +1691,7 @@ pub mod tests {
             modt: m.modified().unwrap(),
             size: m.len(),
             path: PathBuf::from(b.path()),
+            cids: vec![],
         };
         let mut v = vec![y, x];
         v.sort();
@@ -1,4 +1,7 @@
+pub mod at_rest;
 pub mod block;
+pub mod compression;
+pub mod convert;
 pub mod error;
 pub mod provider;
 pub mod storage;
@@ -8,8 +11,15 @@ mod null_provider;
 
 #[cfg(feature = "files")]
 mod file_provider;
+#[cfg(any(test, feature = "memory"))]
+pub mod memory_provider;
 #[cfg(feature = "sqlite")]
 pub mod sql_provider;
 
-#[cfg(all(not(test), feature = "sqlite", feature = "files"))]
+#[cfg(all(
+    not(test),
+    feature = "sqlite",
+    feature = "files",
+    not(feature = "convert")
+))]
 compile_error! {"Outside of unit tests there's not a good reason to compile with multiple StorageProvider implementations."}
@@ -0,0 +1,537 @@
+use crate::{
+    block::{inlined_block, is_inlined, StoredBlock},
+    provider::{
+        expired_dag_cids, now_secs, pinned_bytes, GcActivity, StorageProvider, StorageStats,
+    },
+};
+use anyhow::{bail, Result};
+use cid::Cid;
+use log::{error, info, trace};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+// Keeps everything in RAM, for nodes which should leave nothing on disk (and for fast tests)
+pub struct MemoryStorageProvider {
+    blocks: BTreeMap<String, MemoryBlock>,
+    // Import order of blocks, oldest first, which is the order GC removes them in
+    ages: BTreeMap<u64, String>,
+    next_age: u64,
+    // Corrupt blocks set aside, keeping their links until re-fetched
+    quarantine: BTreeMap<String, MemoryBlock>,
+    names: RefCell<BTreeMap<String, String>>,
+    priorities: RefCell<BTreeMap<String, u8>>,
+    expiries: RefCell<BTreeMap<String, u64>>,
+    // CIDs acknowledged before their blocks arrive
+    acked: RefCell<BTreeSet<String>>,
+    state: RefCell<BTreeMap<String, Vec<u8>>>,
+    usage: u64,
+    high: u64,
+    gc: GcActivity,
+}
+
+struct MemoryBlock {
+    data: Vec<u8>,
+    links: Vec<String>,
+    age: u64,
+}
+
+impl MemoryStorageProvider {
+    pub fn new(high_usage: u64) -> Self {
+        Self {
+            blocks: BTreeMap::default(),
+            ages: BTreeMap::default(),
+            next_age: 0,
+            quarantine: BTreeMap::default(),
+            names: RefCell::default(),
+            priorities: RefCell::default(),
+            expiries: RefCell::default(),
+            acked: RefCell::default(),
+            state: RefCell::default(),
+            usage: 0,
+            high: high_usage,
+            gc: GcActivity::default(),
+        }
+    }
+    // Bytes of the blocks in DAGs given a nonzero priority
+    fn pinned_bytes(&self) -> u64 {
+        let roots: Vec<String> = self
+            .priorities
+            .borrow()
+            .iter()
+            .filter(|(_, p)| **p > 0)
+            .map(|(cid, _)| cid.clone())
+            .collect();
+        pinned_bytes(self, roots, |cid| {
+            self.blocks
+                .get(cid)
+                .map(|b| b.data.len() as u64)
+                .unwrap_or(0)
+        })
+    }
+    // Every CID in the DAG under root, whether or not its block is here, parents first.
+    // Unless unique, a CID appears once per link to it.
+    fn dag_cids(&self, root: &str, unique: bool) -> Vec<String> {
+        let mut result = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![root.to_owned()];
+        while let Some(cid) = todo.pop() {
+            if unique && !seen.insert(cid.clone()) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&cid) {
+                todo.extend(block.links.iter().rev().cloned());
+            }
+            result.push(cid);
+        }
+        result
+    }
+    fn take_block(&mut self, cid: &str) -> Option<MemoryBlock> {
+        let block = self.blocks.remove(cid)?;
+        self.ages.remove(&block.age);
+        self.usage -= block.data.len() as u64;
+        Some(block)
+    }
+    // Returns the bytes freed
+    fn remove_block(&mut self, cid: &str) -> u64 {
+        self.names.borrow_mut().remove(cid);
+        self.priorities.borrow_mut().remove(cid);
+        self.take_block(cid)
+            .map(|b| b.data.len() as u64)
+            .unwrap_or(0)
+    }
+    // Drops every DAG that has expired, if any has
+    fn remove_expired_dags(&mut self) -> Result<bool> {
+        let now = now_secs();
+        let roots = self.get_expired_dags(now)?;
+        if roots.is_empty() {
+            return Ok(false);
+        }
+        let cids = expired_dag_cids(self, &roots, now)?;
+        let freed = cids.iter().map(|c| self.remove_block(c)).sum();
+        self.gc.removed(cids.len() as u64, freed);
+        for root in &roots {
+            self.expiries.borrow_mut().remove(root);
+        }
+        info!("Removed expired DAGs {roots:?}, {} blocks", cids.len());
+        Ok(true)
+    }
+}
+
+impl StorageProvider for MemoryStorageProvider {
+    fn import_block(&mut self, block: &StoredBlock) -> Result<()> {
+        self.take_block(&block.cid);
+        self.quarantine.remove(&block.cid);
+        self.acked.borrow_mut().remove(&block.cid);
+        let age = self.next_age;
+        self.next_age += 1;
+        self.ages.insert(age, block.cid.clone());
+        self.usage += block.data.len() as u64;
+        self.blocks.insert(
+            block.cid.clone(),
+            MemoryBlock {
+                data: block.data.clone(),
+                links: block.links.clone(),
+                age,
+            },
+        );
+        if let Some(name) = &block.filename {
+            self.name_dag(&block.cid, name)?;
+        }
+        Ok(())
+    }
+
+    fn get_available_cids(&self) -> Result<Vec<String>> {
+        Ok(self.blocks.keys().cloned().collect())
+    }
+
+    fn get_block_by_cid(&self, cid: &str) -> Result<StoredBlock> {
+        if let Some(block) = inlined_block(cid) {
+            return Ok(block);
+        }
+        let Some(block) = self.blocks.get(cid) else {
+            bail!("Block {cid} not found");
+        };
+        Ok(StoredBlock {
+            cid: cid.to_owned(),
+            filename: self.names.borrow().get(cid).cloned(),
+            data: block.data.clone(),
+            links: block.links.clone(),
+        })
+    }
+
+    fn get_links_by_cid(&self, cid: &str) -> Result<Vec<String>> {
+        if let Some(block) = inlined_block(cid) {
+            return Ok(block.links);
+        }
+        match self.blocks.get(cid).or_else(|| self.quarantine.get(cid)) {
+            Some(block) => Ok(block.links.clone()),
+            None => bail!("Links of {cid} unknown"),
+        }
+    }
+
+    fn list_available_dags(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .names
+            .borrow()
+            .iter()
+            .filter(|(c, _)| self.blocks.contains_key(*c))
+            .map(|(c, n)| (c.clone(), n.clone()))
+            .collect())
+    }
+
+    fn name_dag(&self, cid: &str, file_name: &str) -> Result<()> {
+        self.names
+            .borrow_mut()
+            .insert(cid.to_owned(), file_name.to_owned());
+        Ok(())
+    }
+
+    fn get_name(&self, cid: &str) -> Result<String> {
+        match self.names.borrow().get(cid) {
+            Some(name) => Ok(name.clone()),
+            None => bail!("{cid} has no name"),
+        }
+    }
+
+    fn set_priority(&self, cid: &str, priority: u8) -> Result<()> {
+        if !self.blocks.contains_key(cid) && !self.quarantine.contains_key(cid) {
+            bail!("Can't prioritize unknown DAG {cid}");
+        }
+        self.priorities
+            .borrow_mut()
+            .insert(cid.to_owned(), priority);
+        Ok(())
+    }
+
+    fn get_priority(&self, cid: &str) -> Result<u8> {
+        Ok(self.priorities.borrow().get(cid).copied().unwrap_or(0))
+    }
+
+    fn set_expiry(&self, cid: &str, expires: u64) -> Result<()> {
+        self.expiries.borrow_mut().insert(cid.to_owned(), expires);
+        Ok(())
+    }
+
+    fn get_expiry(&self, cid: &str) -> Result<Option<u64>> {
+        Ok(self.expiries.borrow().get(cid).copied())
+    }
+
+    fn get_expired_dags(&self, now: u64) -> Result<Vec<String>> {
+        let mut result: Vec<(u64, String)> = self
+            .expiries
+            .borrow()
+            .iter()
+            .filter(|(_, e)| **e <= now)
+            .map(|(c, e)| (*e, c.clone()))
+            .collect();
+        result.sort();
+        Ok(result.into_iter().map(|(_, c)| c).collect())
+    }
+
+    fn get_missing_cid_blocks(&self, cid: &str) -> Result<Vec<String>> {
+        Ok(self
+            .dag_cids(cid, true)
+            .into_iter()
+            .filter(|c| !self.blocks.contains_key(c) && !is_inlined(c))
+            .collect())
+    }
+
+    fn get_dag_blocks_by_window(
+        &self,
+        cid: &str,
+        offset: u32,
+        window_size: u32,
+    ) -> Result<Vec<StoredBlock>> {
+        self.get_all_dag_blocks(cid).map(|blocks| {
+            blocks
+                .into_iter()
+                .skip(offset as usize)
+                .take(window_size as usize)
+                .collect()
+        })
+    }
+
+    fn get_all_dag_cids(
+        &self,
+        cid: &str,
+        offset: Option<u32>,
+        window_size: Option<u32>,
+    ) -> Result<Vec<String>> {
+        let cids = self.dag_cids(cid, true).into_iter();
+        Ok(match (offset, window_size) {
+            (Some(o), Some(w)) => cids.skip(o as usize).take(w as usize).collect(),
+            _ => cids.collect(),
+        })
+    }
+
+    fn get_all_dag_blocks(&self, cid: &str) -> Result<Vec<StoredBlock>> {
+        self.dag_cids(cid, false)
+            .iter()
+            .filter(|c| self.blocks.contains_key(*c) || is_inlined(c))
+            .map(|c| self.get_block_by_cid(c))
+            .collect()
+    }
+
+    fn incremental_gc(&mut self) -> bool {
+        // Expired DAGs go first, whatever the usage, as nobody wants them anymore
+        match self.remove_expired_dags() {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => error!("Trouble removing expired DAGs: {e:?}"),
+        }
+        if self.usage < self.high {
+            trace!("No need to GC: usage={} < high={}", self.usage, self.high);
+            return false;
+        }
+        let Some((_, oldest)) = self.ages.first_key_value() else {
+            return false;
+        };
+        let oldest = oldest.clone();
+        let freed = self.remove_block(&oldest);
+        info!(
+            "Removed {oldest} as usage ({}) > max ({})",
+            self.usage + freed,
+            self.high
+        );
+        self.gc.removed(1, freed);
+        true
+    }
+
+    fn has_cid(&self, cid: &Cid) -> bool {
+        ipfs_unixfs::inlined_data(cid).is_some() || self.blocks.contains_key(&cid.to_string())
+    }
+
+    fn ack_cid(&self, cid: &Cid) {
+        if !self.has_cid(cid) {
+            self.acked.borrow_mut().insert(cid.to_string());
+        }
+    }
+
+    fn get_dangling_cids(&self) -> Result<Vec<Cid>> {
+        let linked = self.blocks.values().flat_map(|b| b.links.iter());
+        let dangling: BTreeSet<&String> = linked
+            .chain(self.quarantine.keys())
+            .filter(|c| !self.blocks.contains_key(*c) && !is_inlined(c))
+            .collect();
+        Ok(dangling
+            .into_iter()
+            .chain(self.acked.borrow().iter())
+            .filter_map(|c| Cid::try_from(c.as_str()).ok())
+            .collect::<BTreeSet<Cid>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn get_cids_after(&self, after: &str, limit: usize) -> Result<Vec<String>> {
+        Ok(self
+            .blocks
+            .range::<str, _>((Bound::Excluded(after), Bound::Unbounded))
+            .take(limit)
+            .map(|(c, _)| c.clone())
+            .collect())
+    }
+
+    fn quarantine_block(&mut self, cid: &str) -> Result<()> {
+        let Some(block) = self.take_block(cid) else {
+            bail!("Can't quarantine {cid}, which isn't stored");
+        };
+        // Links, name and priority stay, so the DAG is whole again once the block is re-fetched
+        self.quarantine.insert(cid.to_owned(), block);
+        Ok(())
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        Ok(StorageStats {
+            used_bytes: self.usage,
+            blocks: self.blocks.len() as u64,
+            dags: self.list_available_dags()?.len() as u64,
+            dangling_cids: self.get_dangling_cids()?.len() as u64,
+            pinned_bytes: self.pinned_bytes(),
+            ..self.gc.stats()
+        })
+    }
+
+    fn save_state(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.state
+            .borrow_mut()
+            .insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn load_state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.state.borrow().get(key).cloned())
+    }
+
+    fn delete_state(&self, key: &str) -> Result<()> {
+        self.state.borrow_mut().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::MultihashDigest;
+
+    fn cid(s: &[u8]) -> String {
+        Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(s)).to_string()
+    }
+
+    fn block(c: &str, links: Vec<String>, name: Option<&str>) -> StoredBlock {
+        StoredBlock {
+            cid: c.to_owned(),
+            data: c.as_bytes().to_vec(),
+            links,
+            filename: name.map(String::from),
+        }
+    }
+
+    // root -> [a -> [c], b], imported with b missing
+    fn import_tree(provider: &mut MemoryStorageProvider) -> (String, String, String, String) {
+        let (root, a, b, c) = (cid(b"r"), cid(b"a"), cid(b"b"), cid(b"c"));
+        for blk in [
+            block(&root, vec![a.clone(), b.clone()], Some("tree")),
+            block(&a, vec![c.clone()], None),
+            block(&c, vec![], None),
+        ] {
+            provider.import_block(&blk).unwrap();
+        }
+        (root, a, b, c)
+    }
+
+    #[test]
+    fn test_dag_windows_and_missing_blocks() {
+        let mut provider = MemoryStorageProvider::new(u64::MAX);
+        let (root, a, b, c) = import_tree(&mut provider);
+
+        assert_eq!(
+            provider.get_all_dag_cids(&root, None, None).unwrap(),
+            vec![root.clone(), a.clone(), c.clone(), b.clone()]
+        );
+        assert_eq!(
+            provider.get_all_dag_cids(&root, Some(1), Some(2)).unwrap(),
+            vec![a.clone(), c.clone()]
+        );
+        let window: Vec<String> = provider
+            .get_dag_blocks_by_window(&root, 1, 5)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.cid)
+            .collect();
+        assert_eq!(window, vec![a, c.clone()]);
+        assert_eq!(
+            provider.get_missing_cid_blocks(&root).unwrap(),
+            vec![b.clone()]
+        );
+        assert_eq!(
+            provider.get_dangling_cids().unwrap(),
+            vec![Cid::try_from(b.as_str()).unwrap()]
+        );
+        assert_eq!(
+            provider.list_available_dags().unwrap(),
+            vec![(root.clone(), "tree".to_string())]
+        );
+        let held = provider.get_available_cids().unwrap();
+        assert_eq!(provider.get_cids_after("", 9).unwrap(), held);
+        assert_eq!(
+            provider.get_cids_after(&held[0], 1).unwrap(),
+            vec![held[1].clone()]
+        );
+
+        provider.import_block(&block(&b, vec![], None)).unwrap();
+        assert!(provider.get_missing_cid_blocks(&root).unwrap().is_empty());
+        assert!(provider.get_dangling_cids().unwrap().is_empty());
+        assert_eq!(provider.get_all_dag_blocks(&root).unwrap().len(), 4);
+
+        // Repeated content: blocks come once per link, CIDs once
+        let twice = cid(b"t");
+        provider
+            .import_block(&block(&twice, vec![c.clone(), c.clone()], None))
+            .unwrap();
+        assert_eq!(provider.get_all_dag_blocks(&twice).unwrap().len(), 3);
+        assert_eq!(
+            provider.get_all_dag_cids(&twice, None, None).unwrap(),
+            vec![twice, c]
+        );
+    }
+
+    #[test]
+    fn test_inlined_blocks_always_present() {
+        let mut provider = MemoryStorageProvider::new(u64::MAX);
+        let tiny = ipfs_unixfs::inline_cid(ipfs_unixfs::codecs::Codec::Raw, b"tiny")
+            .unwrap()
+            .to_string();
+        let (root, c) = (cid(b"r"), cid(b"c"));
+        for blk in [
+            block(&root, vec![tiny.clone(), c.clone()], Some("tree")),
+            block(&c, vec![], None),
+        ] {
+            provider.import_block(&blk).unwrap();
+        }
+
+        assert!(provider.has_cid(&Cid::try_from(tiny.as_str()).unwrap()));
+        assert!(provider.get_missing_cid_blocks(&root).unwrap().is_empty());
+        assert!(provider.get_dangling_cids().unwrap().is_empty());
+        assert_eq!(provider.get_block_by_cid(&tiny).unwrap().data, b"tiny");
+        assert!(provider.get_links_by_cid(&tiny).unwrap().is_empty());
+        let blocks = provider.get_all_dag_blocks(&root).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks[1].hash_matches().unwrap());
+        assert_eq!(provider.get_available_cids().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_gc_removes_oldest_until_under_cap() {
+        let mut provider = MemoryStorageProvider::new(150);
+        let (root, a, _, c) = import_tree(&mut provider);
+        let used = provider.get_stats().unwrap().used_bytes;
+        assert!(used >= 150);
+
+        assert!(provider.incremental_gc());
+        assert!(!provider.has_cid(&Cid::try_from(root.as_str()).unwrap()));
+        assert!(provider.get_name(&root).is_err());
+        assert!(!provider.incremental_gc());
+        assert!(provider.has_cid(&Cid::try_from(a.as_str()).unwrap()));
+        assert!(provider.has_cid(&Cid::try_from(c.as_str()).unwrap()));
+
+        let stats = provider.get_stats().unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.used_bytes, used - root.len() as u64);
+        assert_eq!(stats.gc_blocks_removed, 1);
+    }
+
+    #[test]
+    fn test_expiry_quarantine_and_state() {
+        let mut provider = MemoryStorageProvider::new(u64::MAX);
+        let (root, a, _, _) = import_tree(&mut provider);
+        provider.set_priority(&root, 4).unwrap();
+        assert!(provider.set_priority(&cid(b"x"), 4).is_err());
+
+        provider.quarantine_block(&root).unwrap();
+        assert!(provider.get_block_by_cid(&root).is_err());
+        assert_eq!(provider.get_links_by_cid(&root).unwrap().len(), 2);
+        assert!(provider
+            .get_dangling_cids()
+            .unwrap()
+            .contains(&Cid::try_from(root.as_str()).unwrap()));
+        provider.import_block(&block(&root, vec![a], None)).unwrap();
+        assert_eq!(provider.get_name(&root).unwrap(), "tree");
+        assert_eq!(provider.get_priority(&root).unwrap(), 4);
+
+        provider.set_expiry(&root, 1).unwrap();
+        assert_eq!(provider.get_expired_dags(now_secs()).unwrap(), vec![root]);
+        assert!(provider.incremental_gc());
+        assert!(provider.get_available_cids().unwrap().is_empty());
+        assert!(provider.get_expired_dags(now_secs()).unwrap().is_empty());
+
+        assert_eq!(provider.load_state("sync").unwrap(), None);
+        provider.save_state("sync", b"queued").unwrap();
+        assert_eq!(
+            provider.load_state("sync").unwrap(),
+            Some(b"queued".to_vec())
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::block::StoredBlock;
-use crate::provider::StorageProvider;
+use crate::provider::{StorageProvider, StorageStats};
 use anyhow::bail;
 use cid::Cid;
 
@@ -19,6 +19,30 @@ impl StorageProvider for NullStorageProvider {
     fn get_name(&self, _cid: &str) -> anyhow::Result<String> {
         bail!("nope")
     }
+    fn set_priority(&self, _cid: &str, _priority: u8) -> anyhow::Result<()> {
+        bail!("NullStorageProvider does not implement anything")
+    }
+    fn get_priority(&self, _cid: &str) -> anyhow::Result<u8> {
+        Ok(0)
+    }
+    fn set_expiry(&self, _cid: &str, _expires: u64) -> anyhow::Result<()> {
+        bail!("NullStorageProvider does not implement anything")
+    }
+    fn get_expiry(&self, _cid: &str) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+    fn get_expired_dags(&self, _now: u64) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+    fn get_cids_after(&self, _after: &str, _limit: usize) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+    fn quarantine_block(&mut self, _cid: &str) -> anyhow::Result<()> {
+        bail!("NullStorageProvider does not implement anything")
+    }
+    fn get_stats(&self) -> anyhow::Result<StorageStats> {
+        Ok(StorageStats::default())
+    }
     fn get_block_by_cid(&self, _cid: &str) -> anyhow::Result<StoredBlock> {
         bail!("NullStorageProvider does not implement anything")
     }
@@ -70,4 +94,16 @@ impl StorageProvider for NullStorageProvider {
     }
 
     fn ack_cid(&self, _cid: &Cid) {}
+
+    fn save_state(&self, _key: &str, _value: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_state(&self, _key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn delete_state(&self, _key: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
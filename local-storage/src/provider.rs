@@ -1,7 +1,11 @@
-use crate::block::StoredBlock;
-use anyhow::Result;
+use crate::{at_rest::Cipher, block::StoredBlock, compression::Compression};
+use anyhow::{bail, Result};
 use cid::Cid;
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[allow(unused_imports)]
 use crate::null_provider::NullStorageProvider;
@@ -12,11 +16,46 @@ use crate::sql_provider::SqliteStorageProvider;
 #[cfg(feature = "files")]
 use crate::file_provider::FileStorageProvider;
 
+#[cfg(feature = "memory")]
+use crate::memory_provider::MemoryStorageProvider;
+
+// storage_path asking for nothing to be kept on disk
+pub const IN_MEMORY: &str = ":memory:";
+
 pub type Handle = Arc<Mutex<dyn StorageProvider + Send>>;
 
 pub trait StorageProvider {
     // Import a stored block
     fn import_block(&mut self, block: &StoredBlock) -> Result<()>;
+    // Starts an import whose blocks are staged, to become part of storage all at once on commit_import.
+    // Providers which keep nothing across a power cut import them straight away.
+    fn begin_import(&mut self) -> Result<u64> {
+        Ok(0)
+    }
+    fn stage_block(&mut self, _import: u64, block: &StoredBlock) -> Result<()> {
+        self.import_block(block)
+    }
+    // Makes every staged block part of storage and names the root, all or nothing even across a power cut
+    fn commit_import(&mut self, _import: u64, root: &str, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            self.name_dag(root, name)?;
+        }
+        Ok(())
+    }
+    // Drops whatever was staged
+    fn abort_import(&mut self, _import: u64) {}
+    // Imports a whole DAG and names its root, all or nothing
+    fn import_dag(&mut self, blocks: &[StoredBlock], root: &str, name: Option<&str>) -> Result<()> {
+        let import = self.begin_import()?;
+        let imported = blocks
+            .iter()
+            .try_for_each(|b| self.stage_block(import, b))
+            .and_then(|_| self.commit_import(import, root, name));
+        if imported.is_err() {
+            self.abort_import(import);
+        }
+        imported
+    }
     // Requests a list of CIDs currently available in storage
     fn get_available_cids(&self) -> Result<Vec<String>>;
     // Requests the block associated with the given CID
@@ -27,6 +66,16 @@ pub trait StorageProvider {
     // Attaches filename to dag
     fn name_dag(&self, cid: &str, file_name: &str) -> Result<()>;
     fn get_name(&self, cid: &str) -> Result<String>;
+    // Attaches a scheduling priority to dag, higher is more urgent
+    fn set_priority(&self, cid: &str, priority: u8) -> Result<()>;
+    // Scheduling priority of dag, 0 if none was ever set
+    fn get_priority(&self, cid: &str) -> Result<u8>;
+    // Marks dag to expire at the given unix time (in seconds), whether or not its blocks are here yet
+    fn set_expiry(&self, cid: &str, expires: u64) -> Result<()>;
+    // When dag expires, if ever
+    fn get_expiry(&self, cid: &str) -> Result<Option<u64>>;
+    // DAGs which expired at or before now, soonest-expired first
+    fn get_expired_dags(&self, now: u64) -> Result<Vec<String>>;
     fn get_missing_cid_blocks(&self, cid: &str) -> Result<Vec<String>>;
     fn get_dag_blocks_by_window(
         &self,
@@ -45,9 +94,154 @@ pub trait StorageProvider {
     fn has_cid(&self, cid: &Cid) -> bool;
     fn ack_cid(&self, cid: &Cid);
     fn get_dangling_cids(&self) -> Result<Vec<Cid>>;
+    // Up to limit CIDs of stored blocks, in order, starting after the given one ("" to start at the beginning)
+    fn get_cids_after(&self, after: &str, limit: usize) -> Result<Vec<String>>;
+    // Sets a corrupt block's data aside, leaving its CID missing so it gets fetched again
+    fn quarantine_block(&mut self, cid: &str) -> Result<()>;
+    // Summary of how full storage is and what GC has done about it
+    fn get_stats(&self) -> Result<StorageStats>;
+    // Stores an opaque blob of protocol state (e.g. sync queues) to survive restarts
+    fn save_state(&self, key: &str, value: &[u8]) -> Result<()>;
+    // Blob previously saved under key, if any
+    fn load_state(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    // Forgets whatever was saved under key, if anything was
+    fn delete_state(&self, key: &str) -> Result<()>;
+    // How blocks imported from now on are kept at rest. Providers which don't compress ignore it.
+    fn set_compression(&mut self, _compression: Compression) {}
+    // Seals blocks and names with cipher from now on. An encrypted store can only be opened with its key,
+    // and only an empty one can start being encrypted. Providers which keep nothing at rest ignore it.
+    fn set_cipher(&mut self, _cipher: Option<Cipher>) -> Result<()> {
+        Ok(())
+    }
+}
+
+// How full storage is, as a provider sees it. How much it may hold is up to the caller.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StorageStats {
+    pub used_bytes: u64,
+    pub blocks: u64,
+    // Named DAGs
+    pub dags: u64,
+    // CIDs known of (e.g. linked to) whose blocks aren't here
+    pub dangling_cids: u64,
+    // Held in DAGs given a priority, counting blocks they share once
+    pub pinned_bytes: u64,
+    // What GC has done since startup
+    pub gc_runs: u64,
+    pub gc_blocks_removed: u64,
+    pub gc_bytes_freed: u64,
+}
+
+// What GC has removed since startup
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcActivity {
+    // incremental_gc calls which removed something
+    pub runs: u64,
+    pub blocks_removed: u64,
+    pub bytes_freed: u64,
+}
+
+impl GcActivity {
+    pub(crate) fn removed(&mut self, blocks: u64, bytes: u64) {
+        self.runs += 1;
+        self.blocks_removed += blocks;
+        self.bytes_freed += bytes;
+    }
+    // Stats with just these filled in, for a provider to complete
+    pub(crate) fn stats(&self) -> StorageStats {
+        StorageStats {
+            gc_runs: self.runs,
+            gc_blocks_removed: self.blocks_removed,
+            gc_bytes_freed: self.bytes_freed,
+            ..StorageStats::default()
+        }
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Adds the CIDs held locally anywhere in the DAG under root, not descending into what's in seen
+fn held_dag_cids<P: StorageProvider + ?Sized>(
+    provider: &P,
+    root: &str,
+    seen: &mut BTreeSet<String>,
+    result: &mut BTreeSet<String>,
+) {
+    let mut todo = vec![root.to_owned()];
+    while let Some(cid_str) = todo.pop() {
+        if !seen.insert(cid_str.clone()) {
+            continue;
+        }
+        if let Ok(cid) = Cid::try_from(cid_str.as_str()) {
+            if provider.has_cid(&cid) {
+                result.insert(cid_str.clone());
+            }
+        }
+        todo.extend(provider.get_links_by_cid(&cid_str).unwrap_or_default());
+    }
+}
+
+// Bytes of the blocks held in the DAGs under roots, each block counted once
+#[cfg(any(test, feature = "files", feature = "memory"))]
+pub(crate) fn pinned_bytes<P: StorageProvider + ?Sized, F: Fn(&str) -> u64>(
+    provider: &P,
+    roots: impl IntoIterator<Item = String>,
+    size: F,
+) -> u64 {
+    let mut held = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    for root in roots {
+        held_dag_cids(provider, &root, &mut seen, &mut held);
+    }
+    held.iter().map(|c| size(c)).sum()
+}
+
+// Blocks which may go along with the expired DAGs: those no unexpired named DAG still uses.
+// What's still in use is walked once for all of them, each block at most once.
+pub(crate) fn expired_dag_cids<P: StorageProvider + ?Sized>(
+    provider: &P,
+    roots: &[String],
+    now: u64,
+) -> Result<BTreeSet<String>> {
+    let mut result = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    for root in roots {
+        held_dag_cids(provider, root, &mut seen, &mut result);
+    }
+    if result.is_empty() {
+        return Ok(result);
+    }
+    let mut in_use = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    for (other, name) in provider.list_available_dags()? {
+        if roots.contains(&other) || name.is_empty() {
+            continue;
+        }
+        if provider.get_expiry(&other)?.map(|e| e <= now) == Some(true) {
+            continue;
+        }
+        held_dag_cids(provider, &other, &mut seen, &mut in_use);
+    }
+    Ok(&result - &in_use)
 }
 
 pub fn default_storage_provider(_storage_path: &str, _high_disk_usage: u64) -> Result<Handle> {
+    #[cfg(feature = "memory")]
+    if _storage_path == IN_MEMORY || cfg!(all(not(feature = "files"), not(feature = "sqlite"))) {
+        return Ok(Arc::new(Mutex::new(MemoryStorageProvider::new(
+            _high_disk_usage,
+        ))));
+    }
+    // Rather than quietly keeping everything on disk under a directory named for it
+    #[cfg(not(feature = "memory"))]
+    if _storage_path == IN_MEMORY {
+        bail!("Storage path {IN_MEMORY} asks for in-memory storage, but in-memory storage is not built in");
+    }
     #[cfg(all(not(feature = "files"), not(feature = "sqlite")))]
     let provider = NullStorageProvider::default();
     #[cfg(all(feature = "files", not(feature = "sqlite")))]
@@ -56,3 +250,59 @@ pub fn default_storage_provider(_storage_path: &str, _high_disk_usage: u64) -> R
     let provider = SqliteStorageProvider::new(_storage_path)?;
     Ok(Arc::new(Mutex::new(provider)))
 }
+
+// Opens the provider named by a kind:path spec, e.g. sqlite:storage.db or files:storage
+pub fn open_provider(spec: &str, _high_disk_usage: u64) -> Result<Handle> {
+    let Some((kind, _path)) = spec.split_once(':') else {
+        bail!("Expected storage as kind:path (e.g. sqlite:storage.db), not {spec}");
+    };
+    match kind {
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Arc::new(Mutex::new(SqliteStorageProvider::new(_path)?))),
+        #[cfg(feature = "files")]
+        "files" => Ok(Arc::new(Mutex::new(FileStorageProvider::new(
+            _path,
+            _high_disk_usage,
+        )?))),
+        #[cfg(feature = "memory")]
+        "memory" => Ok(Arc::new(Mutex::new(MemoryStorageProvider::new(
+            _high_disk_usage,
+        )))),
+        _ => bail!("Storage kind {kind} is unknown or not built in"),
+    }
+}
+
+// Opens the provider named by a kind:path spec only to read from it, changing nothing there
+pub fn open_provider_read_only(spec: &str) -> Result<Handle> {
+    let Some((kind, _path)) = spec.split_once(':') else {
+        bail!("Expected storage as kind:path (e.g. sqlite:storage.db), not {spec}");
+    };
+    match kind {
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Arc::new(Mutex::new(SqliteStorageProvider::open_read_only(
+            _path,
+        )?))),
+        #[cfg(feature = "files")]
+        "files" => Ok(Arc::new(Mutex::new(FileStorageProvider::open_read_only(
+            _path,
+        )?))),
+        "memory" => bail!("A memory store starts out empty, there is nothing to read from it"),
+        _ => bail!("Storage kind {kind} is unknown or not built in"),
+    }
+}
+
+#[cfg(all(test, not(feature = "memory")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_in_memory_path_refused_without_memory_feature() {
+        let err = default_storage_provider(IN_MEMORY, 9).err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("in-memory storage is not built in"),
+            "{err}"
+        );
+        assert!(!std::path::Path::new(IN_MEMORY).exists());
+    }
+}
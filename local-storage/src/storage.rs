@@ -1,56 +1,155 @@
-use crate::{block::StoredBlock, error::StorageError, provider::Handle as ProviderHandle};
+use crate::{
+    block::StoredBlock,
+    error::StorageError,
+    provider::{now_secs, Handle as ProviderHandle, StorageStats},
+};
 use anyhow::{bail, Result};
-use cid::Cid;
+use cid::{multihash::Code, Cid};
 use futures::TryStreamExt;
 use ipfs_unixfs::{
     builder::{File, FileBuilder},
-    Block,
+    codecs::Codec,
+    encode_document, DEFAULT_HASH,
 };
 use log::warn;
-use std::{fs::File as FsFile, io::Write, path::Path, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fs,
+    fs::File as FsFile,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use log::{debug, error, info, trace};
 
+// Links a stem of a tree may have and still fit in a block. A link takes about 50 bytes,
+// or more when it is an identity CID carrying up to inline_limit bytes rather than a 32-byte digest.
+fn degree(block_size: u32, inline_limit: usize) -> usize {
+    let link_size = 50 + inline_limit.saturating_sub(32);
+    ((block_size as usize - 8) / link_size).clamp(
+        //A stem in a tree must be allowed at least 2 links for it to be a tree
+        2,
+        //the default degree is also the spec-defined max
+        ipfs_unixfs::balanced_tree::DEFAULT_DEGREE,
+    )
+}
+
+// Outcome of scrubbing a batch of blocks
+#[derive(Debug, Default)]
+pub struct ScrubPass {
+    // Last CID looked at, to carry on after next time
+    pub last: Option<String>,
+    pub checked: usize,
+    pub corrupt: Vec<String>,
+}
+
 pub struct Storage {
     provider: ProviderHandle,
     block_size: u32,
     degree: usize,
+    // Multihash new blocks are addressed by
+    hash: Code,
+    // Chunks of up to this many bytes are inlined into their parents as identity CIDs
+    inline_limit: usize,
+    // Where recent breadth-first walks for DAG windows got to, most recent last
+    walks: Mutex<VecDeque<DagWalk>>,
+}
+
+// How many DAGs' walks to keep, e.g. one per transfer in progress
+const CACHED_WALKS: usize = 4;
+
+// A breadth-first walk of a DAG, up to `position`: the links of every block before it have been
+// queued, the first of which is at `position`. Positions are fixed by the DAG's content, so
+// this stays valid whichever of its blocks come and go.
+struct DagWalk {
+    root: String,
+    position: u32,
+    queue: VecDeque<String>,
 }
 
 impl Storage {
     pub fn new(provider: ProviderHandle, block_size: u32) -> Self {
-        let degree = ((block_size as usize - 8) / 50).clamp(
-            //A stem in a tree must be allowed at least 2 links for it to be a tree
-            2,
-            //the default degree is also the spec-defined max
-            ipfs_unixfs::balanced_tree::DEFAULT_DEGREE,
-        );
         Storage {
             provider,
             block_size,
-            degree,
+            degree: degree(block_size, 0),
+            hash: DEFAULT_HASH,
+            inline_limit: 0,
+            walks: Mutex::default(),
+        }
+    }
+    pub fn set_hash(&mut self, hash: Code) {
+        self.hash = hash;
+    }
+    pub fn hash(&self) -> Code {
+        self.hash
+    }
+    // Stems get fewer links to make room for inlined ones, so they still fit in a block
+    pub fn set_inline_limit(&mut self, limit: usize) -> Result<()> {
+        if limit > ipfs_unixfs::MAX_INLINE {
+            bail!(
+                "Can't inline blocks of {limit} bytes, at most {}",
+                ipfs_unixfs::MAX_INLINE
+            );
         }
+        self.inline_limit = limit;
+        self.degree = degree(self.block_size, limit);
+        Ok(())
     }
     pub fn import_path(&mut self, path: &Path) -> Result<String> {
-        debug!("import_path({:?})", &path);
+        self.import_path_with_hash(path, self.hash)
+    }
+
+    pub fn import_path_with_hash(&mut self, path: &Path, hash: Code) -> Result<String> {
+        debug!("import_path({:?}, {hash:?})", &path);
         let rt = tokio::runtime::Runtime::new()?;
-        let blocks: Result<Vec<Block>> = rt.block_on(async {
-            let file: File = FileBuilder::new()
-                .path(path)
-                .fixed_chunker(self.block_size.try_into()?)
-                .degree(self.degree)
-                .build()
-                .await?;
-            let blocks: Vec<_> = file.encode().await?.try_collect().await?;
-            Ok(blocks)
-        });
-        let blocks = blocks?;
-        for block in &blocks {
-            assert!(block.data().len() <= self.block_size as usize);
+        let import = self.provider.lock().unwrap().begin_import()?;
+        let imported =
+            rt.block_on(self.stage_file(import, path, hash))
+                .and_then(|(root_cid, count)| {
+                    let filename = path.file_name().and_then(|p| p.to_str());
+                    // Whole and named, or not there at all
+                    self.provider
+                        .lock()
+                        .unwrap()
+                        .commit_import(import, &root_cid, filename)?;
+                    Ok((root_cid, count))
+                });
+        match imported {
+            Ok((root_cid, count)) => {
+                info!(
+                    "Imported path {} to {} in {} blocks",
+                    path.display(),
+                    root_cid,
+                    count
+                );
+                Ok(root_cid)
+            }
+            Err(e) => {
+                self.provider.lock().unwrap().abort_import(import);
+                Err(e)
+            }
         }
-        let mut root_cid: Option<String> = None;
+    }
 
-        blocks.iter().for_each(|b| {
+    // Hands blocks to the provider as the encoder makes them, so only a few are in memory at once.
+    // Returns the root's CID and how many blocks there were.
+    async fn stage_file(&self, import: u64, path: &Path, hash: Code) -> Result<(String, usize)> {
+        let file: File = FileBuilder::new()
+            .path(path)
+            .fixed_chunker(self.block_size.try_into()?)
+            .degree(self.degree)
+            .hash(hash)
+            .inline_limit(self.inline_limit)
+            .build()
+            .await?;
+        let mut blocks = Box::pin(file.encode().await?);
+        let mut root_cid: Option<String> = None;
+        let mut first_cid: Option<String> = None;
+        let mut count = 0;
+        while let Some(b) = blocks.try_next().await? {
+            assert!(b.data().len() <= self.block_size as usize);
             let links = b
                 .links()
                 .iter()
@@ -62,41 +161,71 @@ impl Storage {
                 links,
                 filename: None,
             };
-            // First validate each block
-            if let Err(_e) = stored.validate() {
-                error!("Failed to validate {}, {_e}", b.cid());
+            if let Err(e) = stored.validate() {
+                bail!("Failed to validate {}: {e}", b.cid());
             }
-            if let Err(_e) = self.provider.lock().unwrap().import_block(&stored) {
-                error!("Failed to import block {_e}");
+            if first_cid.is_none() {
+                first_cid = Some(stored.cid.clone());
             }
             if !stored.links.is_empty() {
-                root_cid = Some(stored.cid);
-            }
-        });
-        if blocks.len() == 1 {
-            if let Some(first) = blocks.first() {
-                root_cid = Some(first.cid().to_string());
+                root_cid = Some(stored.cid.clone());
             }
+            self.provider.lock().unwrap().stage_block(import, &stored)?;
+            count += 1;
         }
-        if let Some(root_cid) = root_cid {
-            if let Some(filename) = path.file_name().and_then(|p| p.to_str()) {
-                let lck = self.provider.lock().unwrap();
-                lck.name_dag(&root_cid, filename)?;
-            }
-            info!(
-                "Imported path {} to {} in {} blocks",
-                path.display(),
-                root_cid,
-                blocks.len()
+        if count == 1 {
+            root_cid = first_cid;
+        }
+        match root_cid {
+            Some(root_cid) => Ok((root_cid, count)),
+            None => bail!("Failed to find root block for {path:?}"),
+        }
+    }
+
+    // Imports a JSON or CBOR document as one IPLD block of codec (DagCbor or DagJson), named after the file
+    pub fn import_document(&mut self, path: &Path, codec: Codec, hash: Code) -> Result<String> {
+        let block = encode_document(codec, hash, &fs::read(path)?)?;
+        if block.data().len() > self.block_size as usize {
+            bail!(
+                "{path:?} is {} bytes as {codec:?}, more than fits in a block ({}); split it into documents linking to one another",
+                block.data().len(),
+                self.block_size
             );
-            Ok(root_cid)
-        } else {
-            bail!("Failed to find root block for {path:?}")
         }
+        let stored = StoredBlock {
+            cid: block.cid().to_string(),
+            data: block.data().to_vec(),
+            links: block.links().iter().map(|c| c.to_string()).collect(),
+            filename: None,
+        };
+        let filename = path.file_name().and_then(|p| p.to_str());
+        self.provider
+            .lock()
+            .unwrap()
+            .import_dag(&[stored], &block.cid().to_string(), filename)?;
+        info!(
+            "Imported document {} to {} as {codec:?}, linking to {} CIDs",
+            path.display(),
+            block.cid(),
+            block.links().len()
+        );
+        Ok(block.cid().to_string())
     }
 
     pub fn export_cid(&self, cid: &str, path: &Path) -> Result<()> {
-        let check_missing_blocks = self.get_missing_dag_blocks(cid)?;
+        let root = Cid::try_from(cid)?;
+        // A document is exported as itself, whether or not what it links to is here
+        let document = matches!(
+            Codec::try_from(root.codec()),
+            Ok(Codec::DagCbor | Codec::DagJson)
+        );
+        let check_missing_blocks = if !document {
+            self.get_missing_dag_blocks(cid)?
+        } else if self.has_cid(&root) {
+            vec![]
+        } else {
+            vec![cid.to_string()]
+        };
         if !check_missing_blocks.is_empty() {
             error!(
                 "Can't export {cid} to {}, because we're missing blocks: {:?}",
@@ -106,7 +235,19 @@ impl Storage {
             bail!(StorageError::DagIncomplete(cid.to_string()))
         }
         // Fetch all blocks tied to links under given cid
-        let child_blocks = self.get_all_dag_blocks(cid)?;
+        let child_blocks = if document {
+            let block = self.get_block_by_cid(cid)?;
+            // Only the document itself fits in a file, so leaving out what it links to would lose data
+            if !block.links.is_empty() {
+                bail!(
+                    "Can't export {cid} as a file, it's a document linking to {} other blocks",
+                    block.links.len()
+                );
+            }
+            vec![block]
+        } else {
+            self.get_all_dag_blocks(cid)?
+        };
         debug!(
             "Planning to export {} child_blocks to {path:?}",
             child_blocks.len()
@@ -115,7 +256,7 @@ impl Storage {
         let mut output_file = FsFile::create(path)?;
         // Walk the StoredBlocks and write out to path
         for block in child_blocks {
-            if block.links.is_empty() {
+            if document || block.links.is_empty() {
                 output_file.write_all(&block.data)?;
             }
         }
@@ -185,6 +326,82 @@ impl Storage {
             .get_dag_blocks_by_window(cid, offset, window_size)
     }
 
+    // CIDs at positions [offset, offset + count) of the DAG in breadth-first order, which is
+    // the order both ends of a transfer agree on regardless of provider. If a missing block
+    // could have links, positions past where its children would go can't be known, so the
+    // result stops short there.
+    // Windows are asked for in order, so the walk picks up where the last one for the DAG left
+    // off rather than starting from the root each time.
+    pub fn get_dag_window_cids(&self, cid: &str, offset: u32, count: u32) -> Result<Vec<String>> {
+        let provider = self.provider.lock().unwrap();
+        // The links of a block, or None if they can't be known because it's missing
+        let links = |c: &str| -> Result<Option<Vec<String>>> {
+            let parsed = Cid::try_from(c)?;
+            if provider.has_cid(&parsed) {
+                Ok(Some(provider.get_links_by_cid(c)?))
+            } else if parsed.codec() == u64::from(Codec::Raw) {
+                Ok(Some(Vec::new()))
+            } else {
+                Ok(None)
+            }
+        };
+        let mut walks = self.walks.lock().unwrap();
+        let mut walk = match walks.iter().position(|w| w.root == cid) {
+            Some(i) if walks[i].position <= offset => walks.remove(i).expect("index was found"),
+            _ => DagWalk {
+                root: cid.to_string(),
+                position: 0,
+                queue: VecDeque::from([cid.to_string()]),
+            },
+        };
+        while walk.position < offset {
+            let Some(front) = walk.queue.front() else {
+                break;
+            };
+            let Some(children) = links(front)? else {
+                break;
+            };
+            walk.queue.pop_front();
+            walk.queue.extend(children);
+            walk.position += 1;
+        }
+        // The window itself is read without moving the walk along, as it may be asked for again
+        let mut result = Vec::new();
+        let mut more = Vec::new();
+        let mut known_until = u32::MAX;
+        let mut position = walk.position;
+        for i in 0.. {
+            let curr = match walk.queue.get(i) {
+                Some(c) => c,
+                None => match more.get(i - walk.queue.len()) {
+                    Some(c) => c,
+                    None => break,
+                },
+            };
+            if position >= known_until || result.len() >= count as usize {
+                break;
+            }
+            if position >= offset {
+                result.push(curr.clone());
+            }
+            match links(curr)? {
+                Some(children) if known_until == u32::MAX => more.extend(children),
+                Some(_) => {}
+                None => {
+                    let queued = walk.queue.len() + more.len();
+                    known_until = known_until.min(walk.position + queued as u32);
+                }
+            }
+            position += 1;
+        }
+        walks.retain(|w| w.root != cid);
+        walks.push_back(walk);
+        if walks.len() > CACHED_WALKS {
+            walks.pop_front();
+        }
+        Ok(result)
+    }
+
     pub fn incremental_gc(&mut self) -> bool {
         if let Ok(mut prov) = self.provider.lock() {
             prov.incremental_gc()
@@ -208,6 +425,81 @@ impl Storage {
         Arc::clone(&self.provider)
     }
 
+    pub fn set_priority(&self, cid: &str, priority: u8) -> Result<()> {
+        self.provider.lock().unwrap().set_priority(cid, priority)
+    }
+
+    pub fn get_priority(&self, cid: &str) -> u8 {
+        self.provider
+            .lock()
+            .map(|p| p.get_priority(cid).unwrap_or(0))
+            .unwrap_or(0)
+    }
+
+    // Makes the DAG expire ttl seconds from now
+    pub fn set_ttl(&self, cid: &str, ttl: u64) -> Result<()> {
+        let expires = now_secs().saturating_add(ttl);
+        self.provider.lock().unwrap().set_expiry(cid, expires)
+    }
+
+    // Seconds left until the DAG expires, if it ever does
+    pub fn get_ttl(&self, cid: &str) -> Option<u64> {
+        let expires = self.provider.lock().ok()?.get_expiry(cid).ok()??;
+        Some(expires.saturating_sub(now_secs()))
+    }
+
+    pub fn is_expired(&self, cid: &str) -> bool {
+        self.get_ttl(cid) == Some(0)
+    }
+
+    pub fn get_expired_dags(&self) -> Result<Vec<String>> {
+        self.provider.lock().unwrap().get_expired_dags(now_secs())
+    }
+
+    // Re-hashes up to limit stored blocks following the CID after, quarantining any which no longer match
+    pub fn scrub(&self, after: &str, limit: usize) -> Result<ScrubPass> {
+        let mut provider = self.provider.lock().unwrap();
+        let mut result = ScrubPass::default();
+        for cid in provider.get_cids_after(after, limit)? {
+            result.checked += 1;
+            match provider
+                .get_block_by_cid(&cid)
+                .and_then(|b| b.hash_matches())
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Block {cid} no longer matches its hash, quarantining it");
+                    provider.quarantine_block(&cid)?;
+                    result.corrupt.push(cid.clone());
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(StorageError::CorruptBlock(..))) => {
+                    error!("Block {cid} can't be read back, quarantining it: {e}");
+                    provider.quarantine_block(&cid)?;
+                    result.corrupt.push(cid.clone());
+                }
+                Err(e) => debug!("Unable to check block {cid}: {e:?}"),
+            }
+            result.last = Some(cid);
+        }
+        Ok(result)
+    }
+
+    pub fn get_stats(&self) -> Result<StorageStats> {
+        self.provider.lock().unwrap().get_stats()
+    }
+
+    pub fn save_state(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.provider.lock().unwrap().save_state(key, value)
+    }
+
+    pub fn load_state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.provider.lock().unwrap().load_state(key)
+    }
+
+    pub fn delete_state(&self, key: &str) -> Result<()> {
+        self.provider.lock().unwrap().delete_state(key)
+    }
+
     pub fn set_name(&self, cid: &str, name: &str) {
         if name.is_empty() {
             warn!("Asked to name {cid} to the empty string, being ignored.");
@@ -228,6 +520,7 @@ pub mod tests {
     use super::*;
     use crate::sql_provider::SqliteStorageProvider;
     use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+    use cid::multihash::MultihashDigest;
     use rand::{thread_rng, RngCore};
     use std::sync::{Arc, Mutex};
 
@@ -301,6 +594,80 @@ pub mod tests {
         assert_eq!(available_dags, vec![(root_cid, "data.txt".to_string())]);
     }
 
+    #[test]
+    pub fn test_import_with_other_hash() {
+        let mut harness = TestHarness::new();
+        harness.storage.set_hash(Code::Blake3_256);
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let test_file = temp_dir.child("data.bin");
+        let mut data = vec![0u8; BLOCK_SIZE * 4];
+        thread_rng().fill_bytes(&mut data);
+        test_file.write_binary(&data).unwrap();
+
+        let cid = harness.storage.import_path(test_file.path()).unwrap();
+        let blocks = harness.storage.get_all_dag_blocks(&cid).unwrap();
+        assert_eq!(blocks.len(), 5);
+        for block in &blocks {
+            let parsed = Cid::try_from(block.cid.as_str()).unwrap();
+            assert_eq!(parsed.hash().code(), u64::from(Code::Blake3_256));
+            block.validate().unwrap();
+            assert!(block.hash_matches().unwrap());
+        }
+        let pass = harness.storage.scrub("", 10).unwrap();
+        assert_eq!((pass.checked, pass.corrupt.len()), (5, 0));
+
+        // Asked for explicitly, the same file gets a different address
+        let sha2 = harness
+            .storage
+            .import_path_with_hash(test_file.path(), DEFAULT_HASH)
+            .unwrap();
+        assert_ne!(sha2, cid);
+        let exported = temp_dir.child("exported");
+        harness.storage.export_cid(&cid, exported.path()).unwrap();
+        assert_eq!(std::fs::read(exported.path()).unwrap(), data);
+    }
+
+    #[test]
+    pub fn test_import_with_inlined_tail() {
+        let mut harness = TestHarness::new();
+        harness.storage.set_inline_limit(32).unwrap();
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let test_file = temp_dir.child("data.bin");
+        let mut data = vec![0u8; BLOCK_SIZE * 3 + 20];
+        thread_rng().fill_bytes(&mut data);
+        test_file.write_binary(&data).unwrap();
+
+        let cid = harness.storage.import_path(test_file.path()).unwrap();
+        let root = harness.storage.get_block_by_cid(&cid).unwrap();
+        assert_eq!(root.links.len(), 4);
+        let tail = Cid::try_from(root.links[3].as_str()).unwrap();
+        assert_eq!(
+            ipfs_unixfs::inlined_data(&tail),
+            Some(&data[BLOCK_SIZE * 3..])
+        );
+        assert!(harness.storage.has_cid(&tail));
+        assert!(!harness
+            .storage
+            .list_available_cids()
+            .unwrap()
+            .contains(&root.links[3]));
+        assert_eq!(
+            harness.storage.get_missing_dag_blocks(&cid).unwrap(),
+            Vec::<String>::new()
+        );
+        let blocks = harness.storage.get_all_dag_blocks(&cid).unwrap();
+        assert_eq!(blocks.len(), 5);
+        crate::block::validate_dag(&blocks).unwrap();
+        let exported = temp_dir.child("exported");
+        harness.storage.export_cid(&cid, exported.path()).unwrap();
+        assert_eq!(std::fs::read(exported.path()).unwrap(), data);
+
+        assert!(harness
+            .storage
+            .set_inline_limit(ipfs_unixfs::MAX_INLINE + 1)
+            .is_err());
+    }
+
     #[test]
     pub fn export_path_from_storage() {
         let mut harness = TestHarness::new();
@@ -327,6 +694,77 @@ pub mod tests {
         assert_eq!(test_file_contents, next_test_file_contents);
     }
 
+    #[test]
+    pub fn test_document_dag_imported_traversed_and_exported() {
+        let mut harness = TestHarness::new();
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let image = temp_dir.child("image.bin");
+        image.write_binary(&[7u8; BLOCK_SIZE * 3]).unwrap();
+        let image_cid = harness.storage.import_path(image.path()).unwrap();
+        let elsewhere = Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(b"elsewhere"));
+        let record = temp_dir.child("record.json");
+        record
+            .write_binary(
+                format!(
+                    r#"{{"temp": 21, "image": {{"/": "{image_cid}"}}, "log": {{"/": "{elsewhere}"}}}}"#
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        for codec in [Codec::DagJson, Codec::DagCbor] {
+            let cid = harness
+                .storage
+                .import_document(record.path(), codec, DEFAULT_HASH)
+                .unwrap();
+            assert_eq!(
+                Cid::try_from(cid.as_str()).unwrap().codec(),
+                u64::from(codec)
+            );
+            assert!(harness
+                .storage
+                .list_available_dags()
+                .unwrap()
+                .contains(&(cid.clone(), "record.json".to_string())));
+            // Links are followed into the imported file, and to what isn't here
+            assert_eq!(
+                harness.storage.get_missing_dag_blocks(&cid).unwrap(),
+                vec![elsewhere.to_string()]
+            );
+            assert!(harness
+                .storage
+                .get_all_dag_cids(&cid, None, None)
+                .unwrap()
+                .contains(&image_cid));
+
+            let exported = temp_dir.child("exported");
+            assert!(harness.storage.export_cid(&cid, exported.path()).is_err());
+        }
+
+        // A document linking to nothing is exported as itself
+        let plain = temp_dir.child("plain.json");
+        plain.write_binary(br#"{"temp": 21}"#).unwrap();
+        let cid = harness
+            .storage
+            .import_document(plain.path(), Codec::DagCbor, DEFAULT_HASH)
+            .unwrap();
+        let exported = temp_dir.child("exported");
+        harness.storage.export_cid(&cid, exported.path()).unwrap();
+        assert_eq!(
+            std::fs::read(exported.path()).unwrap(),
+            harness.storage.get_block_by_cid(&cid).unwrap().data
+        );
+
+        let big = temp_dir.child("big.json");
+        big.write_binary(format!("[\"{}\"]", "x".repeat(BLOCK_SIZE)).as_bytes())
+            .unwrap();
+        assert!(harness
+            .storage
+            .import_document(big.path(), Codec::DagJson, DEFAULT_HASH)
+            .is_err());
+        assert_eq!(harness.storage.list_available_dags().unwrap().len(), 4);
+    }
+
     #[test]
     pub fn export_from_storage_various_file_sizes_binary_data() {
         for size in [100, 200, 300, 500, 1_000] {
@@ -381,6 +819,105 @@ pub mod tests {
         }
     }
 
+    #[test]
+    pub fn test_scrub_quarantines_corrupt_blocks_in_batches() {
+        let mut harness = TestHarness::new();
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let test_file = temp_dir.child("data.txt");
+        let mut data = vec![0u8; BLOCK_SIZE * 5];
+        thread_rng().fill_bytes(&mut data);
+        test_file.write_binary(&data).unwrap();
+        let root = harness.storage.import_path(test_file.path()).unwrap();
+        let cids = harness.storage.get_all_dag_cids(&root, None, None).unwrap();
+
+        let mut victim = harness.storage.get_block_by_cid(&cids[2]).unwrap();
+        victim.data[0] ^= 1;
+        rusqlite::Connection::open(harness._db_dir.child("storage.db").path())
+            .unwrap()
+            .execute(
+                "UPDATE blocks SET data = ?1 WHERE cid = ?2",
+                (&victim.data, &victim.cid),
+            )
+            .unwrap();
+
+        let mut corrupt = vec![];
+        let mut checked = 0;
+        let mut after = String::new();
+        loop {
+            let pass = harness.storage.scrub(&after, 2).unwrap();
+            checked += pass.checked;
+            corrupt.extend(pass.corrupt);
+            match pass.last {
+                Some(last) => after = last,
+                None => break,
+            }
+        }
+        assert_eq!(checked, cids.len());
+        assert_eq!(corrupt, vec![victim.cid.clone()]);
+        assert_eq!(
+            harness.storage.get_missing_dag_blocks(&root).unwrap(),
+            vec![victim.cid]
+        );
+        assert_eq!(
+            harness.storage.scrub("", 99).unwrap().checked,
+            cids.len() - 1
+        );
+    }
+
+    #[test]
+    pub fn test_get_dag_window_cids() {
+        let mut harness = TestHarness::new();
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let test_file = temp_dir.child("data.txt");
+
+        let mut data = vec![0u8; BLOCK_SIZE * 200];
+        thread_rng().fill_bytes(&mut data);
+        test_file.write_binary(&data).unwrap();
+        let cid = harness.storage.import_path(test_file.path()).unwrap();
+
+        let full = harness
+            .storage
+            .get_dag_window_cids(&cid, 0, u32::MAX)
+            .unwrap();
+        assert_eq!(
+            full.len(),
+            harness
+                .storage
+                .get_all_dag_cids(&cid, None, None)
+                .unwrap()
+                .len()
+        );
+        for (window_num, chunk) in full.chunks(7).enumerate() {
+            let window = harness
+                .storage
+                .get_dag_window_cids(&cid, window_num as u32 * 7, 7)
+                .unwrap();
+            assert_eq!(chunk, &window);
+        }
+        // Each window carried on from where the walk for the last one stopped
+        let walked = harness.storage.walks.lock().unwrap()[0].position as usize;
+        assert_eq!(walked, (full.len() - 1) / 7 * 7);
+        let first = harness.storage.get_dag_window_cids(&cid, 0, 7).unwrap();
+        assert_eq!(first, full[..7]);
+
+        // Without an interior block, the receiving end can only place blocks up to its children
+        let mut receiver = TestHarness::new();
+        for c in full.iter().filter(|c| *c != &full[1]) {
+            let block = harness.storage.get_block_by_cid(c).unwrap();
+            receiver.storage.import_block(&block).unwrap();
+        }
+        let partial = receiver
+            .storage
+            .get_dag_window_cids(&cid, 0, u32::MAX)
+            .unwrap();
+        assert!(partial.len() > 1 && partial.len() < full.len());
+        assert_eq!(partial, full[..partial.len()]);
+        let block = harness.storage.get_block_by_cid(&full[1]).unwrap();
+        receiver.storage.import_block(&block).unwrap();
+        let window = receiver.storage.get_dag_window_cids(&cid, 1, 7).unwrap();
+        assert_eq!(window, full[1..8]);
+    }
+
     #[test]
     pub fn compare_get_blocks_to_get_cids() {
         let mut harness = TestHarness::new();
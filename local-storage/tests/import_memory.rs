@@ -0,0 +1,90 @@
+// Its own test binary, as the allocator below counts every allocation in the process
+#![cfg(any(feature = "sqlite", feature = "files"))]
+
+use assert_fs::{fixture::PathChild, TempDir};
+use local_storage::{provider::open_provider, storage::Storage};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    io::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Heap an import may use, however big the file
+const MEMORY_BUDGET: usize = 4 << 20;
+const FILE_SIZE: usize = 6 * MEMORY_BUDGET;
+const BLOCK_SIZE: u32 = 16 << 10;
+
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn grew(by: usize) {
+    let now = IN_USE.fetch_add(by, Ordering::SeqCst) + by;
+    PEAK.fetch_max(now, Ordering::SeqCst);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            grew(layout.size());
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            IN_USE.fetch_sub(layout.size(), Ordering::SeqCst);
+            grew(new_size);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[test]
+fn test_import_larger_than_memory_budget() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.child("image.bin");
+    let mut file = File::create(path.path()).unwrap();
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut chunk = vec![0u8; 64 << 10];
+    for _ in 0..FILE_SIZE / chunk.len() {
+        rng.fill_bytes(&mut chunk);
+        file.write_all(&chunk).unwrap();
+    }
+    drop(file);
+
+    #[cfg(feature = "sqlite")]
+    let spec = format!("sqlite:{}", dir.child("storage.db").path().display());
+    #[cfg(not(feature = "sqlite"))]
+    let spec = format!("files:{}", dir.child("storage").path().display());
+    let provider = open_provider(&spec, u64::MAX).unwrap();
+    let mut storage = Storage::new(provider, BLOCK_SIZE);
+
+    let before = IN_USE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let root = storage.import_path(path.path()).unwrap();
+    let used = PEAK.load(Ordering::SeqCst) - before;
+    assert!(
+        used < MEMORY_BUDGET,
+        "importing {FILE_SIZE} bytes peaked at {used} bytes of heap"
+    );
+
+    let blocks = storage.get_all_dag_blocks(&root).unwrap();
+    let data: usize = blocks
+        .iter()
+        .filter(|b| b.links.is_empty())
+        .map(|b| b.data.len())
+        .sum();
+    assert_eq!(data, FILE_SIZE);
+}
@@ -1,6 +1,8 @@
 use clap::Subcommand;
+use ipfs_unixfs::codecs::Codec;
 use parity_scale_codec_derive::{Decode as ParityDecode, Encode as ParityEncode};
 use serde::Serialize;
+use std::fmt;
 
 #[derive(Clone, Debug, ParityEncode, ParityDecode, Serialize, Eq, PartialEq)]
 pub struct DagInfo {
@@ -8,6 +10,75 @@ pub struct DagInfo {
     pub filename: String,
 }
 
+#[derive(Clone, Debug, ParityEncode, ParityDecode, Serialize, Eq, PartialEq)]
+pub struct ShipSessionInfo {
+    pub cid: String,
+    pub target_addr: String,
+    pub window_num: u32,
+    // Number of blocks the next window will hold
+    pub window_size: u32,
+    pub retry_timeout_ms: u64,
+    // Smoothed round-trip time, 0 until a first sample is taken
+    pub srtt_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, ParityEncode, ParityDecode, Serialize, Eq, PartialEq)]
+pub struct StorageStats {
+    // Configured disk_usage, which GC works to keep usage under
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub blocks: u64,
+    // Named DAGs
+    pub dags: u64,
+    // CIDs known of (e.g. linked to) whose blocks aren't here
+    pub dangling_cids: u64,
+    // Held in DAGs given a priority, counting blocks they share once
+    pub pinned_bytes: u64,
+    // What GC has done since startup
+    pub gc_runs: u64,
+    pub gc_blocks_removed: u64,
+    pub gc_bytes_freed: u64,
+}
+
+#[derive(Clone, Debug, ParityEncode, ParityDecode, Serialize, Eq, PartialEq)]
+pub struct ScrubLogEntry {
+    pub cid: String,
+    // Unix time (seconds) the corruption was found
+    pub detected: u64,
+}
+
+impl fmt::Display for StorageStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |n: u64| match self.total_bytes {
+            0 => 0.0,
+            t => n as f64 * 100.0 / t as f64,
+        };
+        writeln!(
+            f,
+            "Used:    {} of {} bytes ({:.1}%)",
+            self.used_bytes,
+            self.total_bytes,
+            percent(self.used_bytes)
+        )?;
+        writeln!(
+            f,
+            "Blocks:  {} in {} DAGs, {} dangling CIDs",
+            self.blocks, self.dags, self.dangling_cids
+        )?;
+        writeln!(
+            f,
+            "Pinned:  {} bytes ({:.1}%)",
+            self.pinned_bytes,
+            percent(self.pinned_bytes)
+        )?;
+        write!(
+            f,
+            "GC:      {} runs removed {} blocks, {} bytes",
+            self.gc_runs, self.gc_blocks_removed, self.gc_bytes_freed
+        )
+    }
+}
+
 #[derive(Clone, Debug, ParityEncode, ParityDecode, Serialize, Subcommand, Eq, PartialEq)]
 pub enum ApplicationAPI {
     /// Asks IPFS instance to import a file path into the local IPFS store
@@ -137,6 +208,89 @@ pub enum ApplicationAPI {
     Acknowledged {
         req: String,
     },
+    // Variants are SCALE-encoded by position and their fields in order, so the ones above, which
+    // deployed nodes already understand, stay as they are. New messages, including extended forms
+    // of those, go below.
+    /// Aborts the transmission of a DAG, dropping its session and any queued pushes
+    CancelTransmitDag {
+        cid: String,
+    },
+    /// Suspends the transmission of a DAG until it is resumed with ResumeTransmitDag
+    PauseTransmitDag {
+        cid: String,
+    },
+    /// Sets the scheduling priority of a DAG, higher values are sent before lower ones
+    SetPriority {
+        cid: String,
+        priority: u8,
+    },
+    /// ImportFile, with options for the resulting DAG
+    ImportFileWithOptions {
+        path: String,
+        /// Scheduling priority of the resulting DAG, higher is more urgent (default 0)
+        #[arg(short, long)]
+        priority: Option<u8>,
+        /// Seconds until the resulting DAG expires, after which it is no longer sent and is garbage collected first (default never)
+        #[arg(short, long)]
+        ttl: Option<u64>,
+        /// Import the file as one IPLD document, dag-json or dag-cbor (which also takes JSON), rather than as a UnixFS file.
+        /// Sent as the multicodec.
+        #[arg(long, value_parser = parse_codec)]
+        codec: Option<u64>,
+        /// Multihash to address the blocks by, e.g. blake3 (default block_hash from config).
+        /// Sent as the multihash code.
+        #[arg(long, value_parser = parse_multihash)]
+        hash: Option<u64>,
+    },
+    /// TransmitDag, with options for the transmission
+    TransmitDagWithOptions {
+        cid: String,
+        target_addr: String,
+        retries: u8,
+        /// Overrides the scheduling priority stored with the DAG
+        #[arg(short, long)]
+        priority: Option<u8>,
+    },
+    /// Request the current window size and retry timeout of each DAG transfer in progress
+    RequestShipSessions,
+    /// Lists DAG transfers in progress along with their adapted parameters
+    #[command(skip)]
+    ShipSessions {
+        sessions: Vec<ShipSessionInfo>,
+    },
+    /// Asks a relay to hold on to the DAG being sent to it and pass it on to the destination
+    #[command(skip)]
+    CustodyTransfer {
+        cid: String,
+        destination: String,
+    },
+    /// Sent by a relay once it holds the whole DAG, so the sender no longer needs to keep it
+    #[command(skip)]
+    CustodyAccepted {
+        cid: String,
+    },
+    /// Makes a DAG expire ttl seconds from now. Also sent along with a DAG that has one, so the receiver drops it too.
+    SetTtl {
+        cid: String,
+        ttl: u64,
+    },
+    /// Request how full storage is and what garbage collection has done
+    GetStorageStats,
+    /// Storage usage figures, in response to GetStorageStats
+    #[command(skip)]
+    StorageStats {
+        stats: StorageStats,
+    },
+    /// Re-hash every stored block against its CID, a batch at a time, quarantining any which have been corrupted.
+    /// Answered with a ScrubReport once every block has been checked.
+    ScrubStorage,
+    /// Outcome of a ScrubStorage, along with corruptions found by the background scrub
+    #[command(skip)]
+    ScrubReport {
+        checked: u64,
+        corrupted: Vec<String>,
+        log: Vec<ScrubLogEntry>,
+    },
     // TODO: Implement later
     // Information about the next pass used for calculating
     // data transfer parameters
@@ -146,3 +300,22 @@ pub enum ApplicationAPI {
     //     receive_bytes: u32,
     // },
 }
+
+// Multihash code of an import's --hash, by name
+fn parse_multihash(name: &str) -> Result<u64, String> {
+    ipfs_unixfs::parse_hash(name)
+        .map(u64::from)
+        .map_err(|e| e.to_string())
+}
+
+// Multicodec of an import's --codec, by name
+fn parse_codec(name: &str) -> Result<u64, String> {
+    match name {
+        "unixfs" | "dag-pb" => Ok(Codec::DagPb.into()),
+        "dag-json" => Ok(Codec::DagJson.into()),
+        "dag-cbor" => Ok(Codec::DagCbor.into()),
+        _ => Err(format!(
+            "can't import as {name}, only as unixfs, dag-json or dag-cbor"
+        )),
+    }
+}
@@ -227,4 +227,16 @@ mod tests {
         }
         assert!(t.encoded_size() <= CHUNK_SIZE);
     }
+
+    #[test]
+    fn other_hash_gets_own_list() {
+        use cid::multihash::{Code, MultihashDigest};
+        let sha = Cid::new_v1(0x55, Code::Sha2_256.digest(b"a"));
+        let blake = Cid::new_v1(0x55, Code::Blake3_256.digest(b"a"));
+        let mut t = CompactList::try_from(&sha).unwrap();
+        assert!(!t.include(&blake, 500));
+        assert!(!t.contains(&blake));
+        let t = CompactList::try_from(&blake).unwrap();
+        assert_eq!(t.into_iter().collect::<Vec<_>>(), vec![blake]);
+    }
 }
@@ -7,8 +7,8 @@ pub(crate) mod message;
 pub(crate) mod protocol;
 mod sync;
 
-pub use api::{ApplicationAPI, DagInfo};
+pub use api::{ApplicationAPI, DagInfo, ScrubLogEntry, ShipSessionInfo, StorageStats};
 pub use message::Message;
 #[cfg(feature = "proto_ship")]
-pub use protocol::{DataProtocol, TransmissionBlock};
-pub use sync::{SyncMessage, PUSH_OVERHEAD};
+pub use protocol::{bitmap_has, to_bitmap, DataProtocol, TransmissionBlock};
+pub use sync::{PushMessage, SummaryMessage, SyncMessage, PUSH_OVERHEAD};
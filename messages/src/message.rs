@@ -135,6 +135,11 @@ impl Message {
         Self::Sync(SyncMessage::Block(block_bytes))
     }
 
+    #[cfg(feature = "proto_sync")]
+    pub fn summary(summary: crate::sync::SummaryMessage) -> Self {
+        Self::Sync(SyncMessage::Summary(summary))
+    }
+
     pub fn needs_envelope(&self) -> bool {
         !matches!(self, Self::Sync(_))
     }
@@ -181,9 +186,10 @@ impl Message {
             Self::ApplicationAPI(ApplicationAPI::TransmitBlock { target_addr, .. }) => {
                 Some(target_addr.clone())
             }
-            Self::ApplicationAPI(ApplicationAPI::TransmitDag { target_addr, .. }) => {
-                Some(target_addr.clone())
-            }
+            Self::ApplicationAPI(ApplicationAPI::TransmitDag { target_addr, .. })
+            | Self::ApplicationAPI(ApplicationAPI::TransmitDagWithOptions {
+                target_addr, ..
+            }) => Some(target_addr.clone()),
             _ => None,
         }
     }
@@ -219,4 +225,20 @@ mod tests {
         assert!(sz > 512, "{sz} should be > 512");
         assert_eq!(sz, 538);
     }
+
+    #[test]
+    fn existing_messages_keep_their_encoding() {
+        let s = |v: &str| v.to_string();
+        assert_eq!(Message::import_file("a").encode(), vec![1, 0, 4, b'a']);
+        assert_eq!(
+            Message::transmit_dag("c", "t", 3).encode(),
+            vec![1, 10, 4, b'c', 4, b't', 3]
+        );
+        assert_eq!(Message::ack("r").unwrap().encode(), vec![1, 27, 4, b'r']);
+        let request = DataProtocol::RequestMissingDagWindowBlocks {
+            cid: s("c"),
+            blocks: vec![],
+        };
+        assert_eq!(request.encode(), vec![6, 4, b'c', 0]);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::cid_list::CompactList;
 use cid::Cid;
 use parity_scale_codec_derive::{Decode as ParityDecode, Encode as ParityEncode};
 use serde::Serialize;
@@ -66,4 +67,102 @@ pub enum DataProtocol {
         cid: String,
         blocks: Vec<String>,
     },
+    // Drops the transmission session of a dag entirely
+    CancelTransmitDag {
+        cid: String,
+    },
+    // Suspends the transmission session of a dag, which may later be resumed
+    PauseTransmitDag {
+        cid: String,
+    },
+    // Updates the scheduling priority of a dag's transmission session
+    SetPriority {
+        cid: String,
+        priority: u8,
+    },
+    // Compact form of RequestMissingDagWindowBlocks, identifying the window's blocks by their
+    // positions in breadth-first order through the dag rather than listing their CIDs
+    RequestMissingDagWindowBitmap {
+        cid: String,
+        #[codec(compact)]
+        offset: u32,
+        #[codec(compact)]
+        count: u32,
+    },
+    // Fallback for when the receiver lacks blocks needed to work out the dag's order:
+    // the window's distinct CIDs, in order, as digest lists
+    RequestMissingDagWindowDigests {
+        cid: String,
+        #[codec(compact)]
+        offset: u32,
+        blocks: Vec<CompactList>,
+    },
+    // Reply to either of the above. Bit i of missing (LSB first) is set if the i-th distinct
+    // block of the window is missing. Only the first `known` could be placed in the dag.
+    MissingDagBlocksBitmap {
+        cid: String,
+        #[codec(compact)]
+        offset: u32,
+        #[codec(compact)]
+        known: u32,
+        missing: Vec<u8>,
+    },
+}
+
+// Packs flags into a bitmap, LSB first
+pub fn to_bitmap<I: IntoIterator<Item = bool>>(bits: I) -> Vec<u8> {
+    let mut result = Vec::new();
+    for (i, bit) in bits.into_iter().enumerate() {
+        if i % 8 == 0 {
+            result.push(0);
+        }
+        if bit {
+            result[i / 8] |= 1 << (i % 8);
+        }
+    }
+    result
+}
+
+pub fn bitmap_has(bitmap: &[u8], i: usize) -> bool {
+    bitmap
+        .get(i / 8)
+        .map(|b| b & (1 << (i % 8)) != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmap_roundtrip() {
+        let bits = [
+            true, false, false, true, false, false, false, false, true, true,
+        ];
+        let bitmap = to_bitmap(bits);
+        assert_eq!(bitmap, vec![0b0000_1001, 0b0000_0011]);
+        for (i, bit) in bits.iter().enumerate() {
+            assert_eq!(bitmap_has(&bitmap, i), *bit);
+        }
+        assert!(!bitmap_has(&bitmap, 100));
+    }
+
+    #[test]
+    fn bitmap_negotiation_fits_one_packet() {
+        use parity_scale_codec::Encode;
+        let cid = "bafybeidhvdywetejoxcbqbeq4bgwhvnsvq5u3jhbhw74wsq7eiwcwphwui".to_string();
+        let request = DataProtocol::RequestMissingDagWindowBitmap {
+            cid: cid.clone(),
+            offset: 100_000,
+            count: 256,
+        };
+        let reply = DataProtocol::MissingDagBlocksBitmap {
+            cid,
+            offset: 100_000,
+            known: 256,
+            missing: to_bitmap([true; 256]),
+        };
+        assert!(request.encoded_size() < 80);
+        assert!(reply.encoded_size() < 128);
+    }
 }
@@ -1,6 +1,7 @@
 use crate::cid_list;
 use cid::multihash;
 use cid::multihash::Hasher;
+use cid::Cid;
 use parity_scale_codec_derive::{Decode as ParityDecode, Encode as ParityEncode};
 use serde::Serialize;
 use std::fmt::{Debug, Formatter};
@@ -16,6 +17,7 @@ pub enum SyncMessage {
     Pull(cid_list::CompactList), //I do not have these CIDs, maybe you could send their blocks to me
     Ack(cid_list::CompactList),  //I *also* have these CIDs, stop pushing them
     Block(Vec<u8>),              //Here's the data for a block.
+    Summary(SummaryMessage), //Everything I have under this root, as a bloom filter. Pull what you lack.
 }
 
 impl SyncMessage {
@@ -25,6 +27,7 @@ impl SyncMessage {
             Self::Pull(_) => "Pull",
             Self::Ack(_) => "Ack",
             Self::Block(_) => "Block",
+            Self::Summary(_) => "Summary",
         }
     }
 }
@@ -36,6 +39,7 @@ impl Debug for SyncMessage {
             Self::Pull(x) => write!(f, "Pull({x:?})"),
             Self::Ack(x) => write!(f, "Ack({x:?})"),
             Self::Block(x) => write!(f, "Block({}B)", x.len()),
+            Self::Summary(x) => write!(f, "Summary({x:?})"),
         }
     }
 }
@@ -79,3 +83,124 @@ impl Debug for PushMessage {
         write!(f, "{:?})", &self.cids)
     }
 }
+
+//Bits per CID beyond which a larger filter isn't worth the bandwidth (~1% false positives)
+const SUMMARY_BITS_PER_CID: usize = 10;
+const SUMMARY_MAX_HASHES: usize = 16;
+
+#[derive(Clone, ParityEncode, ParityDecode, Serialize, Eq, PartialEq)]
+pub struct SummaryMessage {
+    pub root: cid_list::CompactList,
+    hashes: u8,
+    bits: Vec<u8>,
+}
+impl SummaryMessage {
+    //A false positive only costs a Pull the remote can't answer (regular pushes still cover the
+    //  gap), so unlike Push there's no corruption check here. `size` bounds the whole encoded Message.
+    pub fn new<'a, I: IntoIterator<Item = &'a Cid>>(
+        root: &Cid,
+        cids: I,
+        size: usize,
+    ) -> crate::err::Result<Self> {
+        let root = cid_list::CompactList::try_from(root)?;
+        let cids: Vec<&Cid> = cids.into_iter().collect();
+        let budget = size
+            .saturating_sub(root.built_size() + 5)
+            .min(16 * 1024 - 1);
+        let wanted = (cids.len() * SUMMARY_BITS_PER_CID + 7) / 8;
+        let len = budget.min(wanted).max(1);
+        let per_cid = len * 8 / cids.len().max(1);
+        let hashes = ((per_cid as f64) * std::f64::consts::LN_2).round() as usize;
+        let mut result = Self {
+            root,
+            hashes: hashes.clamp(1, SUMMARY_MAX_HASHES) as u8,
+            bits: vec![0u8; len],
+        };
+        for cid in cids {
+            for i in result.indices(cid) {
+                result.bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Ok(result)
+    }
+    pub fn root(&self) -> Option<Cid> {
+        self.root.into_iter().next()
+    }
+    //Whether the filter is one `new` could have built: without hashes it would match every CID
+    pub fn check(&self) -> bool {
+        !self.bits.is_empty() && (1..=SUMMARY_MAX_HASHES).contains(&(self.hashes as usize))
+    }
+    //May be wrong with a "yes", never with a "no"
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.check()
+            && self
+                .indices(cid)
+                .all(|i| self.bits[i / 8] & (1 << (i % 8)) != 0)
+    }
+    fn indices(&self, cid: &Cid) -> impl Iterator<Item = usize> {
+        let mut hasher = multihash::Blake2s128::default();
+        hasher.update(&cid.to_bytes());
+        let digest = hasher.finalize();
+        let a = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let b = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let m = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % m) as usize)
+    }
+}
+impl Debug for SummaryMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SummaryMsg({:?},{}B,k={})",
+            &self.root,
+            self.bits.len(),
+            self.hashes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+    use cid::multihash::{Code, MultihashDigest};
+    use parity_scale_codec::Encode;
+
+    fn cid(i: u32) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(&i.to_le_bytes()))
+    }
+
+    #[test]
+    fn summary_has_no_false_negatives_and_fits() {
+        let root = cid(0);
+        let cids: Vec<Cid> = (1..2000).map(cid).collect();
+        let sm = SummaryMessage::new(&root, &cids, 512).unwrap();
+        let m = Message::Sync(SyncMessage::Summary(sm.clone()));
+        assert!(m.encoded_size() <= 512, "{}", m.encoded_size());
+        assert_eq!(sm.root(), Some(root));
+        assert!(cids.iter().all(|c| sm.contains(c)));
+    }
+
+    #[test]
+    fn summary_with_bad_hash_count_matches_nothing() {
+        let cids: Vec<Cid> = (1..100).map(cid).collect();
+        let sm = SummaryMessage::new(&cid(0), &cids, 1024).unwrap();
+        assert!(sm.check());
+        for hashes in [0, SUMMARY_MAX_HASHES as u8 + 1] {
+            let bad = SummaryMessage {
+                hashes,
+                ..sm.clone()
+            };
+            assert!(!bad.check());
+            assert!(!bad.contains(&cids[0]));
+        }
+    }
+
+    #[test]
+    fn summary_mostly_rejects_strangers() {
+        let cids: Vec<Cid> = (1..100).map(cid).collect();
+        let sm = SummaryMessage::new(&cid(0), &cids, 1024).unwrap();
+        let false_pos = (1000..2000).map(cid).filter(|c| sm.contains(c)).count();
+        assert!(false_pos < 50, "{false_pos}");
+    }
+}
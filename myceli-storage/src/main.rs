@@ -0,0 +1,108 @@
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use local_storage::{
+    at_rest::Cipher,
+    compression::Compression,
+    convert::convert,
+    provider::{open_provider, open_provider_read_only},
+};
+use log::info;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Parser, Debug)]
+#[clap(version, long_about = None, propagate_version = true)]
+#[clap(about = "Work directly on a Myceli instance's local storage, while it's not running")]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy every block, name and dangling CID from one store into another, verifying block hashes
+    Convert {
+        #[arg(long, help = "Store to read, as kind:path, e.g. sqlite:storage.db")]
+        from: String,
+        #[arg(long, help = "Store to write, as kind:path, e.g. files:storage")]
+        to: String,
+        #[arg(
+            long,
+            default_value = "none",
+            help = "Compression of the blocks written: none, zstd or lz4"
+        )]
+        compression: String,
+        #[arg(long, help = "Key file of an encrypted store being read")]
+        from_key_file: Option<PathBuf>,
+        #[arg(long, help = "Key file to encrypt the store written with")]
+        to_key_file: Option<PathBuf>,
+    },
+}
+
+fn cipher(key_file: Option<PathBuf>) -> Result<Option<Cipher>> {
+    key_file.map(|f| Cipher::from_key_file(&f)).transpose()
+}
+
+// Where the data of the store a kind:path spec names is, if it exists, so that two specs spelled
+//  differently (e.g. sqlite:./x.db and sqlite:x.db) are still seen to be the same store
+fn location(spec: &str) -> Option<PathBuf> {
+    let (kind, path) = spec.split_once(':')?;
+    let mut path = PathBuf::from(path);
+    if kind == "sqlite" && path.is_dir() {
+        path = path.join("storage.db");
+    }
+    path.canonicalize().ok()
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    match Cli::parse().command {
+        Command::Convert {
+            from,
+            to,
+            compression,
+            from_key_file,
+            to_key_file,
+        } => {
+            let Some((_, from_path)) = from.split_once(':') else {
+                bail!("Expected --from as kind:path, not {from}");
+            };
+            if !Path::new(from_path).exists() {
+                bail!("Nothing to convert at {from_path}");
+            }
+            if from == to || location(&from) == location(&to) {
+                bail!("Source and destination are the same store");
+            }
+            if let Some((_, to_path)) = to.split_once(':') {
+                // A sqlite path without .db names a directory to hold storage.db, like for myceli
+                if !to_path.ends_with(".db") {
+                    std::fs::create_dir_all(to_path)?;
+                }
+            }
+            let source = open_provider_read_only(&from)?;
+            source.lock().unwrap().set_cipher(cipher(from_key_file)?)?;
+            let destination = open_provider(&to, u64::MAX)?;
+            {
+                let mut to_provider = destination.lock().unwrap();
+                to_provider.set_compression(Compression::from_str(&compression)?);
+                to_provider.set_cipher(cipher(to_key_file)?)?;
+            }
+            info!("Converting {from} into {to}");
+            let converted = convert(&*source.lock().unwrap(), &mut *destination.lock().unwrap())?;
+            println!(
+                "Copied {} blocks ({} named, {} unverified) and {} dangling CIDs",
+                converted.blocks, converted.names, converted.unverified, converted.dangling
+            );
+            if !converted.corrupt.is_empty() {
+                println!(
+                    "{} corrupt blocks left dangling: {:?}",
+                    converted.corrupt.len(),
+                    converted.corrupt
+                );
+            }
+            Ok(())
+        }
+    }
+}
@@ -1,12 +1,50 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use cid::multihash::Code;
+#[cfg(feature = "proto_ship")]
+use cid::Cid;
+use ipfs_unixfs::codecs::Codec;
 use local_storage::storage::Storage;
 #[cfg(feature = "proto_ship")]
-use messages::DataProtocol;
-use messages::{ApplicationAPI, DagInfo, Message};
+use messages::{cid_list::CompactList, to_bitmap, DataProtocol};
+use messages::{ApplicationAPI, DagInfo, Message, StorageStats};
+#[cfg(feature = "proto_ship")]
+use std::collections::HashSet;
 use std::path::PathBuf;
 
-pub fn import_file(path: &str, storage: &mut Storage) -> Result<Message> {
-    let root_cid = storage.import_path(&PathBuf::from(path.to_owned()))?;
+// How a file is imported and what becomes of the resulting DAG, as ImportFileWithOptions asks
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub priority: Option<u8>,
+    pub ttl: Option<u64>,
+    // Multicodec to import the file as a document in, rather than as UnixFS
+    pub codec: Option<u64>,
+    // Multihash to address the blocks by, rather than the storage's
+    pub hash: Option<u64>,
+}
+
+pub fn import_file(path: &str, options: &ImportOptions, storage: &mut Storage) -> Result<Message> {
+    let path_buf = PathBuf::from(path.to_owned());
+    let hash = match options.hash {
+        Some(code) => Code::try_from(code)?,
+        None => storage.hash(),
+    };
+    let codec = options.codec;
+    let root_cid = match codec.map(Codec::try_from) {
+        None | Some(Ok(Codec::DagPb)) => storage.import_path_with_hash(&path_buf, hash)?,
+        Some(Ok(document @ (Codec::DagJson | Codec::DagCbor))) => {
+            storage.import_document(&path_buf, document, hash)?
+        }
+        _ => bail!(
+            "Can't import as multicodec {:#x}, only as unixfs, dag-json or dag-cbor",
+            codec.unwrap_or_default()
+        ),
+    };
+    if let Some(priority) = options.priority {
+        storage.set_priority(&root_cid, priority)?;
+    }
+    if let Some(ttl) = options.ttl {
+        storage.set_ttl(&root_cid, ttl)?;
+    }
     Ok(Message::ApplicationAPI(ApplicationAPI::FileImported {
         path: path.to_string(),
         cid: root_cid,
@@ -64,6 +102,59 @@ pub fn get_missing_dag_blocks_window_protocol(
     }))
 }
 
+// Bitmaps index a window by its distinct CIDs, in order of first appearance
+#[cfg(feature = "proto_ship")]
+pub fn distinct(cids: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    cids.into_iter()
+        .filter(|c| seen.insert(c.clone()))
+        .collect()
+}
+
+#[cfg(feature = "proto_ship")]
+pub fn get_missing_dag_window_bitmap(
+    cid: &str,
+    offset: u32,
+    count: u32,
+    storage: &Storage,
+) -> Result<Message> {
+    let window = distinct(storage.get_dag_window_cids(cid, offset, count)?);
+    let mut missing = Vec::with_capacity(window.len());
+    for c in &window {
+        missing.push(!storage.has_cid(&Cid::try_from(c.as_str())?));
+    }
+    Ok(Message::DataProtocol(
+        DataProtocol::MissingDagBlocksBitmap {
+            cid: cid.to_string(),
+            offset,
+            known: window.len().try_into()?,
+            missing: to_bitmap(missing),
+        },
+    ))
+}
+
+#[cfg(feature = "proto_ship")]
+pub fn get_missing_dag_window_digests(
+    cid: &str,
+    offset: u32,
+    blocks: Vec<CompactList>,
+    storage: &Storage,
+) -> Result<Message> {
+    let missing: Vec<bool> = blocks
+        .iter()
+        .flat_map(|l| l.into_iter())
+        .map(|c| !storage.has_cid(&c))
+        .collect();
+    Ok(Message::DataProtocol(
+        DataProtocol::MissingDagBlocksBitmap {
+            cid: cid.to_string(),
+            offset,
+            known: missing.len().try_into()?,
+            missing: to_bitmap(missing),
+        },
+    ))
+}
+
 pub fn get_available_dags(storage: &Storage) -> Result<Message> {
     let local_dags: Vec<DagInfo> = storage
         .list_available_dags()?
@@ -97,15 +188,34 @@ pub fn get_named_dags(storage: &Storage) -> Result<Message> {
     }))
 }
 
-#[cfg(all(test, feature = "sqlite"))]
+pub fn get_storage_stats(storage: &Storage, disk_usage: u64) -> Result<Message> {
+    let stats = storage.get_stats()?;
+    let stats = StorageStats {
+        total_bytes: disk_usage,
+        used_bytes: stats.used_bytes,
+        blocks: stats.blocks,
+        dags: stats.dags,
+        dangling_cids: stats.dangling_cids,
+        pinned_bytes: stats.pinned_bytes,
+        gc_runs: stats.gc_runs,
+        gc_blocks_removed: stats.gc_blocks_removed,
+        gc_bytes_freed: stats.gc_bytes_freed,
+    };
+    Ok(Message::ApplicationAPI(ApplicationAPI::StorageStats {
+        stats,
+    }))
+}
+
+#[cfg(test)]
 pub mod tests {
     use super::*;
 
     use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+    use cid::Cid;
     use futures::TryStreamExt;
     use ipfs_unixfs::builder::{File, FileBuilder};
     use local_storage::block::StoredBlock;
-    use local_storage::sql_provider::SqliteStorageProvider;
+    use local_storage::memory_provider::MemoryStorageProvider;
     use rand::{thread_rng, RngCore};
     use std::sync::{Arc, Mutex};
 
@@ -123,9 +233,7 @@ pub mod tests {
         }
         pub fn with_block_size(block_sz: u32) -> Self {
             let db_dir = TempDir::new().unwrap();
-            let db_path = db_dir.child("storage.db");
-            let provider = SqliteStorageProvider::new(db_path.path().to_str().unwrap()).unwrap();
-            provider.setup().unwrap();
+            let provider = MemoryStorageProvider::new(u64::MAX);
             let storage = Storage::new(Arc::new(Mutex::new(provider)), block_sz);
             TestHarness {
                 block_size: block_sz,
@@ -194,7 +302,11 @@ pub mod tests {
 
         let test_file_path = harness.generate_file().unwrap();
 
-        let imported_file_cid = match import_file(&test_file_path, &mut harness.storage) {
+        let imported_file_cid = match import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        ) {
             Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
             other => panic!("ImportFile returned wrong response {other:?}"),
         };
@@ -210,13 +322,93 @@ pub mod tests {
         assert_eq!(result, "Dag is valid");
     }
 
+    #[test]
+    pub fn test_import_file_as_document() {
+        let mut harness = TestHarness::new();
+        let record = harness.db_dir.child("record.json");
+        record.write_binary(br#"{"temp": 21}"#).unwrap();
+        let path = record.path().to_str().unwrap();
+
+        let imported_cid = match import_file(
+            path,
+            &ImportOptions {
+                codec: Some(Codec::DagCbor.into()),
+                ..Default::default()
+            },
+            &mut harness.storage,
+        ) {
+            Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
+            other => panic!("ImportFile returned wrong response {other:?}"),
+        };
+        assert_eq!(
+            Cid::try_from(imported_cid.as_str()).unwrap().codec(),
+            u64::from(Codec::DagCbor)
+        );
+        match validate_dag(&imported_cid, &harness.storage) {
+            Ok(Message::ApplicationAPI(ApplicationAPI::ValidateDagResponse { result, .. })) => {
+                assert_eq!(result, "Dag is valid")
+            }
+            other => panic!("ValidateDag returned wrong response {other:?}"),
+        }
+        assert!(import_file(
+            path,
+            &ImportOptions {
+                codec: Some(Codec::Cbor.into()),
+                ..Default::default()
+            },
+            &mut harness.storage
+        )
+        .is_err());
+    }
+
+    #[test]
+    pub fn test_import_file_with_hash() {
+        let mut harness = TestHarness::new();
+        let test_file_path = harness.generate_file().unwrap();
+
+        let imported_cid = match import_file(
+            &test_file_path,
+            &ImportOptions {
+                hash: Some(cid::multihash::Code::Blake3_256.into()),
+                ..Default::default()
+            },
+            &mut harness.storage,
+        ) {
+            Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
+            other => panic!("ImportFile returned wrong response {other:?}"),
+        };
+        assert_eq!(
+            Cid::try_from(imported_cid.as_str()).unwrap().hash().code(),
+            u64::from(cid::multihash::Code::Blake3_256)
+        );
+        match validate_dag(&imported_cid, &harness.storage) {
+            Ok(Message::ApplicationAPI(ApplicationAPI::ValidateDagResponse { result, .. })) => {
+                assert_eq!(result, "Dag is valid")
+            }
+            other => panic!("ValidateDag returned wrong response {other:?}"),
+        }
+        assert!(import_file(
+            &test_file_path,
+            &ImportOptions {
+                hash: Some(0xd5),
+                ..Default::default()
+            },
+            &mut harness.storage
+        )
+        .is_err());
+    }
+
     #[test]
     pub fn test_import_file_validate_blocks() {
         let mut harness = TestHarness::new();
 
         let test_file_path = harness.generate_file().unwrap();
 
-        let imported_file_cid = match import_file(&test_file_path, &mut harness.storage) {
+        let imported_file_cid = match import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        ) {
             Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
             other => panic!("ImportFile returned wrong response {other:?}"),
         };
@@ -250,7 +442,12 @@ pub mod tests {
         assert!(available_blocks.is_empty());
 
         let test_file_path = harness.generate_file().unwrap();
-        import_file(&test_file_path, &mut harness.storage).unwrap();
+        import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        )
+        .unwrap();
 
         let available_blocks = match request_available_blocks(&harness.storage) {
             Ok(Message::ApplicationAPI(ApplicationAPI::AvailableBlocks { cids })) => cids,
@@ -266,7 +463,11 @@ pub mod tests {
 
         let test_file_path = harness.generate_file().unwrap();
 
-        let imported_file_cid = match import_file(&test_file_path, &mut harness.storage) {
+        let imported_file_cid = match import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        ) {
             Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
             other => panic!("ImportFile returned wrong response {other:?}"),
         };
@@ -306,7 +507,11 @@ pub mod tests {
 
         let test_file_path = harness.zero_file(200).unwrap();
 
-        let imported_file_cid = match import_file(&test_file_path, &mut harness.storage) {
+        let imported_file_cid = match import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        ) {
             Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
             other => panic!("ImportFile returned wrong response {other:?}"),
         };
@@ -328,7 +533,11 @@ pub mod tests {
 
         let test_file_path = harness.zero_file(3).unwrap();
 
-        let imported_file_cid = match import_file(&test_file_path, &mut harness.storage) {
+        let imported_file_cid = match import_file(
+            &test_file_path,
+            &ImportOptions::default(),
+            &mut harness.storage,
+        ) {
             Ok(Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. })) => cid,
             other => panic!("ImportFile returned wrong response {other:?}"),
         };
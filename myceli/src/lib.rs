@@ -1,5 +1,7 @@
 mod handlers;
 pub mod listener;
+mod relay;
+mod scrub;
 #[cfg(feature = "proto_ship")]
 pub mod shipper;
 #[cfg(feature = "proto_sync")]
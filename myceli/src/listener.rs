@@ -1,19 +1,28 @@
 use crate::handlers;
+use crate::relay::{Relay, ACCEPTED_ACK, FORWARD_RETRIES};
+use crate::scrub::Scrubber;
 #[cfg(feature = "proto_ship")]
 use crate::shipper::Shipper;
 #[cfg(feature = "proto_sync")]
-use crate::sync::Syncer;
-use anyhow::Result;
-use local_storage::{provider::default_storage_provider, storage::Storage};
+use crate::sync::SyncPeers;
+use anyhow::{bail, Result};
+use cid::multihash::Code;
+#[cfg(feature = "proto_sync")]
+use cid::Cid;
+use local_storage::{
+    at_rest::Cipher, compression::Compression, provider::default_storage_provider, storage::Storage,
+};
 use log::{debug, error, info, trace, warn};
-#[cfg(feature = "proto_ship")]
-use messages::DataProtocol;
 #[cfg(feature = "proto_sync")]
 use messages::SyncMessage;
 use messages::{ApplicationAPI, Message};
-use std::collections::BTreeSet;
+#[cfg(feature = "proto_ship")]
+use messages::{DataProtocol, ShipSessionInfo};
 #[cfg(feature = "proto_sync")]
 use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "proto_sync")]
+use std::time::Instant;
 #[cfg(feature = "proto_ship")]
 use std::{
     iter,
@@ -24,6 +33,7 @@ use std::{
     net::SocketAddr,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use transports::{Transport, TransportError};
 
@@ -36,9 +46,55 @@ pub struct Listener<T> {
     sync_target_addrs: BTreeSet<String>,
     _block_size: u32,
     #[cfg(feature = "proto_sync")]
-    sync_counts: [u64; 3],
-    #[cfg(feature = "proto_sync")]
-    sync: Syncer,
+    sync: SyncPeers,
+    #[cfg(feature = "proto_ship")]
+    ship_sessions: Arc<Mutex<Vec<ShipSessionInfo>>>,
+    #[cfg(feature = "proto_ship")]
+    bitmap_peers: Arc<Mutex<BTreeSet<String>>>,
+    relay: Relay,
+    disk_usage: u64,
+    // Expired DAGs already dropped from the protocols' queues, awaiting GC
+    expired: BTreeSet<String>,
+    scrubber: Scrubber,
+}
+
+// Settings a Listener is created with, mostly carried over from Config
+pub struct ListenerOptions {
+    pub block_size: u32,
+    pub radio_address: Option<String>,
+    pub high_disk_usage: u64,
+    pub mtu: u16,
+    pub routes: BTreeMap<String, String>,
+    // Least time between background scrub steps, 0 for none
+    pub scrub_interval_ms: u64,
+    // Blocks re-hashed by each background scrub step
+    pub scrub_batch: usize,
+    // Applied to blocks as they're stored
+    pub compression: Compression,
+    // Seals blocks and names at rest, if set
+    pub cipher: Option<Cipher>,
+    // Multihash imported blocks are addressed by
+    pub hash: Code,
+    // Chunks of up to this many bytes are inlined into their parents as identity CIDs
+    pub inline_limit: usize,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        ListenerOptions {
+            block_size: 3 * 1024,
+            radio_address: None,
+            high_disk_usage: 1024 * 1024 * 1024,
+            mtu: 512,
+            routes: BTreeMap::default(),
+            scrub_interval_ms: 60_000,
+            scrub_batch: 4,
+            compression: Compression::None,
+            cipher: None,
+            hash: ipfs_unixfs::DEFAULT_HASH,
+            inline_limit: 0,
+        }
+    }
 }
 
 impl<T: Transport + Send + 'static> Listener<T> {
@@ -46,16 +102,36 @@ impl<T: Transport + Send + 'static> Listener<T> {
         listen_address: &SocketAddr,
         storage_path: &str,
         transport: Arc<T>,
-        block_size: u32,
-        radio_address: Option<String>,
-        high_disk_usage: u64,
-        _mtu: u16,
+        options: ListenerOptions,
     ) -> Result<Listener<T>> {
+        let ListenerOptions {
+            block_size,
+            radio_address,
+            high_disk_usage,
+            mtu: _mtu,
+            routes,
+            scrub_interval_ms,
+            scrub_batch,
+            compression,
+            cipher,
+            hash,
+            inline_limit,
+        } = options;
         let provider = default_storage_provider(storage_path, high_disk_usage)?;
-        let storage = Storage::new(provider, block_size);
+        provider.lock().unwrap().set_compression(compression);
+        provider.lock().unwrap().set_cipher(cipher)?;
+        let mut storage = Storage::new(provider, block_size);
+        storage.set_hash(hash);
+        storage.set_inline_limit(inline_limit)?;
         info!("Listening on {listen_address}");
         #[cfg(feature = "proto_sync")]
-        let sync = create_syncer(&storage, _mtu)?;
+        let sync = SyncPeers::load(_mtu.into(), radio_address.clone(), &storage)?;
+        let relay = Relay::new(routes, &storage);
+        let scrubber = Scrubber::new(
+            &storage,
+            Duration::from_millis(scrub_interval_ms),
+            scrub_batch,
+        );
         Ok(Listener {
             storage,
             transport,
@@ -65,9 +141,15 @@ impl<T: Transport + Send + 'static> Listener<T> {
             ship_target_addrs: BTreeSet::default(),
             _block_size: block_size,
             #[cfg(feature = "proto_sync")]
-            sync_counts: [0; 3],
-            #[cfg(feature = "proto_sync")]
             sync,
+            #[cfg(feature = "proto_ship")]
+            ship_sessions: Arc::default(),
+            #[cfg(feature = "proto_ship")]
+            bitmap_peers: Arc::default(),
+            relay,
+            disk_usage: high_disk_usage,
+            expired: BTreeSet::default(),
+            scrubber,
         })
     }
 
@@ -88,6 +170,8 @@ impl<T: Transport + Send + 'static> Listener<T> {
             let shipper_storage_provider = self.storage.get_provider();
             let shipper_sender_clone = shipper_sender.clone();
             let block_size = self._block_size;
+            let ship_sessions = Arc::clone(&self.ship_sessions);
+            let bitmap_peers = Arc::clone(&self.bitmap_peers);
             spawn(move || {
                 let mut shipper = Shipper::new(
                     shipper_storage_provider,
@@ -100,6 +184,8 @@ impl<T: Transport + Send + 'static> Listener<T> {
                     block_size,
                     shipper_radio,
                     _shipper_packet_delay_ms,
+                    ship_sessions,
+                    bitmap_peers,
                 )
                 .expect("Shipper creation failed");
                 shipper.receive_msg_loop();
@@ -147,6 +233,16 @@ impl<T: Transport + Send + 'static> Listener<T> {
                     }
                 }
                 Err(TransportError::TimedOut) => {
+                    if let Err(e) = self.expiry_tasks() {
+                        error!("Error dropping expired DAGs: {e:?}");
+                    }
+                    #[cfg(feature = "proto_ship")]
+                    let relayed = self.relay_tasks(&shipper_sender);
+                    #[cfg(not(feature = "proto_ship"))]
+                    let relayed = self.relay_tasks();
+                    if let Err(e) = relayed {
+                        error!("Error relaying DAGs: {e:?}");
+                    }
                     if let Err(e) = self.bg_tasks() {
                         error!("Error with background task: {e:?}");
                     }
@@ -155,6 +251,7 @@ impl<T: Transport + Send + 'static> Listener<T> {
                     error!("Receive message failed: {e}");
                 }
             }
+            self.save_sync_state();
         }
     }
 
@@ -179,11 +276,20 @@ impl<T: Transport + Send + 'static> Listener<T> {
             }
         };
         let resp: Option<Message> = match message {
-            Message::ApplicationAPI(ApplicationAPI::TransmitDag {
+            Message::ApplicationAPI(ApplicationAPI::TransmitDagWithOptions {
                 cid,
                 target_addr,
                 retries: _retries,
+                priority,
             }) => {
+                if self.storage.is_expired(&cid) {
+                    bail!("DAG {cid} has expired");
+                }
+                if let Some(priority) = priority {
+                    self.set_priority(&cid, priority)?;
+                }
+                let target_addr = self.route(&cid, target_addr)?;
+                self.announce_ttl(&cid, &target_addr)?;
                 #[cfg(feature = "proto_ship")]
                 if self.sync_target_addrs.contains(&target_addr)
                     || !self.ship_target_addrs.contains(&target_addr)
@@ -209,6 +315,38 @@ impl<T: Transport + Send + 'static> Listener<T> {
                 }
                 Message::ack("TransmitDag")
             }
+            Message::ApplicationAPI(ApplicationAPI::TransmitDag {
+                cid,
+                target_addr,
+                retries,
+            }) => {
+                let msg = ApplicationAPI::TransmitDagWithOptions {
+                    cid,
+                    target_addr,
+                    retries,
+                    priority: None,
+                };
+                #[cfg(feature = "proto_ship")]
+                return self.handle_message(Message::ApplicationAPI(msg), sender, shipper_sender);
+                #[cfg(not(feature = "proto_ship"))]
+                return self.handle_message(Message::ApplicationAPI(msg), sender);
+            }
+            Message::ApplicationAPI(ApplicationAPI::CustodyTransfer { cid, destination }) => {
+                self.relay.hold(&cid, &destination, sender, &self.storage)?;
+                None
+            }
+            Message::ApplicationAPI(ApplicationAPI::CustodyAccepted { cid }) => {
+                if self.relay.accepted(&cid, sender, &self.storage)? {
+                    info!("{sender} accepted custody of {cid}, no longer sending or keeping it");
+                    self.forget_dag(&cid);
+                    #[cfg(feature = "proto_ship")]
+                    ship(self, DataProtocol::CancelTransmitDag { cid: cid.clone() });
+                } else {
+                    debug!("{sender} accepted custody of {cid}, which isn't waiting on it");
+                }
+                //Even if we'd already heard, so that it stops telling us
+                Message::ack(&format!("{ACCEPTED_ACK}{cid}"))
+            }
             Message::ApplicationAPI(ApplicationAPI::TransmitBlock { cid, target_addr }) => {
                 self.transmit_dag(&cid, &target_addr, false)?;
                 #[cfg(feature = "proto_ship")]
@@ -219,7 +357,32 @@ impl<T: Transport + Send + 'static> Listener<T> {
                 Message::ack("TransmitBlock")
             }
             Message::ApplicationAPI(ApplicationAPI::ImportFile { path }) => {
-                let result = handlers::import_file(&path, &mut self.storage)?;
+                let msg = ApplicationAPI::ImportFileWithOptions {
+                    path,
+                    priority: None,
+                    ttl: None,
+                    codec: None,
+                    hash: None,
+                };
+                #[cfg(feature = "proto_ship")]
+                return self.handle_message(Message::ApplicationAPI(msg), sender, shipper_sender);
+                #[cfg(not(feature = "proto_ship"))]
+                return self.handle_message(Message::ApplicationAPI(msg), sender);
+            }
+            Message::ApplicationAPI(ApplicationAPI::ImportFileWithOptions {
+                path,
+                priority,
+                ttl,
+                codec,
+                hash,
+            }) => {
+                let options = handlers::ImportOptions {
+                    priority,
+                    ttl,
+                    codec,
+                    hash,
+                };
+                let result = handlers::import_file(&path, &options, &mut self.storage)?;
                 match &result {
                     Message::ApplicationAPI(ApplicationAPI::FileImported { path, cid }) => {
                         if let Err(e) = self.upon_import(cid) {
@@ -297,12 +460,27 @@ impl<T: Transport + Send + 'static> Listener<T> {
                     {
                         info!("Remote {_remote} reported that it supports sync protocol, so adding it to addresses to target with that.");
                     }
+                    #[cfg(feature = "proto_sync")]
+                    if features
+                        .iter()
+                        .any(|f| f == crate::version_info::SYNC_SUMMARY)
+                    {
+                        self.sync.understands_summaries(&_remote);
+                    }
                     #[cfg(feature = "proto_ship")]
                     if features.iter().any(|f| f == "PROTO_SHIP")
                         && self.ship_target_addrs.insert(_remote.clone())
                     {
                         info!("Remote {_remote} reported that it supports ship protocol, so adding it to addresses to target with that.");
                     }
+                    #[cfg(feature = "proto_ship")]
+                    if features
+                        .iter()
+                        .any(|f| f == crate::version_info::SHIP_BITMAP)
+                        && self.bitmap_peers.lock().unwrap().insert(_remote.clone())
+                    {
+                        info!("Remote {_remote} reported that it understands ship window bitmaps, so asking it about windows with those.");
+                    }
                 }
                 None
             }
@@ -323,7 +501,7 @@ impl<T: Transport + Send + 'static> Listener<T> {
                 }))
             }
             Message::ApplicationAPI(ApplicationAPI::ResumeTransmitDag { cid }) => {
-                self.transmit_dag(&cid, sender, false)?;
+                self.resume_dag(&cid, sender)?;
                 #[cfg(feature = "proto_ship")]
                 ship(self, DataProtocol::ResumeTransmitDag { cid });
                 Message::ack("ResumeTransmitDag")
@@ -333,6 +511,46 @@ impl<T: Transport + Send + 'static> Listener<T> {
                 ship(self, DataProtocol::ResumeTransmitAllDags);
                 Message::ack("ResumeTransmitAllDags")
             }
+            Message::ApplicationAPI(ApplicationAPI::CancelTransmitDag { cid }) => {
+                self.forget_dag(&cid);
+                #[cfg(feature = "proto_ship")]
+                ship(self, DataProtocol::CancelTransmitDag { cid });
+                Message::ack("CancelTransmitDag")
+            }
+            Message::ApplicationAPI(ApplicationAPI::PauseTransmitDag { cid }) => {
+                let paused = self.pause_dag(&cid);
+                #[cfg(feature = "proto_ship")]
+                let paused = paused
+                    || self
+                        .ship_sessions
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .any(|s| s.cid == cid);
+                if !paused {
+                    bail!("No transmission of DAG {cid} to pause");
+                }
+                #[cfg(feature = "proto_ship")]
+                ship(self, DataProtocol::PauseTransmitDag { cid });
+                Message::ack("PauseTransmitDag")
+            }
+            Message::ApplicationAPI(ApplicationAPI::SetPriority { cid, priority }) => {
+                self.set_priority(&cid, priority)?;
+                #[cfg(feature = "proto_ship")]
+                ship(self, DataProtocol::SetPriority { cid, priority });
+                Message::ack("SetPriority")
+            }
+            Message::ApplicationAPI(ApplicationAPI::SetTtl { cid, ttl }) => {
+                self.storage.set_ttl(&cid, ttl)?;
+                Message::ack("SetTtl")
+            }
+            Message::ApplicationAPI(ApplicationAPI::Acknowledged { req }) => {
+                debug!("{sender} acknowledged {req}");
+                if let Some(cid) = req.strip_prefix(ACCEPTED_ACK) {
+                    self.relay.acknowledged(cid, sender, &self.storage)?;
+                }
+                None
+            }
             Message::ApplicationAPI(ApplicationAPI::ValidateDagResponse { cid, result }) => {
                 info!("Received ValidateDagResponse from {sender} for {cid}: {result}");
                 None
@@ -359,11 +577,31 @@ impl<T: Transport + Send + 'static> Listener<T> {
             Message::ApplicationAPI(ApplicationAPI::ListFiles) => {
                 Some(handlers::get_named_dags(&self.storage)?)
             }
+            Message::ApplicationAPI(ApplicationAPI::GetStorageStats) => {
+                Some(handlers::get_storage_stats(&self.storage, self.disk_usage)?)
+            }
+            Message::ApplicationAPI(ApplicationAPI::ScrubStorage) => {
+                //Paced by bg_tasks, which sends the report once it's through
+                info!("Scrubbing all of storage for {sender}");
+                self.scrubber.request(sender);
+                None
+            }
+            Message::ApplicationAPI(ApplicationAPI::RequestShipSessions) => {
+                #[cfg(feature = "proto_ship")]
+                let sessions = self.ship_sessions.lock().unwrap().clone();
+                #[cfg(not(feature = "proto_ship"))]
+                let sessions = vec![];
+                Some(Message::ApplicationAPI(ApplicationAPI::ShipSessions {
+                    sessions,
+                }))
+            }
             Message::Sync(sm) => {
                 #[cfg(feature = "proto_sync")]
                 {
-                    self.sync_counts[1] += 1;
-                    self.sync.handle(sm, &mut self.storage)?
+                    let peer = self.sync.get(sender, &self.storage)?;
+                    peer.counts[1] += 1;
+                    peer.heard = Instant::now();
+                    peer.sync.handle(sm, &mut self.storage)?
                 }
                 #[cfg(not(feature = "proto_sync"))]
                 {
@@ -392,14 +630,181 @@ impl<T: Transport + Send + 'static> Listener<T> {
         Ok(())
     }
 
+    // Where to actually send a DAG bound for destination, handing it to a relay if one is configured
+    fn route(&mut self, cid: &str, destination: String) -> Result<String> {
+        match self.relay.next_hop(&destination) {
+            Some(hop) => {
+                info!("Sending {cid} to {destination} by way of {hop}");
+                self.transmit_response(
+                    Message::ApplicationAPI(ApplicationAPI::CustodyTransfer {
+                        cid: cid.to_owned(),
+                        destination: destination.clone(),
+                    }),
+                    &hop,
+                )?;
+                self.relay.sent(cid, &hop, &destination, &self.storage)?;
+                Ok(hop)
+            }
+            None => Ok(destination),
+        }
+    }
+
+    // Let upstream know about relayed DAGs which have fully arrived, and pass them on while connected
+    fn relay_tasks(
+        &mut self,
+        #[cfg(feature = "proto_ship")] shipper_sender: &Sender<(DataProtocol, String)>,
+    ) -> Result<()> {
+        for (cid, upstream) in self.relay.arrived(&self.storage)? {
+            info!("Accepting custody of {cid} from {upstream}");
+            self.transmit_response(
+                Message::ApplicationAPI(ApplicationAPI::CustodyAccepted { cid }),
+                &upstream,
+            )?;
+        }
+        for (msg, addr) in self.relay.unconfirmed() {
+            debug!("Sending {msg:?} to {addr} again, as it hasn't been confirmed");
+            self.transmit_response(Message::ApplicationAPI(msg), &addr)?;
+        }
+        if !*self.connected.lock().unwrap() {
+            return Ok(());
+        }
+        for (cid, destination) in self.relay.forwardable() {
+            let msg = Message::ApplicationAPI(ApplicationAPI::TransmitDag {
+                cid: cid.clone(),
+                target_addr: destination.clone(),
+                retries: FORWARD_RETRIES,
+            });
+            #[cfg(feature = "proto_ship")]
+            self.handle_message(msg, &destination, shipper_sender.clone())?;
+            #[cfg(not(feature = "proto_ship"))]
+            self.handle_message(msg, &destination)?;
+            self.relay.forwarded(&cid, &self.storage)?;
+        }
+        Ok(())
+    }
+
     fn upon_import(&mut self, _root_cid_str: &str) -> Result<()> {
         #[cfg(feature = "proto_sync")]
         {
+            for target in self.sync_target_addrs.clone() {
+                self.announce_ttl(_root_cid_str, &target)?;
+            }
             let root = self.storage.get_block_by_cid(_root_cid_str)?;
-            self.sync.push_dag(&root, &self.storage, false)?;
+            let storage = &self.storage;
+            self.sync
+                .for_each(|s| s.push_dag(&root, storage, false).map(|_| ()))?;
         }
         Ok(())
     }
+    //Pick back up with the remotes it was paused for, otherwise (re)start sending it to target
+    fn resume_dag(&mut self, _root_cid_str: &str, target: &str) -> Result<()> {
+        #[cfg(feature = "proto_sync")]
+        {
+            let mut resumed = false;
+            self.sync.for_each(|s| {
+                resumed |= s.resume_dag(_root_cid_str)?;
+                Ok(())
+            })?;
+            if resumed {
+                return Ok(());
+            }
+        }
+        self.transmit_dag(_root_cid_str, target, false)
+    }
+    fn set_priority(&mut self, root_cid_str: &str, priority: u8) -> Result<()> {
+        self.storage.set_priority(root_cid_str, priority)?;
+        #[cfg(feature = "proto_sync")]
+        {
+            let storage = &self.storage;
+            self.sync
+                .for_each(|s| s.prioritize(root_cid_str, priority, storage))?;
+        }
+        Ok(())
+    }
+    // Let the receiving end know how long a DAG it's being sent is good for
+    fn announce_ttl(&self, cid: &str, target: &str) -> Result<()> {
+        if let Some(ttl) = self.storage.get_ttl(cid) {
+            self.transmit_response(
+                Message::ApplicationAPI(ApplicationAPI::SetTtl {
+                    cid: cid.to_owned(),
+                    ttl,
+                }),
+                target,
+            )?;
+        }
+        Ok(())
+    }
+    // Stop sending (or fetching) DAGs which have expired, ahead of GC removing them.
+    // The shipper notices expiry of its own sessions.
+    fn expiry_tasks(&mut self) -> Result<()> {
+        let expired = self.storage.get_expired_dags()?;
+        self.expired.retain(|c| expired.contains(c));
+        for cid in expired {
+            if !self.expired.insert(cid.clone()) {
+                continue;
+            }
+            info!("DAG {cid} has expired, dropping it");
+            #[cfg(feature = "proto_sync")]
+            {
+                let storage = &self.storage;
+                self.sync.for_each(|s| s.expire_dag(&cid, storage))?;
+            }
+            self.relay.expired(&cid, &self.storage)?;
+        }
+        Ok(())
+    }
+    // Have sync peers send back blocks which had to be quarantined
+    fn refetch(&mut self, _cids: &[String]) {
+        #[cfg(not(feature = "proto_sync"))]
+        if !_cids.is_empty() {
+            warn!(
+                "Quarantined {} corrupt blocks, which only the sync protocol re-fetches: {_cids:?}",
+                _cids.len()
+            );
+        }
+        #[cfg(feature = "proto_sync")]
+        for cid in _cids {
+            let pulled = Cid::try_from(cid.as_str())
+                .map_err(anyhow::Error::from)
+                .and_then(|c| self.sync.for_each(|s| s.will_pull(&c)));
+            if let Err(e) = pulled {
+                warn!("Unable to re-fetch {cid}: {e:?}");
+            }
+        }
+    }
+    fn save_sync_state(&mut self) {
+        #[cfg(feature = "proto_sync")]
+        if let Err(e) = self.sync.save(&self.storage) {
+            warn!("Unable to persist sync state: {e:?}");
+        }
+    }
+    fn forget_dag(&mut self, _root_cid_str: &str) {
+        #[cfg(feature = "proto_sync")]
+        {
+            let storage = &self.storage;
+            if let Err(e) = self.sync.for_each(|s| s.forget_dag(_root_cid_str, storage)) {
+                warn!("Unable to drop pending pushes for DAG {_root_cid_str}: {e:?}");
+            }
+        }
+    }
+    //Returns whether the sync protocol had anything of the DAG left to push
+    fn pause_dag(&mut self, _root_cid_str: &str) -> bool {
+        #[cfg(feature = "proto_sync")]
+        {
+            let mut paused = false;
+            let storage = &self.storage;
+            let result = self.sync.for_each(|s| {
+                paused |= s.pause_dag(_root_cid_str, storage)?;
+                Ok(())
+            });
+            if let Err(e) = result {
+                warn!("Unable to pause pending pushes for DAG {_root_cid_str}: {e:?}");
+            }
+            paused
+        }
+        #[cfg(not(feature = "proto_sync"))]
+        false
+    }
     fn transmit_dag(
         &mut self,
         _root_cid_str: &str,
@@ -409,13 +814,16 @@ impl<T: Transport + Send + 'static> Listener<T> {
         #[cfg(feature = "proto_sync")]
         {
             let root = self.storage.get_block_by_cid(_root_cid_str)?;
-            if let Some(immediate_msg) = self.sync.push_dag(&root, &self.storage, false)? {
+            //Queued messages only go out once the target is known to speak sync
+            let peer = self.sync.get(_target, &self.storage)?;
+            if let Some(immediate_msg) = peer.sync.push_dag(&root, &self.storage, false)? {
                 self.transmit_response(immediate_msg, _target)?;
             }
             if _with_blocks {
                 let mut track = HashSet::new();
+                let peer = self.sync.get(_target, &self.storage)?;
                 let immediate_msg =
-                    self.sync
+                    peer.sync
                         .push_dag_blocks(_root_cid_str, &self.storage, &mut track)?;
                 if let Some(msg) = immediate_msg {
                     self.transmit_response(msg, _target)?;
@@ -428,6 +836,17 @@ impl<T: Transport + Send + 'static> Listener<T> {
     }
 
     fn bg_tasks(&mut self) -> Result<()> {
+        match self.scrubber.step(&self.storage) {
+            Ok(step) => {
+                self.refetch(&step.corrupted);
+                if let Some((report, requesters)) = step.report {
+                    for addr in requesters {
+                        self.transmit_response(Message::ApplicationAPI(report.clone()), &addr)?;
+                    }
+                }
+            }
+            Err(e) => error!("Error scrubbing storage: {e:?}"),
+        }
         if let Some(radio) = &self.radio_address {
             if self.sync_target_addrs.contains(radio) {
                 trace!("Configured radio {radio} is a sync target");
@@ -448,55 +867,51 @@ impl<T: Transport + Send + 'static> Listener<T> {
         }
         trace!("sync_target_addrs={:?}", self.sync_target_addrs);
         #[cfg(feature = "proto_sync")]
-        if !self.sync_target_addrs.is_empty() {
-            trace!("Addrs to sync with: {:?}", &self.sync_target_addrs);
-            if let Some(msg) = self.sync.pop_pending_msg(&self.storage) {
-                if matches!(&msg, Message::Sync(SyncMessage::Push(_))) {
-                    self.sync_counts[0] += 1;
-                }
-                info!(
-                    "Sending {msg:?} to {:?}, balance is now {:?}",
-                    &self.sync_target_addrs, self.sync_counts
-                );
-                //Sending a delayed Sync message, so bump that count
-                for addr in &self.sync_target_addrs {
-                    self.transport.send(msg.clone(), addr)?;
+        for addr in self.sync.expire() {
+            self.sync_target_addrs.remove(&addr);
+        }
+        #[cfg(feature = "proto_sync")]
+        {
+            let mut sent = false;
+            for addr in &self.sync.active(&self.sync_target_addrs) {
+                let peer = self.sync.get(addr, &self.storage)?;
+                if let Some(msg) = peer.sync.pop_pending_msg(&self.storage) {
+                    //Sending a delayed Sync message, so bump that count
+                    if matches!(&msg, Message::Sync(SyncMessage::Push(_))) {
+                        peer.counts[0] += 1;
+                    }
+                    info!(
+                        "Sending {msg:?} to {addr}, balance is now {:?}",
+                        peer.counts
+                    );
+                    self.transport.send(msg, addr)?;
+                    sent = true;
                 }
+            }
+            if sent {
                 return Ok(());
-            } else {
-                trace!("No already-pending Sync messages ready to send.");
             }
+            trace!("No already-pending Sync messages ready to send.");
         }
         if self.storage.incremental_gc() {
             debug!("GC run.");
             return Ok(());
         }
         #[cfg(feature = "proto_sync")]
-        if self.sync_counts[0] > self.sync_counts[1] + self.sync_counts[2] {
-            debug!(
-                "Give the remote side a chance to talk. {:?}",
-                &self.sync_counts
-            );
-            self.sync_counts[2] += 1;
-        } else {
-            trace!("Will try to build a new Sync message");
-            self.sync_counts[2] = 0;
+        for addr in &self.sync.active(&self.sync_target_addrs) {
+            let peer = self.sync.get(addr, &self.storage)?;
+            if peer.counts[0] > peer.counts[1] + peer.counts[2] {
+                debug!("Give {addr} a chance to talk. {:?}", &peer.counts);
+                peer.counts[2] += 1;
+            } else {
+                trace!("Will try to build a new Sync message for {addr}");
+                peer.counts[2] = 0;
 
-            if let Err(e) = self.sync.build_msg(&mut self.storage) {
-                error!("Error while building a new Sync message to send: {e:?}");
+                if let Err(e) = peer.sync.build_msg(&mut self.storage) {
+                    error!("Error while building a new Sync message to send to {addr}: {e:?}");
+                }
             }
         }
         Ok(())
     }
 }
-
-#[cfg(feature = "proto_sync")]
-fn create_syncer(storage: &Storage, mtu: u16) -> Result<Syncer> {
-    let missing_blocks = storage.get_provider().lock().unwrap().get_dangling_cids()?;
-    let present_blocks = storage.list_available_dags()?;
-    let present_blocks = present_blocks.iter().flat_map(|(c, n)| {
-        let c = cid::Cid::try_from(c.as_str())?;
-        Ok::<_, cid::Error>((c, n.clone()))
-    });
-    Syncer::new(mtu.into(), present_blocks, missing_blocks)
-}
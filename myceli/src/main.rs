@@ -1,12 +1,23 @@
 use anyhow::Result;
 use config::Config;
+use local_storage::{at_rest::Cipher, compression::Compression, provider::IN_MEMORY};
 use log::{info, warn};
 use messages::Message;
-use myceli::listener::Listener;
-use std::{net::ToSocketAddrs, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
-use transports::UdpTransport;
+use myceli::listener::{Listener, ListenerOptions};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use transports::{BundleTransport, SpacePacketConfig, Transport, UdpTransport};
 
-#[cfg(all(not(feature = "sqlite"), not(feature = "files")))]
+#[cfg(all(
+    not(feature = "sqlite"),
+    not(feature = "files"),
+    not(feature = "memory")
+))]
 compile_error! {"Myceli built without a local storage implementation will not function. Select a feature, recommended: either big or small"}
 
 #[cfg(all(not(feature = "proto_ship"), not(feature = "proto_sync")))]
@@ -39,27 +50,82 @@ fn main() -> Result<()> {
         .next()
         .expect("Unable to resolve socket addr");
 
-    std::fs::create_dir_all(&cfg.storage_path).expect("Failed to create storage dir");
+    if cfg.storage_path != IN_MEMORY {
+        std::fs::create_dir_all(&cfg.storage_path).expect("Failed to create storage dir");
+    }
+
+    let timeout = Duration::from_millis(cfg.chatter_ms.clamp(10, 60 * 60 * 1000).into());
+    if let Some(agent) = &cfg.bundle_agent {
+        let mut bundle_transport = BundleTransport::new(
+            &cfg.listen_address,
+            cfg.mtu,
+            cfg.chunk_transmit_throttle,
+            agent,
+            cfg.bundle_eid.as_deref().unwrap_or_default(),
+            cfg.bundle_lifetime_ms,
+        )
+        .expect("Failed to create bundle transport");
+        bundle_transport
+            .set_read_timeout(Some(timeout))
+            .expect("Failed to set timeout");
+        run(cfg, resolved_listen_addr, bundle_transport)
+    } else {
+        let mut udp_transport =
+            UdpTransport::new(&cfg.listen_address, cfg.mtu, cfg.chunk_transmit_throttle)
+                .expect("Failed to create udp transport");
+        if let Some(apid) = cfg.space_packet_apid {
+            udp_transport
+                .set_space_packets(SpacePacketConfig {
+                    apid,
+                    telecommand: cfg.space_packet_telecommand,
+                    segmented: cfg.space_packet_segmented,
+                })
+                .expect("Failed to set up space packet framing");
+        }
+        udp_transport
+            .set_read_timeout(Some(timeout))
+            .expect("Failed to set timeout");
+        run(cfg, resolved_listen_addr, udp_transport)
+    }
+}
 
+fn run<T: Transport + Send + 'static>(
+    cfg: Config,
+    resolved_listen_addr: SocketAddr,
+    transport: T,
+) -> Result<()> {
     let db_path = cfg.storage_path.clone();
     let disk_bytes = cfg.disk_usage * 1024;
-    let timeout = Duration::from_millis(cfg.chatter_ms.clamp(10, 60 * 60 * 1000).into());
-    let mut udp_transport =
-        UdpTransport::new(&cfg.listen_address, cfg.mtu, cfg.chunk_transmit_throttle)
-            .expect("Failed to create udp transport");
-    udp_transport
-        .set_read_timeout(Some(timeout))
-        .expect("Failed to set timeout");
+    let compression =
+        Compression::from_str(&cfg.block_compression).expect("Unusable block_compression");
+    let hash = ipfs_unixfs::parse_hash(&cfg.block_hash).expect("Unusable block_hash");
+    let cipher = match (&cfg.storage_key, &cfg.storage_key_file) {
+        (Some(key), _) => Some(Cipher::from_hex(key)),
+        (None, Some(file)) => Some(Cipher::from_key_file(Path::new(file))),
+        (None, None) => None,
+    }
+    .transpose()
+    .expect("Unusable storage key");
     println!("pid={}", std::process::id());
     let mut listener = Listener::new(
         &resolved_listen_addr,
         &db_path,
-        Arc::new(udp_transport),
-        cfg.block_size
-            .expect("Block size default should've been calculated."),
-        cfg.radio_address,
-        disk_bytes,
-        cfg.mtu,
+        Arc::new(transport),
+        ListenerOptions {
+            block_size: cfg
+                .block_size
+                .expect("Block size default should've been calculated."),
+            radio_address: cfg.radio_address,
+            high_disk_usage: disk_bytes,
+            mtu: cfg.mtu,
+            routes: cfg.routes,
+            scrub_interval_ms: cfg.scrub_interval_ms,
+            scrub_batch: cfg.scrub_batch.into(),
+            compression,
+            cipher,
+            hash,
+            inline_limit: cfg.inline_limit.into(),
+        },
     )
     .expect("Listener creation failed");
     listener
@@ -0,0 +1,308 @@
+use anyhow::Result;
+use local_storage::storage::Storage;
+use log::{debug, info, warn};
+use messages::ApplicationAPI;
+use parity_scale_codec::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Key under which custody of relayed DAGs is persisted in local storage
+const STATE_KEY: &str = "relay";
+// Retries given to the transfer of a DAG being passed on
+pub(crate) const FORWARD_RETRIES: u8 = 5;
+// How long to wait on the other end to confirm a custody message before sending it again
+const RESEND_INTERVAL: Duration = Duration::from_secs(60);
+// What a relay acknowledges CustodyAccepted with, followed by the CID
+pub(crate) const ACCEPTED_ACK: &str = "CustodyAccepted ";
+
+// A DAG handed to us by someone upstream, to be passed on towards its destination
+#[derive(Debug, Encode, Decode)]
+struct Held {
+    destination: String,
+    upstream: String,
+    // Set once the whole DAG is here and upstream has been told it can stop sending it
+    accepted: bool,
+}
+
+// A DAG handed to a relay which hasn't yet accepted custody of it
+#[derive(Debug, Encode, Decode)]
+struct Sent {
+    relay: String,
+    destination: String,
+}
+
+#[derive(Default, Encode, Decode)]
+struct State {
+    held: BTreeMap<String, Held>,
+    // By CID
+    sent: BTreeMap<String, Sent>,
+    // DAGs we accepted custody of, by CID, with the upstream which hasn't acknowledged that yet
+    accepting: BTreeMap<String, String>,
+}
+
+pub(crate) struct Relay {
+    // Next hop by final destination
+    routes: BTreeMap<String, String>,
+    state: State,
+    resent_at: Instant,
+}
+
+impl Relay {
+    pub fn new(routes: BTreeMap<String, String>, storage: &Storage) -> Self {
+        let state = match storage.load_state(STATE_KEY) {
+            Ok(Some(bytes)) => State::decode(&mut bytes.as_slice()).unwrap_or_else(|e| {
+                warn!("Discarding unreadable relay state: {e:?}");
+                State::default()
+            }),
+            Ok(None) => State::default(),
+            Err(e) => {
+                warn!("Unable to load relay state: {e:?}");
+                State::default()
+            }
+        };
+        Self {
+            routes,
+            state,
+            resent_at: Instant::now(),
+        }
+    }
+
+    // The relay to go through to reach the destination, if it can't be sent to directly
+    pub fn next_hop(&self, destination: &str) -> Option<String> {
+        self.routes
+            .get(destination)
+            .filter(|hop| *hop != destination)
+            .cloned()
+    }
+
+    pub fn sent(
+        &mut self,
+        cid: &str,
+        hop: &str,
+        destination: &str,
+        storage: &Storage,
+    ) -> Result<()> {
+        self.state.sent.insert(
+            cid.to_owned(),
+            Sent {
+                relay: hop.to_owned(),
+                destination: destination.to_owned(),
+            },
+        );
+        self.save(storage)
+    }
+
+    pub fn hold(
+        &mut self,
+        cid: &str,
+        destination: &str,
+        upstream: &str,
+        storage: &Storage,
+    ) -> Result<()> {
+        // A repeat of a transfer we already have in hand, because our acceptance hasn't got through
+        if self.state.held.contains_key(cid) || self.state.accepting.contains_key(cid) {
+            debug!("Already have custody of {cid} from {upstream}");
+            return Ok(());
+        }
+        info!("Holding {cid} from {upstream} until it can be passed on to {destination}");
+        self.state.held.insert(
+            cid.to_owned(),
+            Held {
+                destination: destination.to_owned(),
+                upstream: upstream.to_owned(),
+                accepted: false,
+            },
+        );
+        self.save(storage)
+    }
+
+    // Returns whether the DAG was indeed waiting on this relay to accept it, in which case the
+    //  relay is responsible for it now and our copy is left for GC to free, as if it had expired
+    pub fn accepted(&mut self, cid: &str, relay: &str, storage: &Storage) -> Result<bool> {
+        if self.state.sent.get(cid).map(|s| s.relay == relay) != Some(true) {
+            return Ok(false);
+        }
+        self.state.sent.remove(cid);
+        self.save(storage)?;
+        storage.set_ttl(cid, 0)?;
+        Ok(true)
+    }
+
+    // Upstream has heard that we accepted custody of the DAG
+    pub fn acknowledged(&mut self, cid: &str, upstream: &str, storage: &Storage) -> Result<()> {
+        if self.state.accepting.get(cid).map(|u| u == upstream) == Some(true) {
+            self.state.accepting.remove(cid);
+            self.save(storage)?;
+        }
+        Ok(())
+    }
+
+    // Custody messages the other end hasn't confirmed, with who to send them to again, at most
+    //  once every RESEND_INTERVAL
+    pub fn unconfirmed(&mut self) -> Vec<(ApplicationAPI, String)> {
+        if self.resent_at.elapsed() < RESEND_INTERVAL {
+            return Vec::new();
+        }
+        self.resent_at = Instant::now();
+        let transfers = self.state.sent.iter().map(|(cid, s)| {
+            let msg = ApplicationAPI::CustodyTransfer {
+                cid: cid.clone(),
+                destination: s.destination.clone(),
+            };
+            (msg, s.relay.clone())
+        });
+        let acceptances = self.state.accepting.iter().map(|(cid, upstream)| {
+            let msg = ApplicationAPI::CustodyAccepted { cid: cid.clone() };
+            (msg, upstream.clone())
+        });
+        transfers.chain(acceptances).collect()
+    }
+
+    // DAGs which have now fully arrived, with who to tell so
+    pub fn arrived(&mut self, storage: &Storage) -> Result<Vec<(String, String)>> {
+        let mut result = Vec::new();
+        for (cid, held) in self.state.held.iter_mut().filter(|(_, h)| !h.accepted) {
+            let complete = storage.get_block_by_cid(cid).is_ok()
+                && storage
+                    .get_missing_dag_blocks(cid)
+                    .map(|m| m.is_empty())
+                    .unwrap_or(false);
+            if complete {
+                held.accepted = true;
+                self.state
+                    .accepting
+                    .insert(cid.clone(), held.upstream.clone());
+                result.push((cid.clone(), held.upstream.clone()));
+            } else {
+                debug!("Still waiting on blocks of {cid} for {}", &held.destination);
+            }
+        }
+        if !result.is_empty() {
+            self.save(storage)?;
+        }
+        Ok(result)
+    }
+
+    // Accepted DAGs and where they're headed
+    pub fn forwardable(&self) -> Vec<(String, String)> {
+        self.state
+            .held
+            .iter()
+            .filter(|(_, h)| h.accepted)
+            .map(|(c, h)| (c.clone(), h.destination.clone()))
+            .collect()
+    }
+
+    // The transfer towards the destination has been started and persists on its own from here
+    pub fn forwarded(&mut self, cid: &str, storage: &Storage) -> Result<()> {
+        self.state.held.remove(cid);
+        self.save(storage)
+    }
+
+    // Custody of an expired DAG is worthless, whichever end of it we're on
+    pub fn expired(&mut self, cid: &str, storage: &Storage) -> Result<()> {
+        let held = self.state.held.remove(cid).is_some();
+        let accepting = self.state.accepting.remove(cid).is_some();
+        if self.state.sent.remove(cid).is_some() || held || accepting {
+            self.save(storage)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, storage: &Storage) -> Result<()> {
+        storage.save_state(STATE_KEY, &self.state.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+    use local_storage::{memory_provider::MemoryStorageProvider, provider::Handle};
+    use std::sync::{Arc, Mutex};
+
+    fn storage() -> Storage {
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        Storage::new(provider, 1024)
+    }
+
+    #[test]
+    fn test_custody_accepted_once_dag_arrives() {
+        let dir = TempDir::new().unwrap();
+        let mut store = storage();
+        let file = dir.child("data");
+        file.write_binary(&[3u8; 5000]).unwrap();
+        let mut relay = Relay::new(BTreeMap::default(), &store);
+        let cid = "bafybeie5gq4jxvzmsym6hjlwxej4rwdoxt7wadqvmmwbqi7r27fclha2va";
+        relay.hold(cid, "ground:1", "sat:1", &store).unwrap();
+        assert!(relay.arrived(&store).unwrap().is_empty());
+        assert!(relay.forwardable().is_empty());
+
+        let cid = store.import_path(file.path()).unwrap();
+        relay.hold(&cid, "ground:1", "sat:1", &store).unwrap();
+        assert_eq!(
+            relay.arrived(&store).unwrap(),
+            vec![(cid.clone(), "sat:1".to_string())]
+        );
+        assert!(relay.arrived(&store).unwrap().is_empty());
+        //A repeated transfer doesn't start it over
+        relay.hold(&cid, "ground:1", "sat:1", &store).unwrap();
+        assert!(relay.arrived(&store).unwrap().is_empty());
+
+        let mut relay = Relay::new(BTreeMap::default(), &store);
+        assert_eq!(
+            relay.forwardable(),
+            vec![(cid.clone(), "ground:1".to_string())]
+        );
+        relay.forwarded(&cid, &store).unwrap();
+        assert!(relay.forwardable().is_empty());
+
+        //Acceptance is told again until upstream acknowledges it
+        relay.resent_at -= RESEND_INTERVAL;
+        let accepted = ApplicationAPI::CustodyAccepted { cid: cid.clone() };
+        assert_eq!(relay.unconfirmed(), vec![(accepted, "sat:1".to_string())]);
+        assert!(relay.unconfirmed().is_empty());
+        relay.acknowledged(&cid, "other:1", &store).unwrap();
+        relay.acknowledged(&cid, "sat:1", &store).unwrap();
+        relay.resent_at -= RESEND_INTERVAL;
+        assert!(relay.unconfirmed().is_empty());
+    }
+
+    #[test]
+    fn test_routes_and_custody_of_sent() {
+        let dir = TempDir::new().unwrap();
+        let mut store = storage();
+        let file = dir.child("data");
+        file.write_binary(&[5u8; 5000]).unwrap();
+        let cid = store.import_path(file.path()).unwrap();
+        let routes = [
+            ("ground:1".to_string(), "relay:1".to_string()),
+            ("relay:1".to_string(), "relay:1".to_string()),
+        ];
+        let mut relay = Relay::new(routes.into_iter().collect(), &store);
+        assert_eq!(relay.next_hop("ground:1"), Some("relay:1".to_string()));
+        assert_eq!(relay.next_hop("relay:1"), None);
+        assert_eq!(relay.next_hop("other:1"), None);
+
+        relay.sent(&cid, "relay:1", "ground:1", &store).unwrap();
+        relay.resent_at -= RESEND_INTERVAL;
+        let transfer = ApplicationAPI::CustodyTransfer {
+            cid: cid.clone(),
+            destination: "ground:1".to_string(),
+        };
+        assert_eq!(relay.unconfirmed(), vec![(transfer, "relay:1".to_string())]);
+        assert!(!relay.accepted(&cid, "other:1", &store).unwrap());
+        assert!(!store.incremental_gc());
+        assert!(relay.accepted(&cid, "relay:1", &store).unwrap());
+        assert!(!relay.accepted(&cid, "relay:1", &store).unwrap());
+        relay.resent_at -= RESEND_INTERVAL;
+        assert!(relay.unconfirmed().is_empty());
+
+        //The relay has it now, so the origin's blocks are released
+        let blocks = store.get_all_dag_cids(&cid, None, None).unwrap();
+        assert!(blocks.len() > 1);
+        assert!(store.incremental_gc());
+        assert!(store.list_available_cids().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use anyhow::Result;
+use local_storage::storage::Storage;
+use log::{debug, error, info, warn};
+use messages::{ApplicationAPI, ScrubLogEntry};
+use parity_scale_codec::{Decode, Encode};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Key under which scrub progress and findings are persisted in local storage
+const STATE_KEY: &str = "scrub";
+// Blocks re-hashed by each step of a ScrubStorage
+const ON_DEMAND_BATCH: usize = 64;
+// Blocks checked in the background between writes of the cursor, to spare the flash
+const SAVE_EVERY: usize = 64;
+// Corruptions kept for reporting, oldest dropped first
+const LOG_LEN: usize = 100;
+
+#[derive(Default, Encode, Decode)]
+struct State {
+    // Background scrubbing resumes after this CID, starting over once it runs off the end
+    cursor: String,
+    log: Vec<ScrubLogEntry>,
+}
+
+// A ScrubStorage under way, going through every block from the start
+#[derive(Default)]
+struct OnDemand {
+    after: String,
+    checked: u64,
+    corrupted: Vec<String>,
+    // Who asked for it, to be sent the report once it's done
+    requesters: BTreeSet<String>,
+}
+
+// What a step of scrubbing came up with
+#[derive(Default)]
+pub(crate) struct Step {
+    // Blocks found corrupt, and so quarantined
+    pub corrupted: Vec<String>,
+    // Once a ScrubStorage has been through every block, its report and who to send it to
+    pub report: Option<(ApplicationAPI, BTreeSet<String>)>,
+}
+
+pub(crate) struct Scrubber {
+    state: State,
+    // Least time between background steps, zero to not scrub in the background at all
+    interval: Duration,
+    // Blocks re-hashed by each background step
+    batch: usize,
+    stepped_at: Option<Instant>,
+    // Blocks checked in the background since the cursor was last saved, and in this pass
+    unsaved: usize,
+    pass_checked: u64,
+    on_demand: Option<OnDemand>,
+}
+
+impl Scrubber {
+    pub fn new(storage: &Storage, interval: Duration, batch: usize) -> Self {
+        let state = match storage.load_state(STATE_KEY) {
+            Ok(Some(bytes)) => State::decode(&mut bytes.as_slice()).unwrap_or_else(|e| {
+                warn!("Discarding unreadable scrub state: {e:?}");
+                State::default()
+            }),
+            Ok(None) => State::default(),
+            Err(e) => {
+                warn!("Unable to load scrub state: {e:?}");
+                State::default()
+            }
+        };
+        Self {
+            state,
+            interval,
+            batch: batch.max(1),
+            stepped_at: None,
+            unsaved: 0,
+            pass_checked: 0,
+            on_demand: None,
+        }
+    }
+
+    // Start re-hashing every block, or have the ScrubStorage already under way report to requester too
+    pub fn request(&mut self, requester: &str) {
+        self.on_demand
+            .get_or_insert_with(OnDemand::default)
+            .requesters
+            .insert(requester.to_owned());
+    }
+
+    // Re-hash the next batch of a ScrubStorage if one is under way, otherwise the next few blocks of
+    //  the background scrub if it's time to
+    pub fn step(&mut self, storage: &Storage) -> Result<Step> {
+        if self.on_demand.is_some() {
+            return self.step_on_demand(storage);
+        }
+        Ok(Step {
+            corrupted: self.step_background(storage)?,
+            report: None,
+        })
+    }
+
+    fn step_on_demand(&mut self, storage: &Storage) -> Result<Step> {
+        let Some(on_demand) = self.on_demand.as_mut() else {
+            return Ok(Step::default());
+        };
+        let pass = storage.scrub(&on_demand.after, ON_DEMAND_BATCH)?;
+        on_demand.checked += pass.checked as u64;
+        on_demand.corrupted.extend(pass.corrupt.iter().cloned());
+        let done = match pass.last {
+            Some(last) if pass.checked == ON_DEMAND_BATCH => {
+                on_demand.after = last;
+                false
+            }
+            _ => true,
+        };
+        self.record(&pass.corrupt);
+        if done || !pass.corrupt.is_empty() {
+            self.save(storage)?;
+        }
+        let finished = if done { self.on_demand.take() } else { None };
+        let report = finished.map(|on_demand| {
+            info!(
+                "Scrub of all storage checked {} blocks, {} corrupt",
+                on_demand.checked,
+                on_demand.corrupted.len()
+            );
+            let report = ApplicationAPI::ScrubReport {
+                checked: on_demand.checked,
+                corrupted: on_demand.corrupted,
+                log: self.log(),
+            };
+            (report, on_demand.requesters)
+        });
+        Ok(Step {
+            corrupted: pass.corrupt,
+            report,
+        })
+    }
+
+    fn step_background(&mut self, storage: &Storage) -> Result<Vec<String>> {
+        if self.interval.is_zero() || self.stepped_at.is_some_and(|t| t.elapsed() < self.interval) {
+            return Ok(vec![]);
+        }
+        self.stepped_at = Some(Instant::now());
+        let pass = storage.scrub(&self.state.cursor, self.batch)?;
+        self.unsaved += pass.checked;
+        self.pass_checked += pass.checked as u64;
+        let cursor = match pass.last {
+            Some(last) if pass.checked == self.batch => last,
+            _ => {
+                if self.pass_checked > 0 {
+                    debug!(
+                        "Background scrub checked {} blocks to the end of storage, starting over",
+                        self.pass_checked
+                    );
+                }
+                self.pass_checked = 0;
+                String::new()
+            }
+        };
+        let wrapped = cursor.is_empty() && !self.state.cursor.is_empty();
+        self.state.cursor = cursor;
+        self.record(&pass.corrupt);
+        if wrapped || !pass.corrupt.is_empty() || self.unsaved >= SAVE_EVERY {
+            self.save(storage)?;
+        }
+        Ok(pass.corrupt)
+    }
+
+    pub fn log(&self) -> Vec<ScrubLogEntry> {
+        self.state.log.clone()
+    }
+
+    fn record(&mut self, corrupt: &[String]) {
+        let detected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        for cid in corrupt {
+            error!("Scrub found block {cid} corrupted, it will be fetched again");
+            self.state.log.push(ScrubLogEntry {
+                cid: cid.clone(),
+                detected,
+            });
+        }
+        let excess = self.state.log.len().saturating_sub(LOG_LEN);
+        self.state.log.drain(..excess);
+    }
+
+    fn save(&mut self, storage: &Storage) -> Result<()> {
+        self.unsaved = 0;
+        storage.save_state(STATE_KEY, &self.state.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+    use local_storage::{memory_provider::MemoryStorageProvider, provider::Handle};
+    use std::sync::{Arc, Mutex};
+
+    fn storage() -> Storage {
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        Storage::new(provider, 1024)
+    }
+
+    #[test]
+    fn test_background_scrub_resumes_and_wraps() {
+        let dir = TempDir::new().unwrap();
+        let mut store = storage();
+        let file = dir.child("data");
+        let data: Vec<u8> = (0..80_000u32).map(|i| (i % 251) as u8).collect();
+        file.write_binary(&data).unwrap();
+        store.import_path(file.path()).unwrap();
+        let blocks = store.list_available_cids().unwrap().len();
+        assert!(blocks > SAVE_EVERY);
+        let interval = Duration::from_secs(60);
+        let step = |s: &mut Scrubber| s.step(&store).unwrap().corrupted;
+
+        assert!(step(&mut Scrubber::new(&store, Duration::ZERO, 4)).is_empty());
+        assert_eq!(Scrubber::new(&store, interval, 4).state.cursor, "");
+
+        let mut scrubber = Scrubber::new(&store, interval, 4);
+        assert!(step(&mut scrubber).is_empty());
+        let cursor = scrubber.state.cursor.clone();
+        assert!(!cursor.is_empty());
+        // Not again until the interval has passed
+        step(&mut scrubber);
+        assert_eq!(scrubber.state.cursor, cursor);
+        // Nor is the cursor written out yet
+        assert_eq!(Scrubber::new(&store, interval, 4).state.cursor, "");
+        for _ in 1..SAVE_EVERY / 4 {
+            scrubber.stepped_at = None;
+            step(&mut scrubber);
+        }
+        // Picks up where it left off after a restart
+        let cursor = scrubber.state.cursor;
+        let mut scrubber = Scrubber::new(&store, interval, 4);
+        assert_eq!(scrubber.state.cursor, cursor);
+        for _ in 0..blocks {
+            scrubber.stepped_at = None;
+            step(&mut scrubber);
+            if scrubber.state.cursor.is_empty() {
+                break;
+            }
+        }
+        assert!(scrubber.state.cursor.is_empty());
+        assert_eq!(Scrubber::new(&store, interval, 4).state.cursor, "");
+    }
+
+    #[test]
+    fn test_scrub_storage_paced_and_reported() {
+        let dir = TempDir::new().unwrap();
+        let mut store = storage();
+        let file = dir.child("data");
+        let data: Vec<u8> = (0..80_000u32).map(|i| (i % 251) as u8).collect();
+        file.write_binary(&data).unwrap();
+        store.import_path(file.path()).unwrap();
+        let blocks = store.list_available_cids().unwrap().len();
+        assert!(blocks > ON_DEMAND_BATCH);
+
+        let mut scrubber = Scrubber::new(&store, Duration::ZERO, 4);
+        assert!(scrubber.step(&store).unwrap().report.is_none());
+        scrubber.request("ground:1");
+        // A batch at a time, and a second request joins the one under way
+        assert!(scrubber.step(&store).unwrap().report.is_none());
+        scrubber.request("other:1");
+        let mut report = None;
+        for _ in 0..blocks {
+            report = scrubber.step(&store).unwrap().report;
+            if report.is_some() {
+                break;
+            }
+        }
+        let expected = ApplicationAPI::ScrubReport {
+            checked: blocks as u64,
+            corrupted: vec![],
+            log: vec![],
+        };
+        let requesters = ["ground:1".to_string(), "other:1".to_string()];
+        assert_eq!(report, Some((expected, BTreeSet::from(requesters))));
+        assert!(scrubber.on_demand.is_none());
+        assert!(scrubber.step(&store).unwrap().report.is_none());
+    }
+
+    #[test]
+    fn test_log_bounded() {
+        let mut scrubber = Scrubber::new(&storage(), Duration::ZERO, 1);
+        let corrupt: Vec<String> = (0..LOG_LEN + 3).map(|i| i.to_string()).collect();
+        scrubber.record(&corrupt);
+        let log = scrubber.log();
+        assert_eq!(log.len(), LOG_LEN);
+        assert_eq!(log[0].cid, "3");
+        assert!(log[0].detected > 0);
+    }
+}
@@ -1,37 +1,88 @@
 use crate::handlers;
 use anyhow::{anyhow, Result};
 use cid::Cid;
-use local_storage::block::StoredBlock;
+use local_storage::block::{is_inlined, StoredBlock};
 use local_storage::{provider::Handle as StorageProviderHandle, storage::Storage};
+use messages::cid_list::CompactList;
 use messages::Message;
-use messages::{DataProtocol, TransmissionBlock};
-use std::collections::BTreeMap;
+use messages::{bitmap_has, DataProtocol, ShipSessionInfo, TransmissionBlock};
+use parity_scale_codec::{Decode, Encode};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::ToSocketAddrs;
+use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread::{sleep, spawn};
-use std::time::Duration;
-use transports::Transport;
+use std::time::{Duration, Instant};
+use transports::{Eid, Transport};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-#[derive(Debug, Clone)]
+// Key under which the open sessions are persisted in local storage
+const STATE_KEY: &str = "ship";
+// How far a session's window may grow, as a multiple of the configured window size
+const MAX_WINDOW_GROWTH: u32 = 4;
+// Lower bound on the adaptive retry timeout, unless the configured timeout is lower still
+const MIN_RETRY_TIMEOUT_MS: u64 = 1_000;
+
+#[derive(Debug, Clone, Encode, Decode)]
 enum SessionMode {
     // Normal transfer mode
     Normal,
     // This session is resuming from scratch and will need to
     // iterate through a DAG's windows to find the last in-progress window
     Resuming,
+    // This session has been suspended by the operator and will not transmit
+    // anything until it is explicitly resumed
+    Paused,
 }
 
-#[derive(Clone)]
+// Fields skipped when encoding are rebuilt when the session resumes after a restart, so
+// changing them doesn't cost a write to storage
+#[derive(Clone, Encode, Decode)]
 struct WindowSession {
     pub max_retries: u8,
     pub remaining_window_retries: u8,
     pub window_num: u32,
     pub target_addr: String,
     pub mode: SessionMode,
+    // Sessions with a higher priority pre-empt those with a lower one
+    pub priority: u8,
+    // Order in which sessions were opened, used to break ties in priority
+    pub seq: u64,
+    // Set when the receiver hadn't advertised bitmaps as the session opened, so windows are
+    // cut from the provider's own block order in a single query, as older receivers expect
+    pub provider_order: bool,
+    // Set when the session was held back by a higher-priority session
+    #[codec(skip)]
+    pub deferred: bool,
+    // Index within the DAG of the first block in the current window
+    pub offset: u32,
+    // Number of blocks in the current window
+    #[codec(skip)]
+    pub window_len: u32,
+    // Number of blocks the next window will hold, adapted to observed loss
+    #[codec(skip)]
+    pub window_size: u32,
+    // Whether the current window has already shrunk the window size due to loss
+    #[codec(skip)]
+    pub lossy: bool,
+    // Smoothed round-trip time and its variation in milliseconds, 0 until measured
+    #[codec(skip)]
+    pub srtt_ms: u64,
+    #[codec(skip)]
+    pub rttvar_ms: u64,
+    // Retry timeout in milliseconds, derived from the round-trip time
+    #[codec(skip)]
+    pub retry_timeout_ms: u64,
+    // When the outstanding request for missing blocks went out
+    #[codec(skip)]
+    pub awaiting_since: Option<Instant>,
+    // Set once a bitmap request timed out unanswered, so windows are asked about by CID instead
+    #[codec(skip)]
+    pub by_cid: bool,
 }
 
 pub struct Shipper<T> {
@@ -54,6 +105,14 @@ pub struct Shipper<T> {
     // Radio address
     radio_address: Option<String>,
     packet_delay_ms: u32,
+    // Sequence number to hand out to the next opened session
+    next_seq: u64,
+    // Adapted parameters of each session, shared with the Listener for querying
+    session_params: Arc<Mutex<Vec<ShipSessionInfo>>>,
+    // Peers whose Version reply said they understand window bitmaps, shared with the Listener
+    bitmap_peers: Arc<Mutex<BTreeSet<String>>>,
+    // Set when a session was opened, closed or changed in a way that survives a restart
+    dirty: bool,
 }
 
 impl<T: Transport + Send + 'static> Shipper<T> {
@@ -69,11 +128,19 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         block_size: u32,
         radio_address: Option<String>,
         packet_delay_ms: u32,
+        session_params: Arc<Mutex<Vec<ShipSessionInfo>>>,
+        bitmap_peers: Arc<Mutex<BTreeSet<String>>>,
     ) -> Result<Shipper<T>> {
         let storage = Storage::new(storage_provider, block_size);
+        let window_sessions = load_window_sessions(&storage, window_size, retry_timeout_duration);
+        let next_seq = window_sessions
+            .values()
+            .map(|s| s.seq + 1)
+            .max()
+            .unwrap_or(0);
         Ok(Shipper {
             storage,
-            window_sessions: BTreeMap::new(),
+            window_sessions,
             receiver,
             sender,
             retry_timeout_duration,
@@ -82,6 +149,10 @@ impl<T: Transport + Send + 'static> Shipper<T> {
             connected,
             radio_address,
             packet_delay_ms,
+            next_seq,
+            session_params,
+            bitmap_peers,
+            dirty: false,
         })
     }
 
@@ -96,8 +167,15 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         }
     }
 
-    // Examine a received message and take appropriate action
+    // Examine a received message and take appropriate action, then persist any session changes
     pub fn process_msg(&mut self, message: DataProtocol, sender_addr: &str) -> Result<()> {
+        let result = self.handle_msg(message, sender_addr);
+        self.save_window_sessions();
+        self.publish_session_params();
+        result
+    }
+
+    fn handle_msg(&mut self, message: DataProtocol, sender_addr: &str) -> Result<()> {
         // Find a reasonable target address to respond to by either using our radio_address
         // or using the sender_addr if no radio address is set
         let target_addr = if let Some(radio_address) = &self.radio_address {
@@ -105,6 +183,7 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         } else {
             sender_addr.to_owned()
         };
+        self.drop_expired_sessions()?;
         match message {
             DataProtocol::RequestTransmitBlock { cid, target_addr } => {
                 if *self.connected.lock().unwrap() {
@@ -120,6 +199,7 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                 self.start_dag_window_session(&cid, &target_addr, retries)?;
             }
             DataProtocol::RetryDagSession { cid } => {
+                self.check_retry_timeout(&cid);
                 if *self.connected.lock().unwrap() {
                     if let Some(_session) = self.window_sessions.get(&cid) {
                         info!(
@@ -127,7 +207,7 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                             &_session.target_addr
                         );
                         self.dag_window_session_run(&cid)?;
-                        self.retry_dag_window_session(&cid);
+                        self.retry_dag_window_session(&cid)?;
                     }
                 }
             }
@@ -149,38 +229,64 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                 }
             }
             DataProtocol::MissingDagBlocks { cid, blocks } => {
+                self.missing_dag_blocks(&cid, blocks, target_addr)?;
+            }
+            DataProtocol::RequestMissingDagWindowBitmap { cid, offset, count } => {
                 if *self.connected.lock().unwrap() {
-                    let target_addr = if let Some(session) = self.window_sessions.get(&cid) {
-                        session.target_addr.to_owned()
-                    } else {
-                        target_addr
-                    };
-
-                    info!("Got missing blocks resp {blocks:?}");
-                    // If no blocks are missing, then attempt to move to next window
-                    if blocks.is_empty() {
-                        info!("No blocks missing, moving to next window");
-                        self.next_dag_window_session(&cid);
-                        self.dag_window_session_run(&cid)?;
-                    } else if let Some(session) = self.window_sessions.get_mut(&cid) {
-                        session.mode = SessionMode::Normal;
-                        info!(
-                            "Dag {cid} is missing {} blocks, sending again",
-                            blocks.len()
-                        );
-                        for b in blocks.clone() {
-                            self.transmit_block(&b, &target_addr)?;
-                        }
-                        self.transmit_msg(
-                            Message::DataProtocol(DataProtocol::RequestMissingDagWindowBlocks {
-                                cid,
-                                blocks,
-                            }),
-                            &target_addr,
-                        )?;
-                    } else {
-                        error!("Unable to find session for {cid:?}");
+                    let missing_blocks_msg = handlers::get_missing_dag_window_bitmap(
+                        &cid,
+                        offset,
+                        count,
+                        &self.storage,
+                    )?;
+                    self.transmit_msg(missing_blocks_msg, &target_addr)?;
+                }
+            }
+            DataProtocol::RequestMissingDagWindowDigests {
+                cid,
+                offset,
+                blocks,
+            } => {
+                if *self.connected.lock().unwrap() {
+                    let missing_blocks_msg = handlers::get_missing_dag_window_digests(
+                        &cid,
+                        offset,
+                        blocks,
+                        &self.storage,
+                    )?;
+                    self.transmit_msg(missing_blocks_msg, &target_addr)?;
+                }
+            }
+            DataProtocol::MissingDagBlocksBitmap {
+                cid,
+                offset,
+                known,
+                missing,
+            } => {
+                let window = match self.window_sessions.get(&cid) {
+                    Some(s) if s.offset == offset => handlers::distinct(
+                        self.storage
+                            .get_dag_window_cids(&cid, offset, s.window_len)?,
+                    ),
+                    _ => {
+                        debug!("Ignoring missing blocks bitmap for {cid} at {offset}, not the current window");
+                        return Ok(());
                     }
+                };
+                if (known as usize) < window.len() {
+                    info!(
+                        "Receiver could only place {known} of {} blocks of {cid}, asking by digest",
+                        window.len()
+                    );
+                    self.request_missing_window_digests(&cid, offset, &window)?;
+                } else {
+                    let blocks = window
+                        .into_iter()
+                        .enumerate()
+                        .filter(|(i, _)| bitmap_has(&missing, *i))
+                        .map(|(_, c)| c)
+                        .collect();
+                    self.missing_dag_blocks(&cid, blocks, target_addr)?;
                 }
             }
             DataProtocol::ResumeTransmitDag { cid } => {
@@ -194,6 +300,85 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                     self.resume_all_dag_window_sessions()?;
                 }
             }
+            DataProtocol::CancelTransmitDag { cid } => {
+                if self.window_sessions.contains_key(&cid) {
+                    info!("Shipper cancel {cid}");
+                    self.end_dag_window_session(&cid);
+                    self.run_deferred_sessions()?;
+                }
+            }
+            DataProtocol::PauseTransmitDag { cid } => {
+                if let Some(session) = self.window_sessions.get_mut(&cid) {
+                    info!("Shipper pause {cid}");
+                    session.mode = SessionMode::Paused;
+                    self.dirty = true;
+                    self.run_deferred_sessions()?;
+                }
+            }
+            DataProtocol::SetPriority { cid, priority } => {
+                if let Some(session) = self.window_sessions.get_mut(&cid) {
+                    info!("Shipper priority {cid} {priority}");
+                    session.priority = priority;
+                    self.dirty = true;
+                    self.run_deferred_sessions()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Write the open sessions to storage so they survive a reset, if they changed
+    fn save_window_sessions(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match self
+            .storage
+            .save_state(STATE_KEY, &self.window_sessions.encode())
+        {
+            Ok(()) => self.dirty = false,
+            Err(e) => warn!("Unable to persist dag transfer sessions: {e:?}"),
+        }
+    }
+
+    // Act on the receiver's report of which blocks of the current window it still lacks
+    fn missing_dag_blocks(
+        &mut self,
+        cid: &str,
+        blocks: Vec<String>,
+        target_addr: String,
+    ) -> Result<()> {
+        self.observe_missing_blocks(cid, blocks.len());
+        if self.is_paused(cid) {
+            info!("Ignoring missing blocks resp for paused dag {cid}");
+        } else if self.defer_if_preempted(cid) {
+            info!("Ignoring missing blocks resp for pre-empted dag {cid}");
+        } else if *self.connected.lock().unwrap() {
+            let target_addr = if let Some(session) = self.window_sessions.get(cid) {
+                session.target_addr.to_owned()
+            } else {
+                target_addr
+            };
+
+            info!("Got missing blocks resp {blocks:?}");
+            // If no blocks are missing, then attempt to move to next window
+            if blocks.is_empty() {
+                info!("No blocks missing, moving to next window");
+                self.next_dag_window_session(cid);
+                self.dag_window_session_run(cid)?;
+            } else if let Some(session) = self.window_sessions.get_mut(cid) {
+                session.mode = SessionMode::Normal;
+                info!(
+                    "Dag {cid} is missing {} blocks, sending again",
+                    blocks.len()
+                );
+                for b in &blocks {
+                    self.transmit_block(b, &target_addr)?;
+                }
+                self.request_missing_window_blocks(cid, blocks, &target_addr)?;
+            } else {
+                error!("Unable to find session for {cid:?}");
+            }
         }
         Ok(())
     }
@@ -206,15 +391,32 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         target_addr: &str,
         mode: SessionMode,
     ) {
-        self.window_sessions
-            .entry(cid.to_string())
-            .or_insert(WindowSession {
-                max_retries: retries,
-                remaining_window_retries: retries,
-                window_num: 0,
-                target_addr: target_addr.to_string(),
-                mode,
-            });
+        if self.window_sessions.contains_key(cid) {
+            return;
+        }
+        let session = WindowSession {
+            max_retries: retries,
+            remaining_window_retries: retries,
+            window_num: 0,
+            target_addr: target_addr.to_string(),
+            mode,
+            priority: self.storage.get_priority(cid),
+            seq: self.next_seq,
+            provider_order: !self.bitmap_peers.lock().unwrap().contains(target_addr),
+            deferred: false,
+            offset: 0,
+            window_len: 0,
+            window_size: self.window_size,
+            lossy: false,
+            srtt_ms: 0,
+            rttvar_ms: 0,
+            retry_timeout_ms: self.retry_timeout_duration,
+            awaiting_since: None,
+            by_cid: false,
+        };
+        self.next_seq += 1;
+        self.window_sessions.insert(cid.to_string(), session);
+        self.dirty = true;
     }
 
     // Helper function for incrementing a session's window and resetting the retries
@@ -227,21 +429,240 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                 session.window_num + 1
             );
             session.window_num += 1;
+            session.offset += session.window_len;
             session.remaining_window_retries = session.max_retries;
+            self.dirty = true;
+            // Additive increase after every window that made it across cleanly
+            if !session.lossy && session.window_size < self.window_size * MAX_WINDOW_GROWTH {
+                session.window_size += 1;
+                info!("Dag {cid} window size grown to {}", session.window_size);
+            }
+            session.lossy = false;
+        }
+    }
+
+    // Account for a missing blocks response: measure the round trip and shrink the window on loss
+    fn observe_missing_blocks(&mut self, cid: &str, missing: usize) {
+        let max_timeout = self.retry_timeout_duration;
+        if let Some(session) = self.window_sessions.get_mut(cid) {
+            if let Some(sent) = session.awaiting_since.take() {
+                let sample = (sent.elapsed().as_millis() as u64).max(1);
+                if session.srtt_ms == 0 {
+                    session.srtt_ms = sample;
+                    session.rttvar_ms = sample / 2;
+                } else {
+                    session.rttvar_ms =
+                        (3 * session.rttvar_ms + session.srtt_ms.abs_diff(sample)) / 4;
+                    session.srtt_ms = (7 * session.srtt_ms + sample) / 8;
+                }
+                session.retry_timeout_ms = (session.srtt_ms + 4 * session.rttvar_ms)
+                    .max(MIN_RETRY_TIMEOUT_MS.min(max_timeout))
+                    .min(max_timeout);
+                debug!(
+                    "Dag {cid} rtt sample {sample}ms, srtt {}ms, retry timeout {}ms",
+                    session.srtt_ms, session.retry_timeout_ms
+                );
+            }
+            if missing > 0 && !session.lossy {
+                session.lossy = true;
+                session.window_size = (session.window_size / 2).max(1);
+                info!(
+                    "Dag {cid} lost {missing} of {} blocks, window size shrunk to {}",
+                    session.window_len, session.window_size
+                );
+            }
+        }
+    }
+
+    // A retry firing while a request is still unanswered is a timeout: back off
+    fn check_retry_timeout(&mut self, cid: &str) {
+        let max_timeout = self.retry_timeout_duration;
+        let bitmap_peers = self.bitmap_peers.lock().unwrap();
+        if let Some(session) = self.window_sessions.get_mut(cid) {
+            let timed_out = session
+                .awaiting_since
+                .map(|s| s.elapsed() >= Duration::from_millis(session.retry_timeout_ms))
+                .unwrap_or(false);
+            if timed_out {
+                session.awaiting_since = None;
+                session.retry_timeout_ms = (session.retry_timeout_ms * 2).min(max_timeout);
+                session.window_size = (session.window_size / 2).max(1);
+                session.lossy = true;
+                info!(
+                    "Dag {cid} timed out, window size now {} and retry timeout {}ms",
+                    session.window_size, session.retry_timeout_ms
+                );
+                if !session.by_cid
+                    && !session.provider_order
+                    && bitmap_peers.contains(&session.target_addr)
+                {
+                    info!("Dag {cid} bitmap request went unanswered, asking by CID instead");
+                    session.by_cid = true;
+                }
+            }
         }
     }
 
-    // Helper function for removing sessions which are complete
+    // Ask which blocks of the current window are missing, by position in the dag if the peer
+    // is known to understand that, otherwise by listing their CIDs
+    fn request_missing_window_blocks(
+        &mut self,
+        cid: &str,
+        blocks: Vec<String>,
+        target_addr: &str,
+    ) -> Result<()> {
+        let bitmap_peers = self.bitmap_peers.lock().unwrap();
+        let msg = match self.window_sessions.get_mut(cid) {
+            Some(session) => {
+                session.awaiting_since.get_or_insert_with(Instant::now);
+                if !session.by_cid && !session.provider_order && bitmap_peers.contains(target_addr)
+                {
+                    DataProtocol::RequestMissingDagWindowBitmap {
+                        cid: cid.to_string(),
+                        offset: session.offset,
+                        count: session.window_len,
+                    }
+                } else {
+                    DataProtocol::RequestMissingDagWindowBlocks {
+                        cid: cid.to_string(),
+                        blocks,
+                    }
+                }
+            }
+            None => return Ok(()),
+        };
+        drop(bitmap_peers);
+        self.transmit_msg(Message::DataProtocol(msg), target_addr)
+    }
+
+    // Same question, listing digests for a receiver that can't place blocks in the dag yet
+    fn request_missing_window_digests(
+        &mut self,
+        cid: &str,
+        offset: u32,
+        window: &[String],
+    ) -> Result<()> {
+        let target_addr = match self.window_sessions.get_mut(cid) {
+            Some(session) => {
+                session.awaiting_since.get_or_insert_with(Instant::now);
+                session.target_addr.to_owned()
+            }
+            None => return Ok(()),
+        };
+        // Each list holds a single codec, so start a new one whenever it changes
+        let mut blocks: Vec<CompactList> = Vec::new();
+        for c in window {
+            let c = Cid::try_from(c.as_str())?;
+            if !blocks
+                .last_mut()
+                .map(|l| l.include(&c, usize::MAX))
+                .unwrap_or(false)
+            {
+                blocks.push(CompactList::try_from(&c)?);
+            }
+        }
+        self.transmit_msg(
+            Message::DataProtocol(DataProtocol::RequestMissingDagWindowDigests {
+                cid: cid.to_string(),
+                offset,
+                blocks,
+            }),
+            &target_addr,
+        )
+    }
+
+    fn publish_session_params(&self) {
+        let params = self
+            .window_sessions
+            .iter()
+            .map(|(cid, s)| ShipSessionInfo {
+                cid: cid.to_owned(),
+                target_addr: s.target_addr.to_owned(),
+                window_num: s.window_num,
+                window_size: s.window_size,
+                retry_timeout_ms: s.retry_timeout_ms,
+                srtt_ms: s.srtt_ms,
+            })
+            .collect();
+        *self.session_params.lock().unwrap() = params;
+    }
+
+    // Helper function for removing sessions which are complete (or cancelled)
     fn end_dag_window_session(&mut self, cid: &str) {
-        self.window_sessions.remove(cid);
+        self.dirty |= self.window_sessions.remove(cid).is_some();
+    }
+
+    // DAGs past their TTL aren't worth the downlink anymore
+    fn drop_expired_sessions(&mut self) -> Result<()> {
+        let expired: Vec<String> = self
+            .window_sessions
+            .keys()
+            .filter(|c| self.storage.is_expired(c))
+            .cloned()
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        for cid in expired {
+            info!("Dropping dag transfer session for {cid}, which has expired");
+            self.end_dag_window_session(&cid);
+        }
+        self.run_deferred_sessions()
+    }
+
+    // A session yields to any other unpaused session with a strictly higher priority
+    fn defer_if_preempted(&mut self, cid: &str) -> bool {
+        let Some(priority) = self.window_sessions.get(cid).map(|s| s.priority) else {
+            return false;
+        };
+        let preempted = self.window_sessions.iter().any(|(c, s)| {
+            c != cid && s.priority > priority && !matches!(s.mode, SessionMode::Paused)
+        });
+        if preempted {
+            if let Some(session) = self.window_sessions.get_mut(cid) {
+                debug!("Dag transfer session for {cid} deferred to higher priority sessions");
+                session.deferred = true;
+            }
+        }
+        preempted
+    }
+
+    // Restart sessions which were held back, now that whatever pre-empted them may be gone
+    fn run_deferred_sessions(&mut self) -> Result<()> {
+        let mut deferred: Vec<(u8, u64, String)> = self
+            .window_sessions
+            .iter()
+            .filter(|(_, s)| s.deferred && !matches!(s.mode, SessionMode::Paused))
+            .map(|(c, s)| (s.priority, s.seq, c.to_owned()))
+            .collect();
+        deferred.sort_by_key(|(p, seq, _)| (Reverse(*p), *seq));
+        for (_, _, cid) in deferred {
+            if let Some(session) = self.window_sessions.get_mut(&cid) {
+                session.deferred = false;
+            }
+            self.resume_dag_window_session(&cid)?;
+        }
+        Ok(())
+    }
+
+    fn is_paused(&self, cid: &str) -> bool {
+        self.window_sessions
+            .get(cid)
+            .map(|s| matches!(s.mode, SessionMode::Paused))
+            .unwrap_or(false)
     }
 
     fn start_dag_window_retry_timeout(&mut self, cid: &str) {
         let sender_clone = self.sender.clone();
         let cid_str = cid.to_string();
 
-        debug!("Starting retry timer at {}", self.retry_timeout_duration);
-        let timeout_duration = Duration::from_millis(self.retry_timeout_duration);
+        let timeout_ms = self
+            .window_sessions
+            .get(cid)
+            .map(|s| s.retry_timeout_ms)
+            .unwrap_or(self.retry_timeout_duration);
+        debug!("Starting retry timer at {timeout_ms}");
+        let timeout_duration = Duration::from_millis(timeout_ms);
         spawn(move || {
             sleep(timeout_duration);
             sender_clone
@@ -253,31 +674,32 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         });
     }
 
-    fn retry_dag_window_session(&mut self, cid: &str) {
+    fn retry_dag_window_session(&mut self, cid: &str) -> Result<()> {
         if let Some(session) = self.window_sessions.get_mut(cid) {
+            if matches!(session.mode, SessionMode::Paused) || session.deferred {
+                return Ok(());
+            }
             if session.remaining_window_retries > 0 {
                 session.remaining_window_retries -= 1;
+                self.dirty = true;
                 self.start_dag_window_retry_timeout(cid);
             }
         }
+        Ok(())
     }
 
     // Run the transmission portion of a dag session
     // Which is either transmitting a window of blocks (in normal mode)
     // or just transmitting a RequestMissingDagWindowBlocks message (in resuming mode)
     fn dag_window_session_run(&mut self, cid: &str) -> Result<()> {
-        if *self.connected.lock().unwrap() {
+        if *self.connected.lock().unwrap() && !self.defer_if_preempted(cid) {
             if let Some(session) = self.window_sessions.get(cid) {
                 let session = session.clone();
                 // Either transmit the blocks in the dag window, or fetch the CIDs
                 // depending on the mode. Return either way to request missing blocks
                 let blocks = match session.mode {
                     SessionMode::Normal => {
-                        let blocks = self.transmit_dag_window(
-                            cid,
-                            session.window_num,
-                            &session.target_addr,
-                        )?;
+                        let blocks = self.transmit_dag_window(cid, &session)?;
                         if !blocks.is_empty() {
                             info!(
                                 "Transmitted window {} for {}, {} blocks",
@@ -297,22 +719,27 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                                 ),
                                 &session.target_addr,
                             )?;
+                            self.run_deferred_sessions()?;
                             return Ok(());
                         }
                     }
-                    SessionMode::Resuming => self.storage.get_all_dag_cids(
+                    SessionMode::Paused => {
+                        debug!("Dag transfer session for {cid} is paused");
+                        return Ok(());
+                    }
+                    SessionMode::Resuming if session.provider_order => self
+                        .storage
+                        .get_all_dag_cids(cid, Some(session.offset), Some(session.window_size))?,
+                    SessionMode::Resuming => self.storage.get_dag_window_cids(
                         cid,
-                        Some(session.window_num * self.window_size),
-                        Some(self.window_size),
+                        session.offset,
+                        session.window_size,
                     )?,
                 };
-                self.transmit_msg(
-                    Message::DataProtocol(DataProtocol::RequestMissingDagWindowBlocks {
-                        cid: cid.to_string(),
-                        blocks,
-                    }),
-                    &session.target_addr,
-                )?;
+                if let Some(s) = self.window_sessions.get_mut(cid) {
+                    s.window_len = blocks.len() as u32;
+                }
+                self.request_missing_window_blocks(cid, blocks, &session.target_addr)?;
             }
         }
         Ok(())
@@ -323,6 +750,7 @@ impl<T: Transport + Send + 'static> Shipper<T> {
     fn resume_dag_window_session(&mut self, cid: &str) -> Result<()> {
         if let Some(session) = self.window_sessions.get_mut(cid) {
             println!("setting {cid} session to resuming and going");
+            self.dirty |= matches!(session.mode, SessionMode::Paused);
             session.mode = SessionMode::Resuming;
             self.dag_window_session_run(cid)?;
             self.start_dag_window_retry_timeout(cid);
@@ -331,10 +759,17 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         Ok(())
     }
 
-    // Iterate through all open sessions and resume them
+    // Iterate through all open sessions and resume them by priority then age,
+    // leaving alone any which were explicitly paused
     fn resume_all_dag_window_sessions(&mut self) -> Result<()> {
-        let session_cids: Vec<String> = self.window_sessions.keys().map(|s| s.to_owned()).collect();
-        for cid in session_cids {
+        let mut sessions: Vec<(u8, u64, String)> = self
+            .window_sessions
+            .iter()
+            .filter(|(_, s)| !matches!(s.mode, SessionMode::Paused))
+            .map(|(c, s)| (s.priority, s.seq, c.to_owned()))
+            .collect();
+        sessions.sort_by_key(|(p, seq, _)| (Reverse(*p), *seq));
+        for (_, _, cid) in sessions {
             self.resume_dag_window_session(&cid)?;
         }
 
@@ -349,6 +784,10 @@ impl<T: Transport + Send + 'static> Shipper<T> {
         target_addr: &str,
         retries: u8,
     ) -> Result<()> {
+        if self.storage.is_expired(cid) {
+            warn!("Not transmitting {cid}, which has expired");
+            return Ok(());
+        }
         if *self.connected.lock().unwrap() {
             let retries = if retries == 0 { 0 } else { retries - 1 };
             self.open_dag_window_session(cid, retries, target_addr, SessionMode::Normal);
@@ -363,13 +802,15 @@ impl<T: Transport + Send + 'static> Shipper<T> {
     }
 
     // Single point of transmission over transport
+    // The target may be a DTN endpoint ID for the bundle transport rather than a socket address
     fn transmit_msg(&mut self, msg: Message, target_addr: &str) -> Result<()> {
-        let _resolved_target_addr = target_addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or(anyhow!("Failed to parse target address"))?;
-
-        info!("Transmitting {msg:?} to {_resolved_target_addr}");
+        if Eid::from_str(target_addr).is_err() {
+            let _resolved_target_addr = target_addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or(anyhow!("Failed to parse target address"))?;
+        }
+        info!("Transmitting {msg:?} to {target_addr}");
         self.transport.send(msg, target_addr)?;
         if self.packet_delay_ms > 0 {
             std::thread::sleep(Duration::from_millis(self.packet_delay_ms.into()));
@@ -389,7 +830,6 @@ impl<T: Transport + Send + 'static> Shipper<T> {
                 block.cid.to_string()
             );
             self.transmit_msg(Message::data_block(transmission), target_addr)?;
-
         }
 
         Ok(())
@@ -406,32 +846,44 @@ impl<T: Transport + Send + 'static> Shipper<T> {
 
     // Grab blocks for CID in current window and transmit to target_addr
     // Return CIDs for blocks transmitted for tracking end of transfer
-    pub fn transmit_dag_window(
-        &mut self,
-        cid: &str,
-        window_num: u32,
-        target_addr: &str,
-    ) -> Result<Vec<String>> {
-        if *self.connected.lock().unwrap() {
-            let window_blocks =
-                self.storage
-                    .get_dag_blocks_by_window(cid, self.window_size, window_num)?;
+    // Windows are in breadth-first order, unless the session kept to the provider's order
+    fn transmit_dag_window(&mut self, cid: &str, session: &WindowSession) -> Result<Vec<String>> {
+        let (offset, window_size) = (session.offset, session.window_size);
+        let target_addr = &session.target_addr;
+        if !*self.connected.lock().unwrap() {
+            Ok(vec![])
+        } else if session.provider_order {
+            let window_blocks = self
+                .storage
+                .get_provider()
+                .lock()
+                .unwrap()
+                .get_dag_blocks_by_window(cid, offset, window_size)?;
+            info!(
+                "transmitting {} blocks in window starting at {}",
+                window_blocks.len(),
+                offset,
+            );
+            self.transmit_blocks(&window_blocks, target_addr)?;
+            Ok(window_blocks.into_iter().map(|b| b.cid).collect())
+        } else {
+            let window_cids = self.storage.get_dag_window_cids(cid, offset, window_size)?;
+            let mut window_blocks = vec![];
+            // Inlined blocks arrive within their parents' links
+            for c in handlers::distinct(window_cids.clone()) {
+                if !is_inlined(&c) {
+                    window_blocks.push(self.storage.get_block_by_cid(&c)?);
+                }
+            }
 
             info!(
-                "transmitting {} blocks in window {}",
+                "transmitting {} blocks in window starting at {}",
                 window_blocks.len(),
-                window_num,
+                offset,
             );
 
             self.transmit_blocks(&window_blocks, target_addr)?;
-            let transmitted_cids = window_blocks
-                .iter()
-                .map(|b| b.cid.to_string())
-                .collect::<Vec<String>>();
-
-            Ok(transmitted_cids)
-        } else {
-            Ok(vec![])
+            Ok(window_cids)
         }
     }
 
@@ -467,7 +919,41 @@ fn stored_block_to_transmission_block(stored: &StoredBlock) -> Result<Transmissi
     })
 }
 
-#[cfg(all(test, feature = "sqlite"))]
+// Reload sessions saved before a restart. They pick up from their saved window and retries, with
+// the configured window size and retry timeout, and come back in resuming mode (except those the
+// operator had paused), so the receiver reports what it still lacks once they are told to resume.
+fn load_window_sessions(
+    storage: &Storage,
+    window_size: u32,
+    retry_timeout_ms: u64,
+) -> BTreeMap<String, WindowSession> {
+    let bytes = match storage.load_state(STATE_KEY) {
+        Ok(Some(b)) => b,
+        Ok(None) => return BTreeMap::new(),
+        Err(e) => {
+            warn!("Unable to load saved dag transfer sessions: {e:?}");
+            return BTreeMap::new();
+        }
+    };
+    let mut sessions = match BTreeMap::<String, WindowSession>::decode(&mut bytes.as_slice()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Discarding corrupt saved dag transfer sessions: {e:?}");
+            return BTreeMap::new();
+        }
+    };
+    for (cid, session) in sessions.iter_mut() {
+        if !matches!(session.mode, SessionMode::Paused) {
+            session.mode = SessionMode::Resuming;
+        }
+        session.window_size = window_size;
+        session.retry_timeout_ms = retry_timeout_ms;
+        info!("Restored dag transfer session for {cid}");
+    }
+    sessions
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -475,7 +961,7 @@ mod tests {
     use assert_fs::{fixture::PathChild, TempDir};
     use cid::multihash::MultihashDigest;
     use cid::Cid;
-    use local_storage::{provider::Handle, sql_provider::SqliteStorageProvider};
+    use local_storage::{memory_provider::MemoryStorageProvider, provider::Handle};
     use messages::{DataProtocol, Message, TransmissionBlock};
     use rand::{thread_rng, Rng, RngCore};
     use std::path::PathBuf;
@@ -508,9 +994,7 @@ mod tests {
             let shipper_transport = Arc::clone(&listen_transport);
 
             let test_dir = TempDir::new().unwrap();
-            let db_path = test_dir.child("storage.db");
-            let provider = SqliteStorageProvider::new(db_path.path().to_str().unwrap()).unwrap();
-            provider.setup().unwrap();
+            let provider = MemoryStorageProvider::new(u64::MAX);
             let provider: Handle = Arc::new(Mutex::new(provider));
             let _storage = Storage::new(Arc::clone(&provider), BLOCK_SIZE);
             let (shipper_sender, shipper_receiver) = mpsc::channel();
@@ -525,7 +1009,9 @@ mod tests {
                 Arc::new(Mutex::new(true)),
                 BLOCK_SIZE,
                 None,
-                0
+                0,
+                Arc::default(),
+                Arc::default(),
             )
             .unwrap();
             TestShipper {
@@ -573,6 +1059,20 @@ mod tests {
         assert_eq!(blocks, vec![cid.to_string()]);
     }
 
+    #[test]
+    pub fn test_transmit_to_unresolvable_target_fails() {
+        let mut harness = TestShipper::new();
+        let target = harness.listen_addr.clone();
+        assert!(harness
+            .shipper
+            .transmit_msg(Message::request_version(String::new()), &target)
+            .is_ok());
+        assert!(harness
+            .shipper
+            .transmit_msg(Message::request_version(String::new()), "no such host")
+            .is_err());
+    }
+
     #[test]
     pub fn test_receive_block_msg_twice() {
         let mut harness = TestShipper::new();
@@ -608,6 +1108,13 @@ mod tests {
         let store = &mut transmitter._storage;
         // Import test file into transmitter storage
         let test_file_cid = store.import_path(&PathBuf::from(test_file_path)).unwrap();
+        // As if the receiver's Version reply said it understands bitmaps
+        transmitter
+            .shipper
+            .bitmap_peers
+            .lock()
+            .unwrap()
+            .insert(receiver.listen_addr.clone());
 
         transmitter
             .shipper
@@ -636,6 +1143,17 @@ mod tests {
             }
         }
         assert_eq!(messages_received, 2);
+        // The receiver answered the window's request with a bitmap, nothing missing
+        let window_report = transmitter.recv_msg().unwrap();
+        assert_eq!(
+            window_report,
+            Message::DataProtocol(DataProtocol::MissingDagBlocksBitmap {
+                cid: test_file_cid.to_owned(),
+                offset: 0,
+                known: 1,
+                missing: vec![0],
+            })
+        );
         // Verify all blocks made it across
         receiver
             .shipper
@@ -649,7 +1167,635 @@ mod tests {
         let missing_blocks_msg = transmitter.recv_msg().unwrap();
         assert_eq!(
             missing_blocks_msg,
-            Message::missing_dag_blocks(&test_file_cid, vec![])
+            Message::ApplicationAPI(messages::ApplicationAPI::MissingDagBlocks {
+                cid: test_file_cid,
+                blocks: vec![]
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_dag_transmit_to_peer_without_bitmaps() {
+        let mut transmitter = TestShipper::new();
+        let mut receiver = TestShipper::new();
+
+        // Enough blocks for several windows, small enough not to overrun the socket buffers
+        let mut data = vec![0u8; 256 * 12];
+        thread_rng().fill_bytes(&mut data);
+        let test_file = transmitter.test_dir.child("test.file");
+        test_file.write_binary(&data).unwrap();
+        let mut store = Storage::new(transmitter._storage.get_provider(), 256);
+        let test_file_cid = store.import_path(test_file.path()).unwrap();
+
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: test_file_cid.to_owned(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 0,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions[&test_file_cid].provider_order);
+        // Windows go back and forth, asked about by CID, until the transfer completes
+        let mut idle = 0;
+        loop {
+            match receiver.recv_msg() {
+                Ok(Message::DataProtocol(msg)) => {
+                    assert!(!matches!(
+                        msg,
+                        DataProtocol::RequestMissingDagWindowBitmap { .. }
+                    ));
+                    receiver
+                        .shipper
+                        .process_msg(msg, &transmitter.listen_addr)
+                        .unwrap();
+                }
+                Ok(Message::ApplicationAPI(
+                    messages::ApplicationAPI::DagTransmissionComplete { .. },
+                )) => break,
+                Ok(msg) => panic!("Unexpected message {msg:?}"),
+                Err(_) => {}
+            }
+            match transmitter.recv_msg() {
+                Ok(Message::DataProtocol(msg)) => transmitter
+                    .shipper
+                    .process_msg(msg, &receiver.listen_addr)
+                    .unwrap(),
+                Ok(msg) => panic!("Unexpected message {msg:?}"),
+                Err(_) => {
+                    idle += 1;
+                    assert!(idle < 100, "Transfer stalled");
+                }
+            }
+        }
+        let exported = receiver.test_dir.child("exported");
+        receiver
+            ._storage
+            .export_cid(&test_file_cid, exported.path())
+            .unwrap();
+        assert_eq!(std::fs::read(exported.path()).unwrap(), data);
+    }
+
+    #[test]
+    pub fn test_dag_transmit_leaves_out_inlined_blocks() {
+        let mut transmitter = TestShipper::new();
+        let mut receiver = TestShipper::new();
+
+        let mut data = vec![0u8; BLOCK_SIZE as usize + 10];
+        thread_rng().fill_bytes(&mut data);
+        let test_file = transmitter.test_dir.child("test.file");
+        test_file.write_binary(&data).unwrap();
+        let store = &mut transmitter._storage;
+        store.set_inline_limit(32).unwrap();
+        let test_file_cid = store.import_path(test_file.path()).unwrap();
+        // As if the receiver's Version reply said it understands bitmaps
+        transmitter
+            .shipper
+            .bitmap_peers
+            .lock()
+            .unwrap()
+            .insert(receiver.listen_addr.clone());
+
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: test_file_cid.to_owned(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 0,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        let mut blocks_received = 0;
+        loop {
+            match receiver.recv_msg() {
+                Ok(Message::DataProtocol(msg)) => {
+                    if matches!(msg, DataProtocol::Block(_)) {
+                        blocks_received += 1;
+                    }
+                    receiver
+                        .shipper
+                        .process_msg(msg, &transmitter.listen_addr)
+                        .unwrap();
+                }
+                Err(transports::TransportError::TimedOut) => break,
+                Ok(msg) => panic!("Unexpected message {msg:?}"),
+                Err(err) => panic!("Unexpected error {err:?}"),
+            }
+        }
+        // The root and the full chunk, but not the tail inlined into the root
+        assert_eq!(blocks_received, 2);
+        let window_report = transmitter.recv_msg().unwrap();
+        assert_eq!(
+            window_report,
+            Message::DataProtocol(DataProtocol::MissingDagBlocksBitmap {
+                cid: test_file_cid.to_owned(),
+                offset: 0,
+                known: 3,
+                missing: vec![0],
+            })
         );
+        let exported = receiver.test_dir.child("exported");
+        receiver
+            ._storage
+            .export_cid(&test_file_cid, exported.path())
+            .unwrap();
+        assert_eq!(std::fs::read(exported.path()).unwrap(), data);
+    }
+
+    #[test]
+    pub fn test_cancel_dag_transmit() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr,
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions.contains_key(&cid));
+
+        transmitter
+            .shipper
+            .process_msg(DataProtocol::CancelTransmitDag { cid }, "127.0.0.1:0")
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions.is_empty());
+    }
+
+    #[test]
+    pub fn test_expired_dag_transmit_dropped() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        let request = DataProtocol::RequestTransmitDag {
+            cid: cid.clone(),
+            target_addr: receiver.listen_addr,
+            retries: 5,
+        };
+        transmitter
+            .shipper
+            .process_msg(request.clone(), "127.0.0.1:0")
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions.contains_key(&cid));
+
+        transmitter._storage.set_ttl(&cid, 0).unwrap();
+        transmitter
+            .shipper
+            .process_msg(DataProtocol::RetryDagSession { cid }, "127.0.0.1:0")
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions.is_empty());
+
+        transmitter
+            .shipper
+            .process_msg(request, "127.0.0.1:0")
+            .unwrap();
+        assert!(transmitter.shipper.window_sessions.is_empty());
+    }
+
+    #[test]
+    pub fn test_pause_then_resume_dag_transmit() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::PauseTransmitDag { cid: cid.clone() },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+
+        // A paused session must not advance, even when told the window was fully received
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::MissingDagBlocks {
+                    cid: cid.clone(),
+                    blocks: vec![],
+                },
+                &receiver.listen_addr,
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert!(matches!(session.mode, SessionMode::Paused));
+        assert_eq!(session.window_num, 0);
+
+        // Reconnecting resumes everything except explicitly paused sessions
+        transmitter
+            .shipper
+            .process_msg(DataProtocol::ResumeTransmitAllDags, "127.0.0.1:0")
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert!(matches!(session.mode, SessionMode::Paused));
+
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::ResumeTransmitDag { cid: cid.clone() },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert!(matches!(session.mode, SessionMode::Resuming));
+    }
+
+    #[test]
+    pub fn test_higher_priority_dag_preempts_transmit() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        let low_cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(transmitter.generate_file().unwrap()))
+            .unwrap();
+        let high_cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(transmitter.generate_file().unwrap()))
+            .unwrap();
+        transmitter._storage.set_priority(&high_cid, 9).unwrap();
+
+        for cid in [&low_cid, &high_cid] {
+            transmitter
+                .shipper
+                .process_msg(
+                    DataProtocol::RequestTransmitDag {
+                        cid: cid.to_string(),
+                        target_addr: receiver.listen_addr.to_owned(),
+                        retries: 5,
+                    },
+                    "127.0.0.1:0",
+                )
+                .unwrap();
+        }
+
+        // The low priority session must hold off on its next window
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::MissingDagBlocks {
+                    cid: low_cid.clone(),
+                    blocks: vec![],
+                },
+                &receiver.listen_addr,
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&low_cid).unwrap();
+        assert!(session.deferred);
+        assert_eq!(session.window_num, 0);
+
+        // Once the high priority session is gone the deferred one picks back up
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::CancelTransmitDag { cid: high_cid },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&low_cid).unwrap();
+        assert!(!session.deferred);
+        assert!(matches!(session.mode, SessionMode::Resuming));
+    }
+
+    #[test]
+    pub fn test_deferred_dag_waits_for_higher_priority() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+        let mut cids = Vec::new();
+        for priority in [0, 9] {
+            let cid = transmitter
+                ._storage
+                .import_path(&PathBuf::from(transmitter.generate_file().unwrap()))
+                .unwrap();
+            transmitter._storage.set_priority(&cid, priority).unwrap();
+            transmitter
+                .shipper
+                .process_msg(
+                    DataProtocol::RequestTransmitDag {
+                        cid: cid.clone(),
+                        target_addr: receiver.listen_addr.to_owned(),
+                        retries: 1,
+                    },
+                    "127.0.0.1:0",
+                )
+                .unwrap();
+            cids.push(cid);
+        }
+        let (low_cid, high_cid) = (&cids[0], &cids[1]);
+        assert!(transmitter.shipper.defer_if_preempted(low_cid));
+
+        // Even once the higher priority session has run out of retries
+        for _ in 0..4 {
+            transmitter
+                .shipper
+                .process_msg(
+                    DataProtocol::RetryDagSession {
+                        cid: high_cid.clone(),
+                    },
+                    "127.0.0.1:0",
+                )
+                .unwrap();
+        }
+        assert!(transmitter.shipper.window_sessions[low_cid].deferred);
+        assert!(transmitter.shipper.defer_if_preempted(low_cid));
+    }
+
+    #[test]
+    pub fn test_sessions_survive_restart() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(!transmitter.shipper.dirty);
+        // Moving through the DAG is written out along with everything else
+        transmitter.shipper.next_dag_window_session(&cid);
+        assert!(transmitter.shipper.dirty);
+        transmitter.shipper.retry_dag_window_session(&cid).unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::SetPriority {
+                    cid: cid.clone(),
+                    priority: 3,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(!transmitter.shipper.dirty);
+        let offset = transmitter.shipper.window_sessions[&cid].offset;
+
+        let (sender, receiver_chan) = mpsc::channel();
+        let restarted = Shipper::new(
+            transmitter._storage.get_provider(),
+            receiver_chan,
+            sender,
+            10,
+            5,
+            Arc::clone(&transmitter.listen_transport),
+            Arc::new(Mutex::new(true)),
+            BLOCK_SIZE,
+            None,
+            0,
+            Arc::default(),
+            Arc::default(),
+        )
+        .unwrap();
+        let session = restarted.window_sessions.get(&cid).unwrap();
+        assert!(matches!(session.mode, SessionMode::Resuming));
+        assert_eq!(session.window_num, 1);
+        assert_eq!(session.offset, offset);
+        assert_eq!(session.remaining_window_retries, session.max_retries - 1);
+        assert_eq!(session.priority, 3);
+        assert_eq!(session.target_addr, receiver.listen_addr);
+        assert_eq!(restarted.next_seq, 1);
+    }
+
+    #[test]
+    pub fn test_window_adapts_to_loss() {
+        let mut transmitter = TestShipper::new();
+        let receiver = TestShipper::new();
+
+        // Enough blocks for several windows
+        let mut data = vec![0u8; BLOCK_SIZE as usize * 20];
+        thread_rng().fill_bytes(&mut data);
+        let test_file = transmitter.test_dir.child("big.file");
+        test_file.write_binary(&data).unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file.path()))
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert_eq!(session.window_size, 5);
+        assert!(session.awaiting_since.is_some());
+
+        // A clean window grows the next one and yields a round-trip measurement
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::MissingDagBlocks {
+                    cid: cid.clone(),
+                    blocks: vec![],
+                },
+                &receiver.listen_addr,
+            )
+            .unwrap();
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert_eq!(session.window_size, 6);
+        assert!(session.srtt_ms > 0);
+        assert!(session.retry_timeout_ms <= 10);
+
+        // Loss halves it, but only once per window
+        for _ in 0..2 {
+            transmitter
+                .shipper
+                .process_msg(
+                    DataProtocol::MissingDagBlocks {
+                        cid: cid.clone(),
+                        blocks: vec![cid.clone()],
+                    },
+                    &receiver.listen_addr,
+                )
+                .unwrap();
+        }
+        let session = transmitter.shipper.window_sessions.get(&cid).unwrap();
+        assert_eq!(session.window_size, 3);
+
+        let params = transmitter.shipper.session_params.lock().unwrap().clone();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].window_size, 3);
+    }
+
+    #[test]
+    pub fn test_missing_blocks_digest_fallback() {
+        let mut transmitter = TestShipper::new();
+        let mut receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+
+        // A receiver which couldn't place the window in the dag gets asked again by digest
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::MissingDagBlocksBitmap {
+                    cid,
+                    offset: 0,
+                    known: 0,
+                    missing: vec![],
+                },
+                &receiver.listen_addr,
+            )
+            .unwrap();
+        let request = loop {
+            match receiver.recv_msg().unwrap() {
+                Message::DataProtocol(m @ DataProtocol::RequestMissingDagWindowDigests { .. }) => {
+                    break m
+                }
+                Message::DataProtocol(_) => continue,
+                other => panic!("Unexpected message {other:?}"),
+            }
+        };
+        receiver
+            .shipper
+            .process_msg(request, &transmitter.listen_addr)
+            .unwrap();
+        match transmitter.recv_msg().unwrap() {
+            Message::DataProtocol(DataProtocol::MissingDagBlocksBitmap { known, .. }) => {
+                assert_eq!(known, 1)
+            }
+            other => panic!("Unexpected message {other:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_window_request_form_follows_peer() {
+        let mut transmitter = TestShipper::new();
+        let mut receiver = TestShipper::new();
+
+        let test_file_path = transmitter.generate_file().unwrap();
+        let cid = transmitter
+            ._storage
+            .import_path(&PathBuf::from(test_file_path))
+            .unwrap();
+        let next_request = |receiver: &mut TestShipper| loop {
+            match receiver.recv_msg().unwrap() {
+                Message::DataProtocol(
+                    m @ (DataProtocol::RequestMissingDagWindowBlocks { .. }
+                    | DataProtocol::RequestMissingDagWindowBitmap { .. }),
+                ) => break m,
+                Message::DataProtocol(_) => continue,
+                other => panic!("Unexpected message {other:?}"),
+            }
+        };
+
+        // A peer that hasn't said it understands bitmaps is asked by CID
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(matches!(
+            next_request(&mut receiver),
+            DataProtocol::RequestMissingDagWindowBlocks { .. }
+        ));
+
+        // Once it has, a session opened after that is asked by bitmap
+        transmitter
+            .shipper
+            .bitmap_peers
+            .lock()
+            .unwrap()
+            .insert(receiver.listen_addr.clone());
+        transmitter.shipper.dag_window_session_run(&cid).unwrap();
+        assert!(matches!(
+            next_request(&mut receiver),
+            DataProtocol::RequestMissingDagWindowBlocks { .. }
+        ));
+        transmitter.shipper.end_dag_window_session(&cid);
+        transmitter
+            .shipper
+            .process_msg(
+                DataProtocol::RequestTransmitDag {
+                    cid: cid.clone(),
+                    target_addr: receiver.listen_addr.to_owned(),
+                    retries: 5,
+                },
+                "127.0.0.1:0",
+            )
+            .unwrap();
+        assert!(matches!(
+            next_request(&mut receiver),
+            DataProtocol::RequestMissingDagWindowBitmap { .. }
+        ));
+
+        // Unless that went unanswered
+        sleep(Duration::from_millis(20));
+        transmitter.shipper.check_retry_timeout(&cid);
+        transmitter.shipper.dag_window_session_run(&cid).unwrap();
+        assert!(matches!(
+            next_request(&mut receiver),
+            DataProtocol::RequestMissingDagWindowBlocks { .. }
+        ));
     }
 }
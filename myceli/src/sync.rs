@@ -1,31 +1,101 @@
 use anyhow::{bail, Result};
 use cid::multihash::MultihashDigest;
 use cid::{Cid, Version};
-use ipfs_unixfs::{codecs::Codec, parse_links};
+use ipfs_unixfs::{codecs::Codec, parse_links, DEFAULT_HASH};
 use libipld::{prelude::Codec as _, Ipld, IpldCodec};
-use local_storage::block::StoredBlock;
+use local_storage::block::{is_inlined, StoredBlock};
 use local_storage::storage::Storage;
 use log::{debug, error, info, trace, warn};
 use messages::cid_list::CompactList;
-use messages::{cid_list, Message, SyncMessage, PUSH_OVERHEAD};
-use parity_scale_codec::Encode;
+use messages::{cid_list, Message, PushMessage, SummaryMessage, SyncMessage, PUSH_OVERHEAD};
+use parity_scale_codec::{Decode, Encode};
 use std::{
-    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     iter,
     iter::IntoIterator,
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 type ByMeta = BTreeMap<cid_list::Meta, ToSend>;
 
+//Keys under which each peer's queues, and the list of peers, are persisted in local storage
+const STATE_KEY_PREFIX: &str = "sync@";
+const PEERS_KEY: &str = "sync_peers";
+//How often sync state may be written out at most, to spare the flash
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+//Anyone who sends us a Sync message gets state kept for them, so bound how many and for how long
+const MAX_PEERS: usize = 8;
+const PEER_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+//Each remote gets its own queues and acks, so one ground station acking a CID doesn't stop it
+//  being pushed to another.
+#[derive(Default)]
+pub(crate) struct SyncPeers {
+    mtu: usize,
+    peers: BTreeMap<String, SyncPeer>,
+    //Never dropped to make room for others, e.g. the configured radio
+    keep: Option<String>,
+    //Peers whose Version said they understand Summary messages, kept apart from their state so
+    //  it applies to state created for them later too
+    summary_peers: BTreeSet<String>,
+    //Peers dropped since the last save, whose saved state is yet to be cleared
+    dropped: Vec<String>,
+    //Set when the list of peers changed since the last save
+    addrs_dirty: bool,
+    saved_at: Option<Instant>,
+}
+
+pub(crate) struct SyncPeer {
+    pub sync: Syncer,
+    //Pushes sent, sync messages received, turns given to the remote to talk
+    pub counts: [u64; 3],
+    //Last time a Sync message came from it, or when its state was created/loaded
+    pub heard: Instant,
+}
+
 #[derive(Default)]
 pub(crate) struct Syncer {
     pull: ByMeta,
     push: ByMeta,
     mtu: usize,
     ready: VecDeque<Message>,
-    pending_names: Vec<(Cid, String)>,
+    pending_names: VecDeque<(Cid, String)>,
     names: HashMap<Cid, String>,
+    //Priority of each CID, inherited from the DAG(s) it belongs to. Absent means 0.
+    priority: HashMap<Cid, u8>,
+    //CIDs taken off the push queues when their DAG was paused, by root, to put back on resume
+    paused: HashMap<Cid, Vec<Cid>>,
+    //Ticks each time a CID is queued, so equal priorities go out oldest first across queues
+    clock: u64,
+    //Set when something worth persisting changed since the last save
+    dirty: bool,
+    state_key: String,
+    //Multihash of the CIDs this peer last told us of, which its blocks are addressed by
+    hash: Option<u64>,
+    //Whether the peer's Version said it understands Summary messages
+    summaries: bool,
+}
+
+//What gets persisted, so that after a restart we don't re-push what the remote already has
+#[derive(Encode, Decode)]
+struct SavedState {
+    pull: Vec<SavedQueue>,
+    push: Vec<SavedQueue>,
+    //CIDs of the pushes and pulls that were built but not yet sent, which get rebuilt on restore
+    ready_push: Vec<Vec<u8>>,
+    ready_pull: Vec<Vec<u8>>,
+    pending_names: Vec<(Vec<u8>, String)>,
+    names: Vec<(Vec<u8>, String)>,
+    paused: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+}
+
+#[derive(Encode, Decode)]
+struct SavedQueue {
+    hi: Vec<Vec<u8>>,
+    lo: Vec<Vec<u8>>,
+    acked: Vec<(Vec<u8>, u8)>,
 }
 
 #[derive(Clone, Copy)]
@@ -34,9 +104,154 @@ enum Side {
     Pull,
 }
 
+impl SyncPeers {
+    //Peers we were syncing with before a restart pick back up with their own saved state
+    pub fn load(mtu: usize, keep: Option<String>, store: &Storage) -> Result<Self> {
+        let mut result = Self {
+            mtu,
+            keep,
+            ..Default::default()
+        };
+        if let Some(bytes) = store.load_state(PEERS_KEY)? {
+            for addr in Vec::<String>::decode(&mut bytes.as_slice())? {
+                result.get(&addr, store)?;
+            }
+        }
+        result.addrs_dirty = false;
+        Ok(result)
+    }
+    //A new peer beyond MAX_PEERS takes the place of the least recently heard one
+    pub fn get(&mut self, addr: &str, store: &Storage) -> Result<&mut SyncPeer> {
+        if !self.peers.contains_key(addr) {
+            if self.peers.len() >= MAX_PEERS {
+                let oldest = self
+                    .peers
+                    .iter()
+                    .filter(|(a, _)| Some(*a) != self.keep.as_ref())
+                    .min_by_key(|(_, p)| p.heard)
+                    .map(|(a, _)| a.clone());
+                if let Some(oldest) = oldest {
+                    self.drop_peer(&oldest);
+                }
+            }
+            let mut sync = Self::create(self.mtu, addr, store)?;
+            sync.set_summaries(self.summary_peers.contains(addr));
+            info!("Keeping sync state for {addr}");
+            self.peers.insert(
+                addr.to_owned(),
+                SyncPeer {
+                    sync,
+                    counts: [0; 3],
+                    heard: Instant::now(),
+                },
+            );
+            self.dropped.retain(|a| a != addr);
+            self.addrs_dirty = true;
+        }
+        Ok(self.peers.get_mut(addr).expect("peer was just added"))
+    }
+    //Drop the state of peers not heard from in PEER_EXPIRY. Returns every peer dropped since the
+    //  last save, including those that made room for new ones.
+    pub fn expire(&mut self) -> Vec<String> {
+        let expired: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(a, p)| Some(*a) != self.keep.as_ref() && p.heard.elapsed() > PEER_EXPIRY)
+            .map(|(a, _)| a.clone())
+            .collect();
+        for addr in &expired {
+            self.drop_peer(addr);
+        }
+        self.dropped.clone()
+    }
+    fn drop_peer(&mut self, addr: &str) {
+        info!("Dropping sync state for {addr}");
+        if self.peers.remove(addr).is_some() && !self.dropped.iter().any(|a| a == addr) {
+            self.dropped.push(addr.to_owned());
+        }
+        self.addrs_dirty = true;
+    }
+    //Of these addresses, those already kept state for, then others only while there's room for
+    //  them, so visiting them all doesn't keep dropping one peer to make room for the next
+    pub fn active<'a, I: IntoIterator<Item = &'a String>>(&self, addrs: I) -> Vec<String> {
+        let (kept, others): (Vec<&String>, Vec<&String>) =
+            addrs.into_iter().partition(|a| self.peers.contains_key(*a));
+        let room = MAX_PEERS.saturating_sub(self.peers.len());
+        kept.into_iter()
+            .chain(others.into_iter().take(room))
+            .cloned()
+            .collect()
+    }
+    pub fn understands_summaries(&mut self, addr: &str) {
+        self.summary_peers.insert(addr.to_owned());
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.sync.set_summaries(true);
+        }
+    }
+    pub fn for_each<F: FnMut(&mut Syncer) -> Result<()>>(&mut self, mut f: F) -> Result<()> {
+        for peer in self.peers.values_mut() {
+            f(&mut peer.sync)?;
+        }
+        Ok(())
+    }
+    //Persist what changed, unless that was done less than SAVE_INTERVAL ago
+    pub fn save(&mut self, store: &Storage) -> Result<()> {
+        if self.saved_at.is_some_and(|t| t.elapsed() < SAVE_INTERVAL) {
+            return Ok(());
+        }
+        self.saved_at = Some(Instant::now());
+        for addr in std::mem::take(&mut self.dropped) {
+            store.delete_state(&format!("{STATE_KEY_PREFIX}{addr}"))?;
+        }
+        if self.addrs_dirty {
+            let addrs: Vec<&String> = self.peers.keys().collect();
+            store.save_state(PEERS_KEY, &addrs.encode())?;
+            self.addrs_dirty = false;
+        }
+        self.for_each(|s| s.save(store))
+    }
+    fn create(mtu: usize, peer: &str, store: &Storage) -> Result<Syncer> {
+        let dags = store.list_available_dags()?;
+        let missing_blocks = store.get_provider().lock().unwrap().get_dangling_cids()?;
+        let present_blocks = dags.iter().flat_map(|(c, n)| {
+            let c = Cid::try_from(c.as_str())?;
+            Ok::<_, cid::Error>((c, n.clone()))
+        });
+        match Syncer::restore(mtu, peer, store) {
+            Ok(Some(mut result)) => {
+                //Saves are throttled, so the store may have moved on since
+                result.catch_up(present_blocks, missing_blocks)?;
+                Self::prioritize_dags(&mut result, &dags, store)?;
+                return Ok(result);
+            }
+            Ok(None) => info!("No saved sync state for {peer}, rebuilding it from storage."),
+            Err(e) => warn!(
+                "Unable to restore saved sync state for {peer}, rebuilding it from storage: {e:?}"
+            ),
+        }
+        let mut result = Syncer::new(mtu, peer, present_blocks, missing_blocks)?;
+        Self::prioritize_dags(&mut result, &dags, store)?;
+        Ok(result)
+    }
+    fn prioritize_dags(
+        sync: &mut Syncer,
+        dags: &[(String, String)],
+        store: &Storage,
+    ) -> Result<()> {
+        for (cid, _) in dags {
+            let priority = store.get_priority(cid);
+            if priority > 0 {
+                sync.prioritize(cid, priority, store)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Syncer {
     pub fn new<I: IntoIterator<Item = (Cid, String)>, J: IntoIterator<Item = Cid>>(
         mtu: usize,
+        peer: &str,
         known_knowns: I,
         known_unknowns: J,
     ) -> Result<Self> {
@@ -45,14 +260,21 @@ impl Syncer {
             push: ByMeta::default(),
             mtu,
             ready: VecDeque::default(),
-            pending_names: Vec::default(),
+            pending_names: VecDeque::default(),
             names: HashMap::default(),
+            priority: HashMap::default(),
+            paused: HashMap::default(),
+            clock: 0,
+            dirty: true,
+            state_key: format!("{STATE_KEY_PREFIX}{peer}"),
+            hash: None,
+            summaries: false,
         };
         for (cid, name) in known_knowns {
             result.will_push(&cid, None)?;
             if !name.is_empty() {
                 result.names.insert(cid, name.clone());
-                result.pending_names.push((cid, name));
+                result.pending_names.push_back((cid, name));
             }
         }
         for cid in known_unknowns {
@@ -60,6 +282,177 @@ impl Syncer {
         }
         Ok(result)
     }
+    //Pick back up from whatever was last saved, if anything was
+    pub fn restore(mtu: usize, peer: &str, store: &Storage) -> Result<Option<Self>> {
+        let state_key = format!("{STATE_KEY_PREFIX}{peer}");
+        let bytes = match store.load_state(&state_key)? {
+            Some(b) if !b.is_empty() => b,
+            _ => return Ok(None),
+        };
+        let state = SavedState::decode(&mut bytes.as_slice())?;
+        let named = |v: Vec<(Vec<u8>, String)>| {
+            v.into_iter()
+                .map(|(c, n)| Ok((Cid::try_from(c)?, n)))
+                .collect::<Result<Vec<_>>>()
+        };
+        //What was about to go out is queued as older than the rest, in the order it was in
+        let mut clock = state.ready_push.len().max(state.ready_pull.len()) as u64;
+        let mut result = Self {
+            pull: Self::restore_side(state.pull, &mut clock)?,
+            push: Self::restore_side(state.push, &mut clock)?,
+            mtu,
+            ready: VecDeque::default(),
+            pending_names: named(state.pending_names)?.into(),
+            names: named(state.names)?.into_iter().collect(),
+            priority: HashMap::default(),
+            paused: state
+                .paused
+                .into_iter()
+                .map(|(r, v)| {
+                    let cids = v.into_iter().map(Cid::try_from);
+                    Ok((Cid::try_from(r)?, cids.collect::<Result<_, _>>()?))
+                })
+                .collect::<Result<_>>()?,
+            clock,
+            dirty: false,
+            state_key,
+            hash: None,
+            summaries: false,
+        };
+        //Whatever was about to go out goes out first again
+        for (side, cids) in [
+            (&mut result.push, state.ready_push),
+            (&mut result.pull, state.ready_pull),
+        ] {
+            for (at, cid) in cids.into_iter().enumerate().rev() {
+                Self::requeue(side, &Cid::try_from(cid)?, at as u64)?;
+            }
+        }
+        info!(
+            "Restored sync state for {peer}: {} pending names",
+            result.pending_names.len()
+        );
+        Ok(Some(result))
+    }
+    //Write the current state to storage, if it changed since last time
+    pub fn save(&mut self, store: &Storage) -> Result<()> {
+        if self.dirty {
+            store.save_state(&self.state_key, &self.snapshot().encode())?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+    fn snapshot(&self) -> SavedState {
+        let named = |v: &mut dyn Iterator<Item = (&Cid, &String)>| {
+            v.map(|(c, n)| (c.to_bytes(), n.clone())).collect()
+        };
+        SavedState {
+            pull: Self::snapshot_side(&self.pull),
+            push: Self::snapshot_side(&self.push),
+            ready_push: self.ready_cids(true),
+            ready_pull: self.ready_cids(false),
+            pending_names: named(&mut self.pending_names.iter().map(|(c, n)| (c, n))),
+            names: named(&mut self.names.iter()),
+            paused: self
+                .paused
+                .iter()
+                .map(|(r, v)| (r.to_bytes(), v.iter().map(|c| c.to_bytes()).collect()))
+                .collect(),
+        }
+    }
+    fn ready_cids(&self, push: bool) -> Vec<Vec<u8>> {
+        let mut result = Vec::new();
+        for m in &self.ready {
+            let cids = match m {
+                Message::Sync(SyncMessage::Push(pm)) if push => &pm.cids,
+                Message::Sync(SyncMessage::Pull(l)) if !push => l,
+                _ => continue,
+            };
+            result.extend(cids.into_iter().map(|c| c.to_bytes()));
+        }
+        result
+    }
+    fn requeue(side: &mut ByMeta, cid: &Cid, at: u64) -> Result<()> {
+        let s = side.entry(cid.try_into()?).or_default();
+        if !s.hi.contains(cid) {
+            s.hi.push_front(*cid);
+        }
+        s.queued_at.insert(*cid, at);
+        if !s.lo.contains(cid) {
+            s.lo.push_back(*cid);
+        }
+        Ok(())
+    }
+    fn snapshot_side(side: &ByMeta) -> Vec<SavedQueue> {
+        let bytes = |q: &VecDeque<Cid>| q.iter().map(|c| c.to_bytes()).collect();
+        side.values()
+            .map(|s| SavedQueue {
+                hi: bytes(&s.hi),
+                lo: bytes(&s.lo),
+                acked: s.acked.iter().map(|(c, n)| (c.to_bytes(), *n)).collect(),
+            })
+            .collect()
+    }
+    //Queue what's in the store but not in the restored state, as new would have
+    fn catch_up<I: IntoIterator<Item = (Cid, String)>, J: IntoIterator<Item = Cid>>(
+        &mut self,
+        known_knowns: I,
+        known_unknowns: J,
+    ) -> Result<()> {
+        let paused: HashSet<Cid> = self
+            .paused
+            .iter()
+            .flat_map(|(r, v)| iter::once(*r).chain(v.iter().copied()))
+            .collect();
+        for (cid, name) in known_knowns {
+            if Self::knows(&self.push, &cid) || paused.contains(&cid) {
+                continue;
+            }
+            debug!("Catching up on {cid}, which is newer than the saved sync state");
+            self.will_push(&cid, None)?;
+            if !name.is_empty() && !self.names.contains_key(&cid) {
+                self.names.insert(cid, name.clone());
+                self.pending_names.push_back((cid, name));
+            }
+        }
+        for cid in known_unknowns {
+            if !Self::knows(&self.pull, &cid) {
+                self.will_pull(&cid)?;
+            }
+        }
+        Ok(())
+    }
+    fn knows(side: &ByMeta, cid: &Cid) -> bool {
+        side.values()
+            .any(|s| s.hi.contains(cid) || s.lo.contains(cid) || s.acked.contains_key(cid))
+    }
+    fn restore_side(queues: Vec<SavedQueue>, clock: &mut u64) -> Result<ByMeta> {
+        let cids = |v: Vec<Vec<u8>>| {
+            v.into_iter()
+                .map(Cid::try_from)
+                .collect::<std::result::Result<VecDeque<_>, _>>()
+        };
+        let mut result = ByMeta::default();
+        for q in queues {
+            let hi = cids(q.hi)?;
+            let s = ToSend {
+                queued_at: hi.iter().map(|c| (*c, tick(clock))).collect(),
+                hi,
+                lo: cids(q.lo)?,
+                acked: q
+                    .acked
+                    .into_iter()
+                    .map(|(c, n)| Ok((Cid::try_from(c)?, n)))
+                    .collect::<Result<_>>()?,
+            };
+            let meta = match s.hi.iter().chain(s.lo.iter()).chain(s.acked.keys()).next() {
+                Some(cid) => cid.try_into()?,
+                None => continue,
+            };
+            result.insert(meta, s);
+        }
+        Ok(result)
+    }
     pub fn push_dag(
         &mut self,
         root: &StoredBlock,
@@ -71,7 +464,8 @@ impl Syncer {
         let mut linked_cids = Vec::default();
         let mut root_push = None;
         if let Some(name) = &root.filename {
-            self.pending_names.push((root_cid, name.clone()));
+            self.dirty = true;
+            self.pending_names.push_back((root_cid, name.clone()));
             self.names.insert(root_cid, name.clone());
             let mut list = CompactList::try_from(&root_cid)?;
             let size = self.mtu - messages::PUSH_OVERHEAD - root.filename.encoded_size();
@@ -91,8 +485,67 @@ impl Syncer {
             linked_cids.push(root_cid);
         }
         self.will_push(&root_cid, Some(store))?;
+        //Otherwise the remote learns of the DAG's CIDs as they come around in Pushes
+        if self.summaries {
+            if let Some(m) = self.summarize(&root_cid, store)? {
+                self.ready.push_back(m);
+            }
+        }
+        let priority = store.get_priority(&root.cid);
+        if priority > 0 {
+            self.prioritize(&root.cid, priority, store)?;
+        }
         Ok(root_push)
     }
+    pub fn set_summaries(&mut self, understood: bool) {
+        self.summaries = understood;
+    }
+    //Everything in the DAG gets scheduled ahead of anything with a lower priority.
+    pub fn prioritize(&mut self, root: &str, priority: u8, store: &Storage) -> Result<()> {
+        let root_cid = Cid::try_from(root)?;
+        let dag = store
+            .get_all_dag_cids(root, None, None)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|c| Cid::try_from(c.as_str()))
+            .chain(iter::once(root_cid))
+            .collect::<Vec<_>>();
+        debug!("Prioritizing {} CIDs of {root} at {priority}", dag.len());
+        for cid in dag {
+            if priority == 0 {
+                self.priority.remove(&cid);
+            } else {
+                self.priority.insert(cid, priority);
+            }
+        }
+        Ok(())
+    }
+    //One message describing the whole DAG, so the remote can work out what it lacks without
+    //  waiting for every CID to come around in a Push. Not worth it for a lone block.
+    fn summarize(&self, root: &Cid, store: &Storage) -> Result<Option<Message>> {
+        let cids = Self::held_dag_cids(root, store)?;
+        if cids.len() < 2 {
+            return Ok(None);
+        }
+        let summary = SummaryMessage::new(root, &cids, self.mtu)?;
+        debug!("Summarizing {} CIDs of {root} as {summary:?}", cids.len());
+        Ok(Some(Message::summary(summary)))
+    }
+    fn held_dag_cids(root: &Cid, store: &Storage) -> Result<Vec<Cid>> {
+        let mut seen = HashSet::new();
+        let result = iter::once(*root)
+            .chain(
+                store
+                    .get_all_dag_cids(&root.to_string(), None, None)?
+                    .iter()
+                    .flat_map(|c| Cid::try_from(c.as_str())),
+            )
+            .filter(|c| {
+                seen.insert(*c) && ipfs_unixfs::inlined_data(c).is_none() && store.has_cid(c)
+            })
+            .collect();
+        Ok(result)
+    }
     pub fn push_dag_blocks(
         &mut self,
         root: &str,
@@ -105,8 +558,8 @@ impl Syncer {
         } else {
             None
         };
-        for link in block.links {
-            match self.push_dag_blocks(&link, store, pushed) {
+        for link in block.links.iter().filter(|l| !is_inlined(l)) {
+            match self.push_dag_blocks(link, store, pushed) {
                 Ok(Some(cm)) => self.ready.push_back(cm),
                 Ok(None) => trace!("Duplicate child in DAG {link}"),
                 Err(e) => warn!("Sending DAG which is incomplete: {root} -> {link}: {e:?}"),
@@ -135,11 +588,17 @@ impl Syncer {
         Ok(result)
     }
     pub fn will_pull(&mut self, cid: &Cid) -> anyhow::Result<()> {
-        Self::add(&mut self.pull, cid)
+        self.dirty = true;
+        Self::add(&mut self.pull, cid, &mut self.clock)
     }
     pub fn will_push(&mut self, cid: &Cid, store: Option<&Storage>) -> anyhow::Result<()> {
         trace!("will_push({cid:?}, {}", store.is_some());
-        Self::add(&mut self.push, cid)?;
+        // Whoever has the link has the block it carries
+        if ipfs_unixfs::inlined_data(cid).is_some() {
+            return Ok(());
+        }
+        self.dirty = true;
+        Self::add(&mut self.push, cid, &mut self.clock)?;
         if let Some(p) = store.map(|s| s.get_provider()) {
             let links = if let Ok(p) = p.try_lock() {
                 p.get_links_by_cid(&cid.to_string())?
@@ -153,10 +612,119 @@ impl Syncer {
         Ok(())
     }
     pub fn stop_pulling(&mut self, cid: &Cid) {
-        Self::stop(&mut self.pull, cid);
+        self.dirty |= Self::stop(&mut self.pull, cid);
     }
     pub fn stop_pushing(&mut self, cid: &Cid) {
-        Self::stop(&mut self.push, cid);
+        self.dirty |= Self::stop(&mut self.push, cid);
+    }
+    //Still to be pushed, but only in low-priority rotation
+    fn demote(&mut self, cid: &Cid) {
+        if let Ok(Some(side)) = cid_list::Meta::try_from(cid).map(|m| self.push.get_mut(&m)) {
+            side.hi.retain(|c| c != cid);
+            side.queued_at.remove(cid);
+        }
+    }
+    //Drop every CID in the DAG from the push queues, e.g. because the operator cancelled or paused its transfer.
+    pub fn forget_dag(&mut self, root: &str, store: &Storage) -> Result<()> {
+        let (root_cid, _) = self.drop_dag(root, store)?;
+        self.paused.remove(&root_cid);
+        Ok(())
+    }
+    //Like forget_dag, but remember what was still to be pushed so resume_dag can pick it back up.
+    //Returns whether anything of the DAG was left to push.
+    pub fn pause_dag(&mut self, root: &str, store: &Storage) -> Result<bool> {
+        let (root_cid, dropped) = self.drop_dag(root, store)?;
+        if dropped.is_empty() {
+            return Ok(false);
+        }
+        self.paused.entry(root_cid).or_default().extend(dropped);
+        Ok(true)
+    }
+    //Returns whether anything of the DAG was left to push when it was paused
+    pub fn resume_dag(&mut self, root: &str) -> Result<bool> {
+        let root_cid = Cid::try_from(root)?;
+        let Some(cids) = self.paused.remove(&root_cid) else {
+            return Ok(false);
+        };
+        debug!("Resuming push of {} CIDs of DAG {root}", cids.len());
+        self.dirty = true;
+        for cid in &cids {
+            Self::add(&mut self.push, cid, &mut self.clock)?;
+        }
+        if let Some(name) = self.names.get(&root_cid) {
+            self.pending_names.push_back((root_cid, name.clone()));
+        }
+        Ok(true)
+    }
+    fn drop_dag(&mut self, root: &str, store: &Storage) -> Result<(Cid, Vec<Cid>)> {
+        let root_cid = Cid::try_from(root)?;
+        let mut dag: HashSet<Cid> = store
+            .get_all_dag_cids(root, None, None)?
+            .iter()
+            .flat_map(|c| Cid::try_from(c.as_str()))
+            .collect();
+        dag.insert(root_cid);
+        self.dirty = true;
+        let mut dropped = Vec::new();
+        for q in self.push.values_mut() {
+            let mut seen = HashSet::new();
+            dropped.extend(
+                q.hi.iter()
+                    .chain(q.lo.iter())
+                    .filter(|c| dag.contains(c) && seen.insert(**c)),
+            );
+            q.hi.retain(|c| !dag.contains(c));
+            q.queued_at.retain(|c, _| !dag.contains(c));
+            q.lo.retain(|c| !dag.contains(c));
+            q.acked.retain(|c, _| !dag.contains(c));
+        }
+        self.pending_names.retain(|(c, _)| *c != root_cid);
+        //A batched push may also carry CIDs of other DAGs, which still need to go out
+        self.ready = std::mem::take(&mut self.ready)
+            .into_iter()
+            .filter_map(|m| match m {
+                Message::Sync(SyncMessage::Push(pm)) => Self::push_without(pm, &dag),
+                m => Some(m),
+            })
+            .collect();
+        debug!("Stopped pushing {} CIDs of DAG {root}", dag.len());
+        Ok((root_cid, dropped))
+    }
+    fn push_without(pm: PushMessage, dag: &HashSet<Cid>) -> Option<Message> {
+        let cids: Vec<Cid> = pm.cids.into_iter().collect();
+        let keep: Vec<&Cid> = cids.iter().filter(|c| !dag.contains(c)).collect();
+        if keep.len() == cids.len() {
+            return Some(Message::Sync(SyncMessage::Push(pm)));
+        }
+        let first = **keep.first()?;
+        let mut list = CompactList::try_from(&first).ok()?;
+        for cid in &keep[1..] {
+            list.include(cid, usize::MAX);
+        }
+        //The name belongs to the first CID, so it only survives if that did
+        let name = if cids.first() == Some(&first) {
+            pm.first_cid_name
+        } else {
+            String::new()
+        };
+        Message::push(list, name).ok()
+    }
+    //An expired DAG is neither worth sending nor fetching any more of
+    pub fn expire_dag(&mut self, root: &str, store: &Storage) -> Result<()> {
+        self.forget_dag(root, store)?;
+        let mut missing = store.get_missing_dag_blocks(root).unwrap_or_default();
+        missing.push(root.to_owned());
+        let missing: HashSet<Cid> = missing
+            .iter()
+            .flat_map(|c| Cid::try_from(c.as_str()))
+            .collect();
+        for q in self.pull.values_mut() {
+            q.hi.retain(|c| !missing.contains(c));
+            q.queued_at.retain(|c, _| !missing.contains(c));
+            q.lo.retain(|c| !missing.contains(c));
+            q.acked.retain(|c, _| !missing.contains(c));
+        }
+        Ok(())
     }
     pub fn pop_pending_msg(&mut self, store: &Storage) -> Option<Message> {
         match self.ready.pop_front() {
@@ -177,12 +745,7 @@ impl Syncer {
         }
     }
     pub fn build_msg(&mut self, store: &mut Storage) -> Result<()> {
-        if let Some(c) = self
-            .pull
-            .iter_mut()
-            .flat_map(|(_, s)| s.hi.pop_front())
-            .next()
-        {
+        if let Some(c) = Self::pop_hi(&mut self.pull, &self.priority) {
             if !store.has_cid(&c) {
                 let v = self.pull_now(vec![c])?;
                 info!("Build: Will pull {v:?}");
@@ -191,7 +754,8 @@ impl Syncer {
                 }
             }
         }
-        if let Some((cid, name)) = self.pending_names.pop() {
+        if let Some((cid, name)) = self.pop_pending_name() {
+            self.dirty = true;
             let mut list = cid_list::CompactList::try_from(&cid)?;
             self.fill(
                 &mut list,
@@ -204,12 +768,7 @@ impl Syncer {
             }
             return Ok(());
         }
-        if let Some(c) = self
-            .push
-            .iter_mut()
-            .flat_map(|(_, s)| s.hi.pop_front())
-            .next()
-        {
+        if let Some(c) = Self::pop_hi(&mut self.push, &self.priority) {
             let v = self.push_now(vec![c])?;
             info!("Build: Will push {v:?}");
             self.ready.extend(v.into_iter());
@@ -217,6 +776,7 @@ impl Syncer {
         if self.ready.is_empty() {
             let size = self.mtu - PUSH_OVERHEAD;
             for q in self.push.values_mut() {
+                q.sort_lo(&self.priority);
                 let mut list = cid_list::CompactList::default();
                 if let Some(inc_cnt) = q.lo.iter().position(|c| !list.include(c, size)) {
                     info!(
@@ -233,6 +793,7 @@ impl Syncer {
             }
             let size = self.mtu;
             for q in self.pull.values_mut() {
+                q.sort_lo(&self.priority);
                 let mut list = cid_list::CompactList::default();
                 if let Some(inc_cnt) = q.lo.iter().position(|c| !list.include(c, size)) {
                     q.lo.rotate_left(inc_cnt);
@@ -248,6 +809,7 @@ impl Syncer {
 
     pub fn handle(&mut self, msg: SyncMessage, store: &mut Storage) -> Result<Option<Message>> {
         debug!("Sync::handle({msg:?})");
+        self.dirty = true;
         match msg {
             SyncMessage::Push(pm) => {
                 if !pm.check() {
@@ -282,6 +844,7 @@ impl Syncer {
                 Ok(result)
             }
             SyncMessage::Block(v) => self.handle_block(v, store),
+            SyncMessage::Summary(s) => self.handle_summary(s, store),
         }
     }
 
@@ -294,6 +857,9 @@ impl Syncer {
         let mut ack_resp = cid_list::CompactList::default();
         let mut pull_resp = cid_list::CompactList::default();
         for cid in cids.clone() {
+            if ipfs_unixfs::inlined_data(&cid).is_none() {
+                self.hash = Some(cid.hash().code());
+            }
             self.stop_pushing(&cid);
             if store.has_cid(&cid) {
                 self.stop_pulling(&cid);
@@ -326,7 +892,7 @@ impl Syncer {
         if !ack_resp.is_empty() {
             if let Some(m) = self.push.get_mut(&ack_resp.shared_traits()) {
                 //This isn't a real push, so don't mark those overflow CIDs as having been actually been pushed.
-                m.fill_cids(&mut ack_resp, self.mtu, false);
+                m.fill_cids(&mut ack_resp, self.mtu, None);
             }
             ack_msg = Some(Message::Sync(SyncMessage::Ack(ack_resp)));
         }
@@ -334,7 +900,7 @@ impl Syncer {
             ack_msg
         } else {
             if let Some(m) = self.pull.get_mut(&pull_resp.shared_traits()) {
-                m.fill_cids(&mut pull_resp, self.mtu, true);
+                m.fill_cids(&mut pull_resp, self.mtu, Some(&mut self.clock));
             }
             if let Some(am) = ack_msg {
                 self.ready.push_front(am);
@@ -343,6 +909,52 @@ impl Syncer {
         };
         Ok(resp)
     }
+    fn handle_summary(&mut self, sm: SummaryMessage, store: &Storage) -> Result<Option<Message>> {
+        if !sm.check() {
+            bail!("Summary with an unusable filter: {sm:?}");
+        }
+        let root = match sm.root() {
+            Some(c) => c,
+            None => bail!("No root in a Summary"),
+        };
+        let mut acks = Vec::new();
+        let mut pulls = Vec::new();
+        if store.has_cid(&root) {
+            //A bloom filter can be wrong about what the remote has, so what it claims only moves
+            //  to the back of the line until the remote acks it for real
+            for cid in Self::held_dag_cids(&root, store)? {
+                if sm.contains(&cid) {
+                    self.demote(&cid);
+                    acks.push(cid);
+                }
+            }
+            for miss in store.get_missing_dag_blocks(&root.to_string())? {
+                let cid = Cid::try_from(miss.as_str())?;
+                if sm.contains(&cid) {
+                    pulls.push(cid);
+                }
+            }
+        } else {
+            pulls.push(root);
+        }
+        debug!(
+            "Summary of {root}: will ack {} CIDs and pull {}",
+            acks.len(),
+            pulls.len()
+        );
+        let mut msgs = self.pull_now(pulls)?;
+        msgs.extend(
+            Self::pack(acks, self.mtu)?
+                .into_iter()
+                .map(|l| Message::Sync(SyncMessage::Ack(l))),
+        );
+        let mut msgs = msgs.into_iter();
+        let result = msgs.next();
+        for m in msgs.rev() {
+            self.ready.push_front(m);
+        }
+        Ok(result)
+    }
     fn handle_block(&mut self, bytes: Vec<u8>, store: &mut Storage) -> Result<Option<Message>> {
         let mut mhs = HashMap::new();
         let mut hit_cid: Option<Cid> = None;
@@ -386,52 +998,45 @@ impl Syncer {
             })?;
             self.stop_pulling(&cid);
         } else {
-            let hash = cid::multihash::Code::Sha2_256.digest(&bytes);
-            let mut cids = Vec::default();
-            if IpldCodec::DagPb
-                .references::<Ipld, _>(bytes.as_slice(), &mut cids)
-                .is_ok()
-            {
-                if !cids.is_empty() {
-                    result = self.handle_push("", cids.iter().cloned(), store);
-                }
-                let cid = Cid::new(Version::V1, Codec::DagPb.into(), hash)?;
-                let cid_s = cid.to_string();
-                let links = cids.into_iter().map(|c| c.to_string()).collect();
-                warn!("Received a block with no matching CID that was waiting for it. {} bytes in block. Importing it as a DAG-PB block, CID={cid_s}, links={links:?}. remaining dangling CIDs: {:?}", bytes.len(), self.pull);
-                store.import_block(&StoredBlock {
-                    cid: cid_s,
-                    filename: None,
-                    data: bytes,
-                    links,
-                })?;
-            } else {
-                let cid = Cid::new(Version::V1, Codec::Raw.into(), hash)?;
-                let cid_s = cid.to_string();
-                warn!("Received a block with no matching CID that was waiting for it. {} bytes in block. Importing it as a RAW block, CID={cid_s}. remaining dangling CIDs: {:?}", bytes.len(), self.pull);
-                store.import_block(&StoredBlock {
-                    cid: cid_s,
-                    filename: None,
-                    data: bytes,
-                    links: Vec::new(),
-                })?;
+            // Addressed the way the peer addresses its blocks, which needn't be how this node does
+            let code = self.hash.unwrap_or(DEFAULT_HASH.into());
+            let hash = cid::multihash::Code::try_from(code)?.digest(&bytes);
+            let (codec, cids) = guess_codec(&bytes);
+            if !cids.is_empty() {
+                result = self.handle_push("", cids.iter().cloned(), store);
             }
+            let cid = Cid::new(Version::V1, codec.into(), hash)?;
+            let cid_s = cid.to_string();
+            let links = cids.into_iter().map(|c| c.to_string()).collect();
+            warn!("Received a block with no matching CID that was waiting for it. {} bytes in block. Importing it as a {codec:?} block, CID={cid_s}, links={links:?}. remaining dangling CIDs: {:?}", bytes.len(), self.pull);
+            store.import_block(&StoredBlock {
+                cid: cid_s,
+                filename: None,
+                data: bytes,
+                links,
+            })?;
         }
         result
     }
 
     fn sending_now(
         &mut self,
-        mut cids: Vec<Cid>,
+        cids: Vec<Cid>,
         size: usize,
         side: Side,
     ) -> anyhow::Result<Vec<cid_list::CompactList>> {
+        let mut result = Self::pack(cids, size)?;
+        for list in &mut result {
+            self.fill(list, size, side)?;
+        }
+        Ok(result)
+    }
+    fn pack(mut cids: Vec<Cid>, size: usize) -> Result<Vec<cid_list::CompactList>> {
         let mut result = Vec::new();
         while !cids.is_empty() {
             let cid = cids.pop().unwrap();
             let mut list = cid_list::CompactList::try_from(&cid)?;
             cids.retain(|cid| !list.include(cid, size));
-            self.fill(&mut list, size, side)?;
             result.push(list);
         }
         Ok(result)
@@ -447,13 +1052,42 @@ impl Syncer {
             Side::Pull => &mut self.pull,
         };
         if let Some(state) = state_map.get_mut(&meta) {
-            state.fill_cids(list, size, true);
+            state.fill_cids(list, size, Some(&mut self.clock));
         }
         Ok(())
     }
-    fn add(side: &mut ByMeta, cid: &Cid) -> anyhow::Result<()> {
+    //Highest priority first, and among equals the one queued earliest
+    fn pop_hi(side: &mut ByMeta, priority: &HashMap<Cid, u8>) -> Option<Cid> {
+        let mut best: Option<((u8, Reverse<u64>), cid_list::Meta, usize)> = None;
+        for (meta, s) in side.iter() {
+            for (idx, cid) in s.hi.iter().enumerate() {
+                let p = priority.get(cid).copied().unwrap_or(0);
+                let key = (p, Reverse(s.queued_at.get(cid).copied().unwrap_or(0)));
+                if best.map(|(b, _, _)| key > b).unwrap_or(true) {
+                    best = Some((key, *meta, idx));
+                }
+            }
+        }
+        let (_, meta, idx) = best?;
+        let s = side.get_mut(&meta)?;
+        let cid = s.hi.remove(idx)?;
+        s.queued_at.remove(&cid);
+        Some(cid)
+    }
+    fn pop_pending_name(&mut self) -> Option<(Cid, String)> {
+        let mut best: Option<(u8, usize)> = None;
+        for (idx, (cid, _)) in self.pending_names.iter().enumerate() {
+            let p = self.priority.get(cid).copied().unwrap_or(0);
+            if best.map(|(b, _)| p > b).unwrap_or(true) {
+                best = Some((p, idx));
+            }
+        }
+        best.and_then(|(_, idx)| self.pending_names.remove(idx))
+    }
+    fn add(side: &mut ByMeta, cid: &Cid, clock: &mut u64) -> anyhow::Result<()> {
         let s = side.entry(cid.try_into()?).or_default();
         s.hi.push_back(*cid);
+        s.queued_at.insert(*cid, tick(clock));
         s.lo.push_back(*cid);
         Ok(())
     }
@@ -461,6 +1095,7 @@ impl Syncer {
         if let Ok(Some(side)) = cid_list::Meta::try_from(cid).map(|m| side.get_mut(&m)) {
             if let Some(index) = side.hi.iter().position(|c| c == cid) {
                 side.hi.remove(index);
+                side.queued_at.remove(cid);
                 return true;
             }
             let ack_count = side.acked.entry(*cid).or_default();
@@ -484,16 +1119,452 @@ struct ToSend {
     hi: VecDeque<Cid>,
     lo: VecDeque<Cid>,
     acked: HashMap<Cid, u8>,
+    //Clock reading when each CID in hi was queued, or last went out in a fill
+    queued_at: HashMap<Cid, u64>,
 }
 
 impl ToSend {
-    fn fill_cids(&mut self, list: &mut cid_list::CompactList, size: usize, mutate: bool) {
-        for q in [&mut self.hi, &mut self.lo] {
-            if let Some(idx) = q.iter().position(|c| !list.include(c, size)) {
-                if mutate {
-                    q.rotate_left(idx);
+    //Stable, so within a priority level the existing rotation order is kept
+    fn sort_lo(&mut self, priority: &HashMap<Cid, u8>) {
+        let level = |c: &Cid| priority.get(c).copied().unwrap_or(0);
+        self.lo.make_contiguous().sort_by_key(|c| Reverse(level(c)));
+    }
+    //With a clock, what went into the list moves to the back of its queue as if queued anew
+    fn fill_cids(
+        &mut self,
+        list: &mut cid_list::CompactList,
+        size: usize,
+        clock: Option<&mut u64>,
+    ) {
+        let hi_sent = self.hi.iter().position(|c| !list.include(c, size));
+        let lo_sent = self.lo.iter().position(|c| !list.include(c, size));
+        if let Some(clock) = clock {
+            if let Some(idx) = hi_sent {
+                self.hi.rotate_left(idx);
+                for c in self.hi.iter().skip(self.hi.len() - idx) {
+                    self.queued_at.insert(*c, tick(clock));
                 }
             }
+            if let Some(idx) = lo_sent {
+                self.lo.rotate_left(idx);
+            }
+        }
+    }
+}
+
+fn tick(clock: &mut u64) -> u64 {
+    *clock += 1;
+    *clock
+}
+
+//What a block no CID was waiting for most likely is, and what it links to. Documents must be a
+//  map or list that re-encodes to exactly the same bytes, which UnixFS blocks practically never do.
+fn guess_codec(bytes: &[u8]) -> (Codec, Vec<Cid>) {
+    for (codec, ipld_codec) in [
+        (Codec::DagCbor, IpldCodec::DagCbor),
+        (Codec::DagJson, IpldCodec::DagJson),
+    ] {
+        let Ok(document) = ipld_codec.decode::<Ipld>(bytes) else {
+            continue;
+        };
+        if !matches!(document, Ipld::Map(_) | Ipld::List(_))
+            || ipld_codec.encode(&document).ok().as_deref() != Some(bytes)
+        {
+            continue;
+        }
+        let mut cids = Vec::default();
+        if ipld_codec.references::<Ipld, _>(bytes, &mut cids).is_ok() {
+            return (codec, cids);
+        }
+    }
+    let mut cids = Vec::default();
+    if IpldCodec::DagPb
+        .references::<Ipld, _>(bytes, &mut cids)
+        .is_ok()
+    {
+        (Codec::DagPb, cids)
+    } else {
+        (Codec::Raw, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
+    use local_storage::{memory_provider::MemoryStorageProvider, provider::Handle};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_pause_keeps_other_dags_in_batched_push() {
+        let dir = TempDir::new().unwrap();
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let mut store = Storage::new(provider, 1024);
+        let mut roots = Vec::new();
+        for (name, byte) in [("a", 1u8), ("b", 2u8)] {
+            let file = dir.child(name);
+            file.write_binary(&[byte; 100]).unwrap();
+            let root = store.import_path(file.path()).unwrap();
+            roots.push(Cid::try_from(root.as_str()).unwrap());
+        }
+        let (paused, other) = (roots[0], roots[1]);
+
+        let mut sync =
+            Syncer::new(512, "a", roots.iter().map(|r| (*r, String::new())), []).unwrap();
+        sync.ready.clear();
+        let mut list = CompactList::try_from(&paused).unwrap();
+        assert!(list.include(&other, 512));
+        sync.ready
+            .push_back(Message::push(list, "a".to_owned()).unwrap());
+        let queued = |s: &Syncer, c: &Cid| s.push.values().any(|q| q.lo.contains(c));
+
+        assert!(sync.pause_dag(&paused.to_string(), &store).unwrap());
+        assert!(!queued(&sync, &paused));
+        //Nothing left of it to pause
+        assert!(!sync.pause_dag(&paused.to_string(), &store).unwrap());
+        match sync.ready.front() {
+            Some(Message::Sync(SyncMessage::Push(pm))) => {
+                assert_eq!(pm.cids.into_iter().collect::<Vec<_>>(), vec![other]);
+                assert!(pm.first_cid_name.is_empty());
+                assert!(pm.check());
+            }
+            m => panic!("Expected the batched push to survive, got {m:?}"),
+        }
+
+        assert!(sync.resume_dag(&paused.to_string()).unwrap());
+        assert!(queued(&sync, &paused));
+        assert!(!sync.resume_dag(&paused.to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_ack_from_one_peer_keeps_pushing_to_another() {
+        let dir = TempDir::new().unwrap();
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[7u8; 4000]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let root = Cid::try_from(root.as_str()).unwrap();
+
+        let queued = |p: &SyncPeer| p.sync.push.values().any(|q| q.hi.contains(&root));
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let ack = SyncMessage::Ack(CompactList::try_from(&root).unwrap());
+        let a = peers.get("a", &store).unwrap();
+        a.sync.handle(ack, &mut store).unwrap();
+        assert!(!queued(a));
+        assert!(queued(peers.get("b", &store).unwrap()));
+
+        peers.save(&store).unwrap();
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        assert_eq!(peers.peers.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(!queued(peers.get("a", &store).unwrap()));
+        assert!(queued(peers.get("b", &store).unwrap()));
+    }
+
+    #[test]
+    fn test_new_peer_beyond_cap_replaces_least_recently_heard() {
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let store = Storage::new(provider, 1024);
+        let mut peers = SyncPeers::load(512, Some("radio".to_owned()), &store).unwrap();
+        peers.get("radio", &store).unwrap();
+        for i in 0..MAX_PEERS - 1 {
+            peers.get(&format!("p{i}"), &store).unwrap();
+        }
+        peers.save(&store).unwrap();
+        peers.get("p1", &store).unwrap().heard = Instant::now();
+
+        //Nothing is written as they come and go, until the next save
+        peers.get("new1", &store).unwrap();
+        peers.get("new2", &store).unwrap();
+        assert_eq!(peers.peers.len(), MAX_PEERS);
+        assert!(peers.peers.contains_key("radio"));
+        assert!(peers.peers.contains_key("p1"));
+        assert_eq!(peers.expire(), vec!["p0", "p2"]);
+        assert!(Syncer::restore(512, "p0", &store).unwrap().is_some());
+        peers.save(&store).unwrap();
+        assert!(Syncer::restore(512, "p0", &store).unwrap().is_some());
+
+        peers.saved_at = None;
+        peers.save(&store).unwrap();
+        assert!(peers.expire().is_empty());
+        assert!(Syncer::restore(512, "p0", &store).unwrap().is_none());
+        assert!(Syncer::restore(512, "p3", &store).unwrap().is_some());
+        assert_eq!(
+            store.load_state(&format!("{STATE_KEY_PREFIX}p0")).unwrap(),
+            None
+        );
+        let peers = SyncPeers::load(512, None, &store).unwrap();
+        assert!(!peers.peers.contains_key("p2"));
+        assert!(peers.peers.contains_key("new2"));
+    }
+
+    #[test]
+    fn test_only_active_peers_visited_beyond_cap() {
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let store = Storage::new(provider, 1024);
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let targets: BTreeSet<String> = (0..MAX_PEERS + 3).map(|i| format!("p{i:02}")).collect();
+        peers.get("p05", &store).unwrap();
+        let active = peers.active(&targets);
+        assert_eq!(active.len(), MAX_PEERS);
+        assert_eq!(active[0], "p05");
+
+        //Once full, visiting every target leaves the pool as it was
+        for addr in &active {
+            peers.get(addr, &store).unwrap();
+        }
+        let pooled: Vec<String> = peers.peers.keys().cloned().collect();
+        for _ in 0..3 {
+            for addr in &peers.active(&targets) {
+                peers.get(addr, &store).unwrap();
+            }
+        }
+        assert_eq!(peers.peers.keys().cloned().collect::<Vec<_>>(), pooled);
+        assert!(peers.expire().is_empty());
+    }
+
+    #[test]
+    fn test_state_saved_on_change_with_pending_cids() {
+        let dir = TempDir::new().unwrap();
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[3u8; 100]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let root = Cid::try_from(root.as_str()).unwrap();
+
+        let mut a = Syncer::new(512, "a", iter::empty(), iter::empty()).unwrap();
+        let pushes = a.push_now(vec![root]).unwrap();
+        a.ready.extend(pushes);
+        a.stop_pushing(&root);
+        assert!(a.dirty);
+        a.save(&store).unwrap();
+        assert!(!a.dirty);
+        a.pop_pending_msg(&store).unwrap();
+        assert!(!a.dirty);
+
+        //The push that hadn't gone out yet is rebuilt from its CID, ahead of anything else
+        let mut b = Syncer::restore(512, "a", &store).unwrap().unwrap();
+        assert!(b.ready.is_empty());
+        assert_eq!(Syncer::pop_hi(&mut b.push, &b.priority), Some(root));
+
+        //Further changes wait for the save interval
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        peers.save(&store).unwrap();
+        let other = Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(b"other"));
+        peers
+            .get("b", &store)
+            .unwrap()
+            .sync
+            .will_pull(&other)
+            .unwrap();
+        peers.save(&store).unwrap();
+        assert!(Syncer::restore(512, "b", &store).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restored_state_catches_up_with_store() {
+        let dir = TempDir::new().unwrap();
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut store = Storage::new(provider, 1024);
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        peers.get("a", &store).unwrap();
+        peers.save(&store).unwrap();
+
+        //Imported, or received with its children still missing, after the last save
+        let file = dir.child("data");
+        file.write_binary(&[9u8; 100]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let root = Cid::try_from(root.as_str()).unwrap();
+        let other: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut other = Storage::new(other, 1024);
+        file.write_binary(&[8u8; 4000]).unwrap();
+        let stem = other.import_path(file.path()).unwrap();
+        let stem = other.get_block_by_cid(&stem).unwrap();
+        store.import_block(&stem).unwrap();
+        let dangling = Cid::try_from(stem.links[0].as_str()).unwrap();
+
+        assert!(Syncer::restore(512, "a", &store).unwrap().is_some());
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let a = &mut peers.get("a", &store).unwrap().sync;
+        assert!(a.dirty);
+        assert!(Syncer::knows(&a.push, &root));
+        assert!(Syncer::knows(&a.pull, &dangling));
+        let queued = a.push.values().map(|q| q.hi.len()).sum::<usize>();
+        a.catch_up([(root, String::new())], [dangling]).unwrap();
+        assert_eq!(a.push.values().map(|q| q.hi.len()).sum::<usize>(), queued);
+    }
+
+    #[test]
+    fn test_summary_only_for_peers_that_understand_it() {
+        let dir = TempDir::new().unwrap();
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[6u8; 4000]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let root = store.get_block_by_cid(&root).unwrap();
+        let summarized = |s: &Syncer| {
+            s.ready
+                .iter()
+                .any(|m| matches!(m, Message::Sync(SyncMessage::Summary(_))))
+        };
+
+        let mut old = Syncer::new(512, "old", iter::empty(), iter::empty()).unwrap();
+        old.push_dag(&root, &store, false).unwrap();
+        assert!(!summarized(&old));
+        let root_cid = Cid::try_from(root.cid.as_str()).unwrap();
+        assert!(old.push.values().any(|q| q.hi.contains(&root_cid)));
+
+        //Said so before there was any state kept for it
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        peers.understands_summaries("new");
+        let new = &mut peers.get("new", &store).unwrap().sync;
+        new.push_dag(&root, &store, false).unwrap();
+        assert!(summarized(new));
+    }
+
+    #[test]
+    fn test_summary_only_demotes_what_remote_may_have() {
+        let dir = TempDir::new().unwrap();
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[5u8; 4000]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let root = Cid::try_from(root.as_str()).unwrap();
+        let cids = Syncer::held_dag_cids(&root, &store).unwrap();
+
+        let mut a = Syncer::new(512, "a", [(root, String::new())], iter::empty()).unwrap();
+        assert!(a.push.values().any(|q| q.hi.contains(&root)));
+        let summary = SummaryMessage::new(&root, &cids, 512).unwrap();
+        let resp = a.handle(SyncMessage::Summary(summary), &mut store);
+        assert!(matches!(resp, Ok(Some(Message::Sync(SyncMessage::Ack(_))))));
+        assert!(cids.len() > 1);
+        assert!(!a.push.values().any(|q| q.hi.contains(&root)));
+        assert!(a.push.values().any(|q| q.lo.contains(&root)));
+    }
+
+    #[test]
+    fn test_equal_priorities_pop_oldest_first() {
+        let digest = |d: &[u8]| cid::multihash::Code::Sha2_256.digest(d);
+        //Different codecs land in different queues, ordered by Meta rather than age
+        let (raw, pb, urgent) = (
+            Cid::new_v1(0x55, digest(b"raw")),
+            Cid::new_v1(0x70, digest(b"pb")),
+            Cid::new_v1(0x55, digest(b"urgent")),
+        );
+        let mut a = Syncer::new(512, "a", iter::empty(), iter::empty()).unwrap();
+        for cid in [pb, raw, urgent] {
+            Syncer::add(&mut a.push, &cid, &mut a.clock).unwrap();
         }
+        assert_eq!(a.push.len(), 2);
+        a.priority.insert(urgent, 5);
+        let order: Vec<_> = iter::from_fn(|| Syncer::pop_hi(&mut a.push, &a.priority)).collect();
+        assert_eq!(order, vec![urgent, pb, raw]);
+    }
+
+    #[test]
+    fn test_unmatched_blocks_guessed() {
+        let dir = TempDir::new().unwrap();
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[7u8; 4000]).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let stem = store.get_block_by_cid(&root).unwrap();
+        let record = format!(r#"{{"temp": 21, "image": {{"/": "{root}"}}}}"#);
+        let root = Cid::try_from(root.as_str()).unwrap();
+
+        for codec in [Codec::DagCbor, Codec::DagJson] {
+            let document =
+                ipfs_unixfs::encode_document(codec, store.hash(), record.as_bytes()).unwrap();
+            assert_eq!(guess_codec(document.data()), (codec, vec![root]));
+        }
+        assert_eq!(guess_codec(&stem.data).0, Codec::DagPb);
+        assert_eq!(guess_codec(b"21").0, Codec::Raw);
+        assert_eq!(guess_codec(&[7u8; 1000]).0, Codec::Raw);
+    }
+
+    #[test]
+    fn test_blocks_addressed_by_peer_hash() {
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut store = Storage::new(provider, 1024);
+        store.set_hash(cid::multihash::Code::Blake3_256);
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let a = peers.get("a", &store).unwrap();
+
+        let wanted = Cid::new_v1(0x55, cid::multihash::Code::Sha2_512.digest(b"wanted"));
+        a.sync.will_pull(&wanted).unwrap();
+        a.sync.handle_block(b"wanted".to_vec(), &mut store).unwrap();
+        assert!(store.get_block_by_cid(&wanted.to_string()).is_ok());
+
+        // Not how this node addresses blocks, but how the peer does
+        a.sync.handle_block(b"stray".to_vec(), &mut store).unwrap();
+        let stray = Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(b"stray"));
+        assert!(store.get_block_by_cid(&stray.to_string()).is_ok());
+        let pushed = Cid::new_v1(0x55, cid::multihash::Code::Sha2_512.digest(b"pushed"));
+        a.sync
+            .handle_push("", std::iter::once(pushed), &store)
+            .unwrap();
+        a.sync.handle_block(b"other".to_vec(), &mut store).unwrap();
+        let other = Cid::new_v1(0x55, cid::multihash::Code::Sha2_512.digest(b"other"));
+        assert!(store.get_block_by_cid(&other.to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_inlined_blocks_not_sent() {
+        let dir = TempDir::new().unwrap();
+        let provider: Handle = Arc::new(Mutex::new(MemoryStorageProvider::new(u64::MAX)));
+        let mut store = Storage::new(provider, 1024);
+        store.set_inline_limit(32).unwrap();
+        let file = dir.child("data");
+        let data: Vec<u8> = (0..3 * 1024 + 10).map(|i| (i % 251) as u8).collect();
+        file.write_binary(&data).unwrap();
+        let root = store.import_path(file.path()).unwrap();
+        let links = store.get_block_by_cid(&root).unwrap().links;
+        let tail = Cid::try_from(links[3].as_str()).unwrap();
+        assert!(ipfs_unixfs::inlined_data(&tail).is_some());
+
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let a = peers.get("a", &store).unwrap();
+        let queued = |c: &Cid| a.sync.push.values().any(|q| q.hi.contains(c));
+        assert!(queued(&Cid::try_from(root.as_str()).unwrap()));
+        assert!(!queued(&tail));
+        let mut pushed = HashSet::new();
+        a.sync.push_dag_blocks(&root, &store, &mut pushed).unwrap();
+        assert_eq!(pushed.len(), 4);
+        assert!(!pushed.contains(&links[3]));
+    }
+
+    #[test]
+    fn test_expired_dag_neither_pushed_nor_pulled() {
+        let dir = TempDir::new().unwrap();
+        let provider = MemoryStorageProvider::new(u64::MAX);
+        let provider: Handle = Arc::new(Mutex::new(provider));
+        let mut store = Storage::new(provider, 1024);
+        let file = dir.child("data");
+        file.write_binary(&[9u8; 4000]).unwrap();
+        let held = store.import_path(file.path()).unwrap();
+        let held_cid = Cid::try_from(held.as_str()).unwrap();
+        let wanted = Cid::new_v1(0x55, cid::multihash::Code::Sha2_256.digest(b"elsewhere"));
+
+        let mut peers = SyncPeers::load(512, None, &store).unwrap();
+        let a = peers.get("a", &store).unwrap();
+        a.sync.will_pull(&wanted).unwrap();
+        let has =
+            |side: &ByMeta, c: &Cid| side.values().any(|q| q.hi.contains(c) || q.lo.contains(c));
+        assert!(has(&a.sync.push, &held_cid));
+        assert!(has(&a.sync.pull, &wanted));
+
+        a.sync.expire_dag(&held, &store).unwrap();
+        a.sync.expire_dag(&wanted.to_string(), &store).unwrap();
+        assert!(!has(&a.sync.push, &held_cid));
+        assert!(!has(&a.sync.pull, &wanted));
     }
 }
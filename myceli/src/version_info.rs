@@ -2,13 +2,24 @@ use messages::ApplicationAPI;
 // The file has been placed there by the build script.
 include!(concat!(env!("OUT_DIR"), "/built.rs"));
 
+// Reported among the features, so peers know they may ask about ship windows with bitmaps
+pub const SHIP_BITMAP: &str = "SHIP_BITMAP";
+// Reported among the features, so peers know they may summarize a DAG in one sync message
+pub const SYNC_SUMMARY: &str = "SYNC_SUMMARY";
+
 pub fn get(remote_label: Option<String>) -> ApplicationAPI {
     ApplicationAPI::Version {
         version: env!("CARGO_PKG_VERSION").to_string(),
         rust: env!("CARGO_PKG_RUST_VERSION").to_string(),
         target: TARGET.to_owned(),
         profile: PROFILE.to_owned(),
-        features: FEATURES.iter().map(|s| s.to_string()).collect(),
+        features: FEATURES
+            .iter()
+            .copied()
+            .chain(cfg!(feature = "proto_ship").then_some(SHIP_BITMAP))
+            .chain(cfg!(feature = "proto_sync").then_some(SYNC_SUMMARY))
+            .map(|s| s.to_string())
+            .collect(),
         remote_label,
     }
 }
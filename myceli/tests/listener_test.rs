@@ -20,12 +20,47 @@ pub fn test_verify_listener_alive() {
     assert_eq!(response, Message::available_blocks(vec![]));
 }
 
+#[test]
+pub fn test_verify_in_memory_listener_alive() {
+    let listener = TestListener::in_memory();
+    listener.start().unwrap();
+
+    let mut controller = TestController::new();
+
+    let response =
+        controller.send_and_recv(&listener.listen_addr, Message::request_available_blocks());
+
+    assert_eq!(response, Message::available_blocks(vec![]));
+}
+
+#[test]
+pub fn test_pause_without_transmission_fails() {
+    let listener = TestListener::in_memory();
+    listener.start().unwrap();
+
+    let mut controller = TestController::new();
+
+    let test_file_path = listener.generate_file().unwrap();
+    let resp =
+        controller.send_and_recv(&listener.listen_addr, Message::import_file(&test_file_path));
+    let root_cid = match resp {
+        Message::ApplicationAPI(ApplicationAPI::FileImported { cid, .. }) => cid,
+        other => panic!("Failed to receive FileImported msg {other:?}"),
+    };
+
+    let response = controller.send_and_recv(
+        &listener.listen_addr,
+        Message::ApplicationAPI(ApplicationAPI::PauseTransmitDag { cid: root_cid }),
+    );
+    assert!(matches!(response, Message::Error(_)), "{response:?}");
+}
+
 #[cfg(feature = "proto_ship")]
 #[ignore]
 #[test]
 pub fn test_resume_dag_after_reconnect() {
-    let transmitter = TestListener::new();
-    let receiver = TestListener::new();
+    let transmitter = TestListener::in_memory();
+    let receiver = TestListener::in_memory();
     let mut controller = TestController::new();
 
     transmitter.start().unwrap();
@@ -81,8 +116,8 @@ pub fn test_resume_dag_after_reconnect() {
 
 #[test]
 pub fn test_no_transmit_after_disconnect() {
-    let transmitter = TestListener::new();
-    let receiver = TestListener::new();
+    let transmitter = TestListener::in_memory();
+    let receiver = TestListener::in_memory();
     let mut controller = TestController::new();
 
     transmitter.start().unwrap();
@@ -123,8 +158,8 @@ pub fn test_no_transmit_after_disconnect() {
 #[test]
 #[ignore]
 pub fn test_transmit_resume_after_timeout() {
-    let transmitter = TestListener::new();
-    let receiver = TestListener::new();
+    let transmitter = TestListener::in_memory();
+    let receiver = TestListener::in_memory();
     let mut controller = TestController::new();
 
     transmitter.start().unwrap();
@@ -1,11 +1,11 @@
 use super::*;
 
 use anyhow::Result;
-use assert_fs::fixture::ChildPath;
 use assert_fs::{fixture::FileWriteBin, fixture::PathChild, TempDir};
 use blake2::{Blake2s256, Digest};
 use file_hashing::get_hash_file;
-use myceli::listener::Listener;
+use local_storage::provider::IN_MEMORY;
+use myceli::listener::{Listener, ListenerOptions};
 use rand::{rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
@@ -46,6 +46,7 @@ pub fn wait_receiving_done(receiver: &TestListener, controller: &mut TestControl
 pub struct TestListener {
     pub listen_addr: String,
     pub test_dir: TempDir,
+    in_memory: bool,
 }
 
 impl TestListener {
@@ -57,6 +58,15 @@ impl TestListener {
         TestListener {
             listen_addr,
             test_dir,
+            in_memory: false,
+        }
+    }
+
+    // Keeps storage in RAM rather than under test_dir
+    pub fn in_memory() -> TestListener {
+        TestListener {
+            in_memory: true,
+            ..TestListener::new()
         }
     }
 
@@ -67,7 +77,10 @@ impl TestListener {
             .to_socket_addrs()
             .map(|mut i| i.next().unwrap())
             .unwrap();
-        let thread_db_path = self.test_dir.child("storage.db");
+        let thread_db_path = match self.in_memory {
+            true => IN_MEMORY.to_owned(),
+            false => self.test_dir.child("storage.db").to_string_lossy().into(),
+        };
 
         spawn(move || start_listener_thread(thread_listen_addr, thread_db_path));
 
@@ -88,8 +101,7 @@ impl TestListener {
     }
 }
 
-fn start_listener_thread(listen_addr: SocketAddr, db_path: ChildPath) {
-    let db_path = db_path.path().to_str().unwrap();
+fn start_listener_thread(listen_addr: SocketAddr, db_path: String) {
     let listen_addr_str = listen_addr.to_string();
     std::thread::sleep(Duration::from_millis(1));
     let mut transport = UdpTransport::new(&listen_addr_str, 60, None).unwrap();
@@ -98,8 +110,17 @@ fn start_listener_thread(listen_addr: SocketAddr, db_path: ChildPath) {
         .unwrap();
     transport.set_max_read_attempts(Some(1));
     let transport = Arc::new(transport);
-    let mut listener =
-        Listener::new(&listen_addr, db_path, transport, BLOCK_SIZE, None, 9, 512).unwrap();
+    let mut listener = Listener::new(
+        &listen_addr,
+        &db_path,
+        transport,
+        ListenerOptions {
+            block_size: BLOCK_SIZE,
+            high_disk_usage: 9,
+            ..Default::default()
+        },
+    )
+    .unwrap();
     listener
         .start(10, 2, 1)
         .expect("Error encountered in listener");
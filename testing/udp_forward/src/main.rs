@@ -19,7 +19,7 @@ fn main() -> err::Result<()> {
         .map(|s| str::parse(&s))
         .unwrap_or(Ok(usize::MAX))?;
     let mut buf = [0u8; u16::MAX as usize];
-    let socket = UdpSocket::bind(listen.clone())?;
+    let socket = UdpSocket::bind(listen)?;
     let mut good = 0;
     let mut bad = 0;
     let mut rng = rand::thread_rng();
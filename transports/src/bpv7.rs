@@ -0,0 +1,432 @@
+// Just enough of RFC 9171 (Bundle Protocol Version 7) to carry a message in the payload of a bundle,
+// and of RFC 8949 (CBOR) to encode one.
+use crate::error::{adhoc, Result};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const VERSION: u64 = 7;
+const PAYLOAD_BLOCK: u64 = 1;
+// Bundle processing control flags
+const IS_FRAGMENT: u64 = 0x01;
+const MUST_NOT_FRAGMENT: u64 = 0x04;
+const CRC_NONE: u64 = 0;
+const CRC_16: u64 = 1;
+const CRC_32C: u64 = 2;
+// Milliseconds between the UNIX epoch and the DTN epoch, 2000-01-01T00:00:00Z
+const DTN_EPOCH_MS: u64 = 946_684_800_000;
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const INDEFINITE_ARRAY: u8 = 0x9F;
+const BREAK: u8 = 0xFF;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Eid {
+    // dtn:none
+    None,
+    // dtn://node/service, holding everything after "dtn:"
+    Dtn(String),
+    // ipn:node.service
+    Ipn { node: u64, service: u64 },
+}
+
+impl FromStr for Eid {
+    type Err = crate::TransportError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "dtn:none" {
+            Ok(Eid::None)
+        } else if let Some(ssp) = s.strip_prefix("dtn:") {
+            Ok(Eid::Dtn(ssp.to_owned()))
+        } else if let Some((node, service)) = s.strip_prefix("ipn:").and_then(|s| s.split_once('.'))
+        {
+            let num = |n: &str| {
+                n.parse()
+                    .map_err(|_| adhoc(&format!("Bad ipn endpoint ID: {s}")))
+            };
+            Ok(Eid::Ipn {
+                node: num(node)?,
+                service: num(service)?,
+            })
+        } else {
+            Err(adhoc(&format!("Not a dtn: or ipn: endpoint ID: {s}")))
+        }
+    }
+}
+
+impl Display for Eid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Eid::None => write!(f, "dtn:none"),
+            Eid::Dtn(ssp) => write!(f, "dtn:{ssp}"),
+            Eid::Ipn { node, service } => write!(f, "ipn:{node}.{service}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bundle {
+    pub destination: Eid,
+    pub source: Eid,
+    pub report_to: Eid,
+    // Creation time in milliseconds since the DTN epoch, 0 if the clock is not trusted
+    pub created_ms: u64,
+    pub sequence: u64,
+    pub lifetime_ms: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn new(
+        destination: Eid,
+        source: Eid,
+        lifetime_ms: u64,
+        sequence: u64,
+        payload: Vec<u8>,
+    ) -> Self {
+        let created_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_millis() as u64).saturating_sub(DTN_EPOCH_MS))
+            .unwrap_or(0);
+        Self {
+            destination,
+            report_to: source.clone(),
+            source,
+            created_ms,
+            sequence,
+            lifetime_ms,
+            payload,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![INDEFINITE_ARRAY];
+        let start = out.len();
+        put_head(&mut out, MAJOR_ARRAY, 9);
+        put_head(&mut out, MAJOR_UINT, VERSION);
+        put_head(&mut out, MAJOR_UINT, MUST_NOT_FRAGMENT);
+        put_head(&mut out, MAJOR_UINT, CRC_16);
+        put_eid(&mut out, &self.destination);
+        put_eid(&mut out, &self.source);
+        put_eid(&mut out, &self.report_to);
+        put_head(&mut out, MAJOR_ARRAY, 2);
+        put_head(&mut out, MAJOR_UINT, self.created_ms);
+        put_head(&mut out, MAJOR_UINT, self.sequence);
+        put_head(&mut out, MAJOR_UINT, self.lifetime_ms);
+        put_head(&mut out, MAJOR_BYTES, 2);
+        out.extend_from_slice(&[0, 0]);
+        let crc = crc16(&out[start..]);
+        let len = out.len();
+        out[len - 2..].copy_from_slice(&crc.to_be_bytes());
+
+        let start = out.len();
+        put_head(&mut out, MAJOR_ARRAY, 6);
+        put_head(&mut out, MAJOR_UINT, PAYLOAD_BLOCK);
+        put_head(&mut out, MAJOR_UINT, 1);
+        put_head(&mut out, MAJOR_UINT, 0);
+        put_head(&mut out, MAJOR_UINT, CRC_32C);
+        put_head(&mut out, MAJOR_BYTES, self.payload.len() as u64);
+        out.extend_from_slice(&self.payload);
+        put_head(&mut out, MAJOR_BYTES, 4);
+        out.extend_from_slice(&[0; 4]);
+        let crc = crc32c(&out[start..]);
+        let len = out.len();
+        out[len - 4..].copy_from_slice(&crc.to_be_bytes());
+        out.push(BREAK);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader { buf: bytes, pos: 0 };
+        if r.byte()? != INDEFINITE_ARRAY {
+            return Err(adhoc("Bundle is not an indefinite-length array"));
+        }
+        let start = r.pos;
+        let fields = r.expect(MAJOR_ARRAY)?;
+        if r.expect(MAJOR_UINT)? != VERSION {
+            return Err(adhoc("Not a version 7 bundle"));
+        }
+        let flags = r.expect(MAJOR_UINT)?;
+        if flags & IS_FRAGMENT != 0 {
+            return Err(adhoc(
+                "Bundle fragments should be reassembled by the bundle agent",
+            ));
+        }
+        let crc_type = r.expect(MAJOR_UINT)?;
+        let destination = r.eid()?;
+        let source = r.eid()?;
+        let report_to = r.eid()?;
+        if r.expect(MAJOR_ARRAY)? != 2 {
+            return Err(adhoc("Malformed creation timestamp"));
+        }
+        let created_ms = r.expect(MAJOR_UINT)?;
+        let sequence = r.expect(MAJOR_UINT)?;
+        let lifetime_ms = r.expect(MAJOR_UINT)?;
+        let expected_fields = if crc_type == CRC_NONE { 8 } else { 9 };
+        if fields != expected_fields {
+            return Err(adhoc("Unexpected number of primary block fields"));
+        }
+        r.check_crc(start, crc_type)?;
+        let mut payload = None;
+        while r.peek()? != BREAK {
+            let start = r.pos;
+            let fields = r.expect(MAJOR_ARRAY)?;
+            let block_type = r.expect(MAJOR_UINT)?;
+            let _number = r.expect(MAJOR_UINT)?;
+            let _flags = r.expect(MAJOR_UINT)?;
+            let crc_type = r.expect(MAJOR_UINT)?;
+            let data = r.bytes()?;
+            if fields != if crc_type == CRC_NONE { 5 } else { 6 } {
+                return Err(adhoc("Unexpected number of canonical block fields"));
+            }
+            r.check_crc(start, crc_type)?;
+            // Extension blocks are the bundle agent's business
+            if block_type == PAYLOAD_BLOCK {
+                payload = Some(data.to_vec());
+            }
+        }
+        Ok(Self {
+            destination,
+            source,
+            report_to,
+            created_ms,
+            sequence,
+            lifetime_ms,
+            payload: payload.ok_or_else(|| adhoc("Bundle has no payload block"))?,
+        })
+    }
+}
+
+fn put_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= u8::MAX.into() {
+        out.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= u16::MAX.into() {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX.into() {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn put_eid(out: &mut Vec<u8>, eid: &Eid) {
+    put_head(out, MAJOR_ARRAY, 2);
+    match eid {
+        Eid::None => {
+            put_head(out, MAJOR_UINT, 1);
+            put_head(out, MAJOR_UINT, 0);
+        }
+        Eid::Dtn(ssp) => {
+            put_head(out, MAJOR_UINT, 1);
+            put_head(out, MAJOR_TEXT, ssp.len() as u64);
+            out.extend_from_slice(ssp.as_bytes());
+        }
+        Eid::Ipn { node, service } => {
+            put_head(out, MAJOR_UINT, 2);
+            put_head(out, MAJOR_ARRAY, 2);
+            put_head(out, MAJOR_UINT, *node);
+            put_head(out, MAJOR_UINT, *service);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Result<u8> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| adhoc("Bundle truncated"))
+    }
+    fn byte(&mut self) -> Result<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Ok(b)
+    }
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|e| *e <= self.buf.len())
+            .ok_or_else(|| adhoc("Bundle truncated"))?;
+        let result = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(result)
+    }
+    fn head(&mut self) -> Result<(u8, u64)> {
+        let initial = self.byte()?;
+        let n = match initial & 0x1F {
+            n @ 0..=23 => n.into(),
+            24 => self.byte()?.into(),
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()).into(),
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()).into(),
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(adhoc("Unsupported CBOR length encoding in bundle")),
+        };
+        Ok((initial >> 5, n))
+    }
+    fn expect(&mut self, major: u8) -> Result<u64> {
+        match self.head()? {
+            (m, n) if m == major => Ok(n),
+            (m, _) => Err(adhoc(&format!(
+                "Expected CBOR major type {major} in bundle, found {m}"
+            ))),
+        }
+    }
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let n = self.expect(MAJOR_BYTES)?;
+        self.take(n.try_into()?)
+    }
+    fn eid(&mut self) -> Result<Eid> {
+        if self.expect(MAJOR_ARRAY)? != 2 {
+            return Err(adhoc("Malformed endpoint ID"));
+        }
+        match self.expect(MAJOR_UINT)? {
+            1 => match self.head()? {
+                (MAJOR_UINT, 0) => Ok(Eid::None),
+                (MAJOR_TEXT, n) => {
+                    let ssp = self.take(n.try_into()?)?;
+                    let ssp =
+                        std::str::from_utf8(ssp).map_err(|_| adhoc("Endpoint ID not UTF-8"))?;
+                    Ok(Eid::Dtn(ssp.to_owned()))
+                }
+                _ => Err(adhoc("Malformed dtn endpoint ID")),
+            },
+            2 => {
+                if self.expect(MAJOR_ARRAY)? != 2 {
+                    return Err(adhoc("Malformed ipn endpoint ID"));
+                }
+                Ok(Eid::Ipn {
+                    node: self.expect(MAJOR_UINT)?,
+                    service: self.expect(MAJOR_UINT)?,
+                })
+            }
+            s => Err(adhoc(&format!("Unsupported endpoint ID scheme {s}"))),
+        }
+    }
+    // The CRC is the block's last field, computed over the block with that field's value zeroed
+    fn check_crc(&mut self, start: usize, crc_type: u64) -> Result<()> {
+        let crc_len = match crc_type {
+            CRC_NONE => return Ok(()),
+            CRC_16 => 2,
+            CRC_32C => 4,
+            _ => return Err(adhoc("Unsupported bundle CRC type")),
+        };
+        let value = self.bytes()?;
+        if value.len() != crc_len {
+            return Err(adhoc("Bundle CRC has the wrong length"));
+        }
+        let mut block = self.buf[start..self.pos].to_vec();
+        let len = block.len();
+        block[len - crc_len..].fill(0);
+        let ok = match crc_type {
+            CRC_16 => crc16(&block).to_be_bytes().as_slice() == value,
+            _ => crc32c(&block).to_be_bytes().as_slice() == value,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(adhoc("Bundle block failed its CRC check"))
+        }
+    }
+}
+
+// CRC-16/X-25
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for b in data {
+        crc ^= u16::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// CRC-32C (Castagnoli)
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc16(b"123456789"), 0x906E);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn eid_parse_and_display() {
+        for s in ["dtn:none", "dtn://ground/myceli", "ipn:12.3"] {
+            assert_eq!(Eid::from_str(s).unwrap().to_string(), s);
+        }
+        assert!(Eid::from_str("127.0.0.1:8001").is_err());
+    }
+
+    #[test]
+    fn bundle_roundtrip() {
+        let b = Bundle::new(
+            Eid::from_str("ipn:2.1").unwrap(),
+            Eid::from_str("dtn://sat/myceli").unwrap(),
+            60_000,
+            7,
+            vec![9; 300],
+        );
+        let bytes = b.encode();
+        assert_eq!(bytes[0], INDEFINITE_ARRAY);
+        assert_eq!(Bundle::decode(&bytes).unwrap(), b);
+    }
+
+    #[test]
+    fn corrupted_payload_rejected() {
+        let b = Bundle::new(Eid::None, Eid::None, 1, 0, vec![1, 2, 3]);
+        let mut bytes = b.encode();
+        let pos = bytes.len() - 7;
+        assert_eq!(bytes[pos], 3);
+        bytes[pos] = 4;
+        assert!(Bundle::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn corrupted_primary_block_rejected() {
+        let b = Bundle::new(Eid::None, Eid::from_str("ipn:1.0").unwrap(), 1, 0, vec![1]);
+        let mut bytes = b.encode();
+        // The source's node number, which is covered by the primary block's CRC
+        assert_eq!(bytes[11], 1);
+        bytes[11] = 0;
+        assert!(Bundle::decode(&bytes).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    bpv7::{Bundle, Eid},
+    error::{adhoc, Result},
+    Transport, UdpTransport,
+};
+use log::{debug, info, warn};
+use messages::Message;
+use parity_scale_codec::Decode;
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Largest bundle accepted from the bundle agent
+const MAX_BUNDLE_SIZE: usize = 64 * 1024;
+
+// Sends messages addressed to DTN endpoint IDs (dtn: or ipn:) as BPv7 bundles by way of a local
+// bundle agent, which takes care of getting them there. Any other address, e.g. a local
+// controller's, gets chunked UDP just as with UdpTransport.
+pub struct BundleTransport {
+    udp: UdpTransport,
+    agent: SocketAddr,
+    eid: Eid,
+    lifetime_ms: u64,
+    sequence: AtomicU64,
+}
+
+impl BundleTransport {
+    pub fn new(
+        listen_addr: &str,
+        mtu: u16,
+        chunk_transmit_throttle: Option<u32>,
+        agent_addr: &str,
+        eid: &str,
+        lifetime_ms: u64,
+    ) -> Result<Self> {
+        let agent = agent_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(adhoc("Failed to parse bundle agent address"))?;
+        let eid = Eid::from_str(eid)?;
+        info!("Will exchange bundles as {eid} with the bundle agent at {agent}");
+        Ok(BundleTransport {
+            udp: UdpTransport::new(listen_addr, mtu, chunk_transmit_throttle)?,
+            agent,
+            eid,
+            lifetime_ms,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> Result<()> {
+        self.udp.set_read_timeout(dur)
+    }
+
+    pub fn set_max_read_attempts(&mut self, attempts: Option<u16>) {
+        self.udp.set_max_read_attempts(attempts);
+    }
+}
+
+impl Transport for BundleTransport {
+    fn receive(&self) -> Result<(Message, String)> {
+        let mut buf = vec![0; MAX_BUNDLE_SIZE];
+        let mut read_errors = 0;
+        let mut timeouts = 0;
+        loop {
+            let (len, sender_addr) =
+                self.udp
+                    .recv_datagram(&mut buf, &mut timeouts, &mut read_errors)?;
+            if sender_addr != self.agent {
+                if let Some(msg) = self.udp.assemble(&buf[0..len])? {
+                    return Ok((msg, sender_addr.to_string()));
+                }
+                continue;
+            }
+            let received = Bundle::decode(&buf[0..len]).and_then(|b| {
+                let msg = Message::decode(&mut b.payload.as_slice())?;
+                Ok((msg, b.source))
+            });
+            match received {
+                Ok((msg, source)) => {
+                    debug!("Received bundle from {source}: {msg:?}");
+                    return Ok((msg, source.to_string()));
+                }
+                Err(e) => warn!("Dropping {len} byte bundle that could not be read: {e:?}"),
+            }
+        }
+    }
+
+    fn send(&self, msg: Message, addr: &str) -> Result<()> {
+        let destination = match Eid::from_str(addr) {
+            Ok(eid) => eid,
+            Err(_) => return self.udp.send(msg, addr),
+        };
+        debug!("Bundling msg for {destination}: {msg:?}");
+        let bundle = Bundle::new(
+            destination,
+            self.eid.clone(),
+            self.lifetime_ms,
+            self.sequence.fetch_add(1, Ordering::Relaxed),
+            msg.to_bytes(),
+        );
+        self.udp.socket.send_to(&bundle.encode(), self.agent)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::ApplicationAPI;
+    use std::net::UdpSocket;
+
+    // Stands in for a bundle agent, which would otherwise route the bundle on to its destination
+    fn agent() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn transport(agent: &UdpSocket) -> BundleTransport {
+        let agent_addr = agent.local_addr().unwrap().to_string();
+        let mut t =
+            BundleTransport::new("127.0.0.1:0", 512, None, &agent_addr, "ipn:1.1", 60_000).unwrap();
+        t.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        t
+    }
+
+    #[test]
+    fn sends_bundles_to_agent() {
+        let agent = agent();
+        let t = transport(&agent);
+        let msg = Message::ApplicationAPI(ApplicationAPI::GetConnected);
+        t.send(msg.clone(), "dtn://ground/myceli").unwrap();
+        let mut buf = vec![0; MAX_BUNDLE_SIZE];
+        let (len, _) = agent.recv_from(&mut buf).unwrap();
+        let bundle = Bundle::decode(&buf[..len]).unwrap();
+        assert_eq!(bundle.destination.to_string(), "dtn://ground/myceli");
+        assert_eq!(bundle.source.to_string(), "ipn:1.1");
+        assert_eq!(bundle.lifetime_ms, 60_000);
+        assert_eq!(
+            Message::decode(&mut bundle.payload.as_slice()).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn receives_bundles_from_agent_and_udp_from_others() {
+        let agent = agent();
+        let t = transport(&agent);
+        let listen_addr = t.udp.socket.local_addr().unwrap();
+        let msg = Message::ApplicationAPI(ApplicationAPI::ConnectedState { connected: true });
+        let bundle = Bundle::new(
+            Eid::from_str("ipn:2.1").unwrap(),
+            Eid::from_str("ipn:7.1").unwrap(),
+            1_000,
+            0,
+            msg.to_bytes(),
+        );
+        agent.send_to(&bundle.encode(), listen_addr).unwrap();
+        assert_eq!(t.receive().unwrap(), (msg.clone(), "ipn:7.1".to_string()));
+
+        let controller = UdpTransport::new("127.0.0.1:0", 512, None).unwrap();
+        controller
+            .send(msg.clone(), &listen_addr.to_string())
+            .unwrap();
+        let controller_addr = controller.socket.local_addr().unwrap().to_string();
+        assert_eq!(t.receive().unwrap(), (msg, controller_addr));
+    }
+}
@@ -0,0 +1,219 @@
+// CCSDS Space Packet (CCSDS 133.0-B-2) framing of chunks, so they can travel through a spacecraft's packet router
+use crate::error::{adhoc, Result};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+
+pub const PRIMARY_HEADER_LEN: usize = 6;
+const MAX_APID: u16 = 0x7FF;
+const SEQUENCE_COUNT_MODULUS: u16 = 1 << 14;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Segment {
+    Continuation = 0b00,
+    First = 0b01,
+    Last = 0b10,
+    Unsegmented = 0b11,
+}
+
+impl Segment {
+    // Where the index-th of count chunks of one message falls
+    pub fn of(index: usize, count: usize) -> Self {
+        if count <= 1 {
+            Segment::Unsegmented
+        } else if index == 0 {
+            Segment::First
+        } else if index + 1 == count {
+            Segment::Last
+        } else {
+            Segment::Continuation
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SpacePacketConfig {
+    // Application process ID identifying myceli's packets to the router, 0 to 2047
+    pub apid: u16,
+    // Telecommand packets rather than telemetry, i.e. the ground end of the link
+    pub telecommand: bool,
+    // Mark the packets of a multi-chunk message as first/continuation/last segments,
+    // rather than each one as unsegmented
+    pub segmented: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PrimaryHeader {
+    pub telecommand: bool,
+    pub apid: u16,
+    pub segment: Segment,
+    pub sequence_count: u16,
+    pub data_len: usize,
+}
+
+impl PrimaryHeader {
+    pub fn encode(&self) -> [u8; PRIMARY_HEADER_LEN] {
+        // Version 0, no secondary header
+        let id = (u16::from(self.telecommand) << 12) | (self.apid & MAX_APID);
+        let seq = ((self.segment as u16) << 14) | (self.sequence_count % SEQUENCE_COUNT_MODULUS);
+        let len = (self.data_len - 1) as u16;
+        let mut result = [0u8; PRIMARY_HEADER_LEN];
+        result[0..2].copy_from_slice(&id.to_be_bytes());
+        result[2..4].copy_from_slice(&seq.to_be_bytes());
+        result[4..6].copy_from_slice(&len.to_be_bytes());
+        result
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self> {
+        if packet.len() < PRIMARY_HEADER_LEN {
+            return Err(adhoc("Space packet shorter than its primary header"));
+        }
+        let word = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let (id, seq, len) = (word(0), word(2), word(4));
+        if id >> 13 != 0 {
+            return Err(adhoc("Unsupported space packet version"));
+        }
+        let segment = match seq >> 14 {
+            0b00 => Segment::Continuation,
+            0b01 => Segment::First,
+            0b10 => Segment::Last,
+            _ => Segment::Unsegmented,
+        };
+        Ok(Self {
+            telecommand: id & (1 << 12) != 0,
+            apid: id & MAX_APID,
+            segment,
+            sequence_count: seq % SEQUENCE_COUNT_MODULUS,
+            data_len: usize::from(len) + 1,
+        })
+    }
+}
+
+pub(crate) struct SpacePacketFramer {
+    config: SpacePacketConfig,
+    next_sequence_count: u16,
+    // Last sequence count seen from each sender, to notice lost packets
+    last_received: BTreeMap<String, u16>,
+}
+
+impl SpacePacketFramer {
+    pub fn new(config: SpacePacketConfig) -> Result<Self> {
+        if config.apid > MAX_APID {
+            return Err(adhoc("APID must fit in 11 bits"));
+        }
+        Ok(Self {
+            config,
+            next_sequence_count: 0,
+            last_received: BTreeMap::default(),
+        })
+    }
+
+    pub fn frame(&mut self, chunk: &[u8], segment: Segment) -> Result<Vec<u8>> {
+        if chunk.is_empty() || chunk.len() > usize::from(u16::MAX) + 1 {
+            return Err(adhoc("Chunk does not fit in a space packet"));
+        }
+        let header = PrimaryHeader {
+            telecommand: self.config.telecommand,
+            apid: self.config.apid,
+            segment: if self.config.segmented {
+                segment
+            } else {
+                Segment::Unsegmented
+            },
+            sequence_count: self.next_sequence_count,
+            data_len: chunk.len(),
+        };
+        self.next_sequence_count = (self.next_sequence_count + 1) % SEQUENCE_COUNT_MODULUS;
+        let mut result = header.encode().to_vec();
+        result.extend_from_slice(chunk);
+        Ok(result)
+    }
+
+    // The chunk carried by the packet, or None if the packet belongs to some other application
+    pub fn unframe<'a>(&mut self, packet: &'a [u8], sender: &str) -> Result<Option<&'a [u8]>> {
+        let header = PrimaryHeader::decode(packet)?;
+        if header.apid != self.config.apid {
+            debug!("Ignoring space packet for APID {}", header.apid);
+            return Ok(None);
+        }
+        let data = packet
+            .get(PRIMARY_HEADER_LEN..PRIMARY_HEADER_LEN + header.data_len)
+            .ok_or_else(|| adhoc("Space packet shorter than its data length"))?;
+        if let Some(last) = self
+            .last_received
+            .insert(sender.to_owned(), header.sequence_count)
+        {
+            let expected = (last + 1) % SEQUENCE_COUNT_MODULUS;
+            if header.sequence_count != expected {
+                let lost = (header.sequence_count + SEQUENCE_COUNT_MODULUS - expected)
+                    % SEQUENCE_COUNT_MODULUS;
+                warn!(
+                    "Space packet sequence count from {sender} jumped to {}, {lost} packet(s) may have been lost",
+                    header.sequence_count
+                );
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(segmented: bool) -> SpacePacketConfig {
+        SpacePacketConfig {
+            apid: 0x123,
+            telecommand: true,
+            segmented,
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = PrimaryHeader {
+            telecommand: false,
+            apid: 0x7FF,
+            segment: Segment::Unsegmented,
+            sequence_count: 0x3FFF,
+            data_len: 1,
+        };
+        assert_eq!(header.encode(), [0x07, 0xFF, 0xFF, 0xFF, 0x00, 0x00]);
+        assert_eq!(PrimaryHeader::decode(&header.encode()).unwrap(), header);
+    }
+
+    #[test]
+    fn frame_roundtrip_with_segments_and_counts() {
+        let mut tx = SpacePacketFramer::new(config(true)).unwrap();
+        let mut rx = SpacePacketFramer::new(config(true)).unwrap();
+        let chunks = [vec![1u8; 10], vec![2u8; 10], vec![3u8; 3]];
+        for (i, chunk) in chunks.iter().enumerate() {
+            let packet = tx.frame(chunk, Segment::of(i, chunks.len())).unwrap();
+            let header = PrimaryHeader::decode(&packet).unwrap();
+            assert_eq!(header.segment, Segment::of(i, chunks.len()));
+            assert_eq!(header.sequence_count, i as u16);
+            assert!(header.telecommand);
+            assert_eq!(rx.unframe(&packet, "a").unwrap(), Some(chunk.as_slice()));
+        }
+        assert_eq!(Segment::of(0, 3), Segment::First);
+        assert_eq!(Segment::of(1, 3), Segment::Continuation);
+        assert_eq!(Segment::of(2, 3), Segment::Last);
+    }
+
+    #[test]
+    fn unsegmented_when_configured_and_other_apids_ignored() {
+        let mut tx = SpacePacketFramer::new(config(false)).unwrap();
+        let packet = tx.frame(&[5; 4], Segment::First).unwrap();
+        assert_eq!(
+            PrimaryHeader::decode(&packet).unwrap().segment,
+            Segment::Unsegmented
+        );
+        let mut other = SpacePacketFramer::new(SpacePacketConfig {
+            apid: 0x124,
+            ..config(false)
+        })
+        .unwrap();
+        assert_eq!(other.unframe(&packet, "a").unwrap(), None);
+        let mut rx = SpacePacketFramer::new(config(false)).unwrap();
+        assert!(rx.unframe(&packet[..7], "a").is_err());
+    }
+}
@@ -1,3 +1,6 @@
+mod bpv7;
+mod bundle_transport;
+mod ccsds;
 mod chunking;
 mod error;
 mod udp_chunking;
@@ -13,4 +16,7 @@ pub trait Transport: Send + Sync {
     fn send(&self, msg: Message, addr: &str) -> Result<()>;
 }
 
+pub use bpv7::Eid;
+pub use bundle_transport::BundleTransport;
+pub use ccsds::SpacePacketConfig;
 pub use udp_transport::UdpTransport;
@@ -1,5 +1,6 @@
 use crate::error::TransportError;
 use crate::{
+    ccsds::{Segment, SpacePacketConfig, SpacePacketFramer, PRIMARY_HEADER_LEN},
     error::{adhoc, Result},
     udp_chunking::SimpleChunker,
     Transport, MAX_MTU,
@@ -8,7 +9,7 @@ use log::{debug, error, info, trace};
 use messages::Message;
 use std::{
     io,
-    net::{ToSocketAddrs, UdpSocket},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
     sync::{Arc, Mutex},
     thread::sleep,
     time::Duration,
@@ -17,6 +18,8 @@ use std::{
 pub struct UdpTransport {
     pub socket: UdpSocket,
     chunker: Arc<Mutex<SimpleChunker>>,
+    mtu: u16,
+    space_packets: Option<Mutex<SpacePacketFramer>>,
     max_read_attempts: Option<u16>,
     chunk_transmit_throttle: Option<u32>,
     timeout: Option<Duration>,
@@ -29,6 +32,8 @@ impl UdpTransport {
         Ok(UdpTransport {
             socket,
             chunker: Arc::new(Mutex::new(SimpleChunker::new(mtu))),
+            mtu,
+            space_packets: None,
             max_read_attempts: None,
             chunk_transmit_throttle,
             timeout: None,
@@ -43,70 +48,98 @@ impl UdpTransport {
     pub fn set_max_read_attempts(&mut self, attempts: Option<u16>) {
         self.max_read_attempts = attempts;
     }
+
+    // Wraps every chunk in a CCSDS Space Packet, the chunks shrinking to leave room for its header
+    pub fn set_space_packets(&mut self, config: SpacePacketConfig) -> Result<()> {
+        let mtu = self
+            .mtu
+            .checked_sub(PRIMARY_HEADER_LEN as u16)
+            .ok_or(adhoc("MTU too small for space packets"))?;
+        self.chunker = Arc::new(Mutex::new(SimpleChunker::new(mtu)));
+        self.space_packets = Some(Mutex::new(SpacePacketFramer::new(config)?));
+        Ok(())
+    }
+
+    // Waits for the next non-empty datagram, counting timeouts and errors across calls
+    pub(crate) fn recv_datagram(
+        &self,
+        buf: &mut [u8],
+        timeouts: &mut u16,
+        read_errors: &mut u16,
+    ) -> Result<(usize, SocketAddr)> {
+        loop {
+            trace!("Receiving...");
+            match self.socket.recv_from(buf) {
+                Ok((len, sender)) => {
+                    debug!("Received {len} bytes from {sender}");
+                    if len > 0 {
+                        return Ok((len, sender));
+                    }
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                        trace!("Receive timed out. May be normal depending on usage.");
+                        if *timeouts >= 10 {
+                            return Err(TransportError::TimedOut);
+                        }
+                        *timeouts += 1;
+                    }
+                    _ => {
+                        error!("Recv failed {e}");
+                        if self.max_read_attempts.unwrap_or(u16::MAX) <= *read_errors {
+                            return Err(e.into());
+                        }
+                        *read_errors += 1;
+                    }
+                },
+            }
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Hands a received chunk to the chunker, returning the message it completes if any
+    pub(crate) fn assemble(&self, chunk: &[u8]) -> Result<Option<Message>> {
+        debug!("Received possible chunk of {} bytes", chunk.len());
+        let hex_str = chunk.iter().map(|b| format!("{b:02X}")).collect::<String>();
+        trace!("Received possible chunk of hex {hex_str}");
+
+        let result = self
+            .chunker
+            .lock()
+            .expect("Lock failed, this is really bad")
+            .unchunk(chunk)?;
+        match &result {
+            Some(msg) => debug!("Assembled msg: {msg:?}"),
+            None => debug!("Received: no msg ready for assembly yet"),
+        }
+        Ok(result)
+    }
 }
 
 impl Transport for UdpTransport {
     fn receive(&self) -> Result<(Message, String)> {
         let mut buf = vec![0; usize::from(MAX_MTU)];
-        let mut sender_addr;
         let mut read_errors = 0;
-        let mut read_len;
         let mut timeouts = 0;
         loop {
-            loop {
-                trace!("Receiving...");
-                match self.socket.recv_from(&mut buf) {
-                    Ok((len, sender)) => {
-                        debug!("Received {len} bytes from {sender}");
-                        if len > 0 {
-                            read_len = len;
-                            sender_addr = sender;
-                            break;
-                        }
+            let (len, sender_addr) =
+                self.recv_datagram(&mut buf, &mut timeouts, &mut read_errors)?;
+            let chunk = match &self.space_packets {
+                Some(framer) => {
+                    let sender = sender_addr.to_string();
+                    match framer
+                        .lock()
+                        .expect("Lock failed, this is really bad")
+                        .unframe(&buf[0..len], &sender)?
+                    {
+                        Some(data) => data,
+                        None => continue,
                     }
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
-                            trace!("Receive timed out. May be normal depending on usage.");
-                            if timeouts >= 10 {
-                                return Err(TransportError::TimedOut);
-                            }
-                            timeouts += 1;
-                        }
-                        _ => {
-                            error!("Recv failed {e}");
-                            if self.max_read_attempts.unwrap_or(u16::MAX) <= read_errors {
-                                return Err(e.into());
-                            }
-                            read_errors += 1;
-                        }
-                    },
-                }
-                sleep(Duration::from_millis(1));
-            }
-
-            debug!("Received possible chunk of {} bytes", read_len);
-            let hex_str = buf[0..read_len]
-                .iter()
-                .map(|b| format!("{b:02X}"))
-                .collect::<String>();
-            trace!("Received possible chunk of hex {hex_str}");
-
-            match self
-                .chunker
-                .lock()
-                .expect("Lock failed, this is really bad")
-                .unchunk(&buf[0..read_len])
-            {
-                Ok(Some(msg)) => {
-                    debug!("Assembled msg: {msg:?}");
-                    return Ok((msg, sender_addr.to_string()));
-                }
-                Ok(None) => {
-                    debug!("Received: no msg ready for assembly yet");
-                }
-                Err(err) => {
-                    return Err(err);
                 }
+                None => &buf[0..len],
+            };
+            if let Some(msg) = self.assemble(chunk)? {
+                return Ok((msg, sender_addr.to_string()));
             }
         }
     }
@@ -117,12 +150,20 @@ impl Transport for UdpTransport {
             .to_socket_addrs()?
             .next()
             .ok_or(adhoc("Failed to parse address"))?;
-        for chunk in self
+        let chunks = self
             .chunker
             .lock()
             .expect("Lock failed, this is really bad")
-            .chunk(msg)?
-        {
+            .chunk(msg)?;
+        let count = chunks.len();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let chunk = match &self.space_packets {
+                Some(framer) => framer
+                    .lock()
+                    .expect("Lock failed, this is really bad")
+                    .frame(&chunk, Segment::of(i, count))?,
+                None => chunk,
+            };
             debug!("Transmitting chunk of {} bytes to {addr}", chunk.len());
             let hex_str = chunk.iter().map(|b| format!("{b:02X}")).collect::<String>();
             trace!("Transmitting chunk of hex {hex_str}");